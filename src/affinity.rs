@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{retry::random_u64, Conversation, LlamaLink};
+
+/// Which server slot the requests of a [`Conversation`] are sent to. The server only reuses the
+/// KV cache of the previous turns when the next turn hits the same slot, so without affinity a
+/// long conversation may have its whole prompt evaluated again on every turn. An `id_slot` in the
+/// conversation's overrides takes precedence.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub enum SlotAffinity {
+    /// The slot is derived from the session key and the `total_slots` of `GET /props`, so the
+    /// same session always hits the same slot, also after being saved and loaded. Without
+    /// `total_slots`, e.g. behind a proxy without `/props`, the server picks the slot.
+    ///
+    /// Sessions are not balanced: the requests of two conversations whose sessions hash to the
+    /// same slot are processed one after the other, while other slots may sit idle.
+    #[default]
+    Session,
+    /// Always this slot.
+    Slot(usize),
+    /// The server picks a slot for every request.
+    None,
+}
+
+impl Conversation {
+    /// The key the slot of [`SlotAffinity::Session`] is derived from. Random unless set when
+    /// building the conversation.
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    /// The prompt tokens reused from the server's cache by the last reply. `None` before the first
+    /// reply, or if the server does not report it.
+    pub fn tokens_cached(&self) -> Option<usize> {
+        self.tokens_cached
+    }
+
+    /// The link for a request of the conversation, with its overrides, its slot and
+    /// [`crate::Config::cache_prompt`] enabled unless set.
+    pub(crate) async fn request_link(&self, link: &LlamaLink) -> LlamaLink {
+        let mut link = self.link(link);
+        let unset =
+            |link: &LlamaLink, key: &str| link.request_config.get(key).is_none_or(Value::is_null);
+        if unset(&link, "cache_prompt") {
+            link.request_config
+                .insert("cache_prompt".to_owned(), Value::Bool(true));
+        }
+        if unset(&link, "id_slot") {
+            if let Some(slot) = self.slot(&link).await {
+                link.request_config
+                    .insert("id_slot".to_owned(), Value::from(slot));
+            }
+        }
+        link
+    }
+
+    async fn slot(&self, link: &LlamaLink) -> Option<usize> {
+        match self.affinity {
+            SlotAffinity::Session => {}
+            SlotAffinity::Slot(slot) => return Some(slot),
+            SlotAffinity::None => return None,
+        }
+        let total_slots = link.total_slots().await?;
+        Some((fnv1a(&self.session) % total_slots.max(1) as u64) as usize)
+    }
+}
+
+/// A random session key.
+pub(crate) fn new_session() -> String {
+    format!("{:016x}", random_u64())
+}
+
+/// A hash that is stable across runs and Rust versions, unlike the std hashers.
+fn fnv1a(key: &str) -> u64 {
+    key.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,43 @@
+use std::{fmt, sync::Arc};
+
+/// The api key sent as `Authorization: Bearer <key>` for servers started with `--api-key`.
+#[derive(Clone)]
+pub enum ApiKey {
+    Static(String),
+    /// Called before every request, so keys can be rotated without rebuilding the link.
+    Provider(Arc<dyn Fn() -> String + Send + Sync>),
+}
+
+impl ApiKey {
+    pub fn provider(provider: impl Fn() -> String + Send + Sync + 'static) -> Self {
+        Self::Provider(Arc::new(provider))
+    }
+
+    pub(crate) fn key(&self) -> String {
+        match self {
+            ApiKey::Static(key) => key.clone(),
+            ApiKey::Provider(provider) => provider(),
+        }
+    }
+}
+
+impl From<String> for ApiKey {
+    fn from(key: String) -> Self {
+        Self::Static(key)
+    }
+}
+
+impl From<&str> for ApiKey {
+    fn from(key: &str) -> Self {
+        Self::Static(key.to_owned())
+    }
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiKey::Static(_) => f.write_str("ApiKey::Static(..)"),
+            ApiKey::Provider(_) => f.write_str("ApiKey::Provider(..)"),
+        }
+    }
+}
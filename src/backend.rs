@@ -0,0 +1,367 @@
+use std::future::Future;
+
+use llmtoolbox::ToolBox;
+use serde_json::{Map, Value};
+use tokio_stream::StreamExt;
+
+use crate::{
+    image::has_images, Completion, CompletionChunkStream, CompletionError, CompletionStream,
+    FunctionCallContext, FunctionCallError, LlamaLink, LlamaPool, Message, OllamaLink,
+    PromptFormatter, RequestError,
+};
+
+/// How [`CompletionBackend::complete_constrained`] restricts the generated text.
+#[derive(Debug, Clone, Copy)]
+pub enum Constraint<'a> {
+    /// The output must be json matching the schema.
+    JsonSchema(&'a Map<String, Value>),
+    /// The output must match the GBNF grammar. Engines without grammar support may reject this.
+    Grammar(&'a str),
+}
+
+/// The raw operations of an inference engine. Formatting, structured output and function calling
+/// are provided on top of them, so they work the same for [`LlamaLink`], [`LlamaPool`], other
+/// engines or a test double.
+pub trait CompletionBackend: Send + Sync {
+    /// Completes `prompt` with the backend's configured sampling settings.
+    fn complete(
+        &self,
+        prompt: String,
+    ) -> impl Future<Output = Result<Completion, CompletionError>> + Send;
+
+    fn complete_stream(&self, prompt: String) -> CompletionChunkStream;
+
+    fn complete_constrained(
+        &self,
+        prompt: String,
+        constraint: Constraint<'_>,
+    ) -> impl Future<Output = Result<Completion, CompletionError>> + Send;
+
+    /// Tokenizes `content`, parsing special tokens and without adding a BOS token.
+    fn tokenize(
+        &self,
+        content: &str,
+    ) -> impl Future<Output = Result<Vec<u32>, RequestError>> + Send;
+
+    /// Fails with [`RequestError::Unsupported`] if `messages` have images, unless the backend
+    /// overrides it to send them, like [`LlamaLink`] and [`LlamaPool`].
+    fn complete_with_format(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> impl Future<Output = Result<Completion, CompletionError>> + Send {
+        let images = reject_images(messages);
+        let prompt = (formatter.0)(system, messages);
+        async move {
+            images?;
+            self.complete(prompt).await
+        }
+    }
+
+    /// Streams only the content of each chunk.
+    fn complete_stream_content(&self, prompt: String) -> CompletionStream {
+        Box::pin(
+            self.complete_stream(prompt)
+                .map(|chunk| chunk.map(|chunk| chunk.content)),
+        )
+    }
+
+    /// See [`CompletionBackend::complete_with_format`].
+    fn complete_stream_with_format(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> CompletionChunkStream {
+        if let Err(error) = reject_images(messages) {
+            return Box::pin(tokio_stream::once(Err(error.into())));
+        }
+        self.complete_stream((formatter.0)(system, messages))
+    }
+
+    /// Generates json matching `schema` and parses it.
+    fn complete_json(
+        &self,
+        prompt: String,
+        schema: &Map<String, Value>,
+    ) -> impl Future<Output = Result<Value, CompletionError>> + Send {
+        async move {
+            let completion = self
+                .complete_constrained(prompt, Constraint::JsonSchema(schema))
+                .await?;
+            serde_json::from_str(&completion.content).map_err(|_| CompletionError::Parsing {
+                issue: "Could not parse the structured response into valid json".to_owned(),
+            })
+        }
+    }
+
+    /// Generates a call to one of the functions of `toolbox` and runs it.
+    fn call_function_full<O, E>(
+        &self,
+        prompt: String,
+        toolbox: &ToolBox<O, E>,
+    ) -> impl Future<Output = Result<FunctionCallContext<O, E>, FunctionCallError>> {
+        async move {
+            let content = self
+                .complete_constrained(prompt, Constraint::JsonSchema(toolbox.schema()))
+                .await?
+                .content;
+            run_function_call(toolbox, content).await
+        }
+    }
+
+    fn call_function<O, E>(
+        &self,
+        prompt: String,
+        toolbox: &ToolBox<O, E>,
+    ) -> impl Future<Output = Result<Result<O, E>, FunctionCallError>> {
+        async move {
+            self.call_function_full(prompt, toolbox)
+                .await
+                .map(|context| context.output_result)
+        }
+    }
+
+    fn call_function_with_format<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+        toolbox: &ToolBox<O, E>,
+    ) -> impl Future<Output = Result<Result<O, E>, FunctionCallError>> {
+        let images = reject_images(messages);
+        let prompt = (formatter.0)(system, messages);
+        async move {
+            images?;
+            self.call_function(prompt, toolbox).await
+        }
+    }
+}
+
+/// The formatted methods of [`CompletionBackend`] only send the prompt text, so images would be
+/// lost.
+fn reject_images(messages: &[Message]) -> Result<(), RequestError> {
+    if !has_images(messages) {
+        return Ok(());
+    }
+    Err(RequestError::Unsupported {
+        operation: "Image input".to_owned(),
+        backend: "the formatted completion methods of this backend".to_owned(),
+    })
+}
+
+/// Parses the generated tool call `content` and runs the function in `toolbox`.
+pub(crate) async fn run_function_call<O, E>(
+    toolbox: &ToolBox<O, E>,
+    content: String,
+) -> Result<FunctionCallContext<O, E>, FunctionCallError> {
+    #[cfg(feature = "tracing")]
+    tracing::debug!("Raw tool_call response:\n`{}`", &content);
+    let tool_call = serde_json::from_str(&content).map_err(|_| FunctionCallError::Parsing {
+        issue: "Could not parse tool call response into valid json".to_owned(),
+    })?;
+    let output_result = toolbox.call_from_value(tool_call).await?;
+    Ok(FunctionCallContext {
+        output_result,
+        raw_input: content,
+    })
+}
+
+impl LlamaLink {
+    /// Sends `prompt`, a string or a multimodal prompt, restricted by `constraint`.
+    pub(crate) async fn constrained_completion(
+        &self,
+        prompt: Value,
+        constraint: Constraint<'_>,
+    ) -> Result<Completion, CompletionError> {
+        let mut json = self.completion_request(prompt);
+        match constraint {
+            Constraint::JsonSchema(schema) => {
+                json.insert("json_schema".to_owned(), Value::Object(schema.clone()))
+            }
+            Constraint::Grammar(grammar) => {
+                json.insert("grammar".to_owned(), Value::String(grammar.to_owned()))
+            }
+        };
+        self.send_completion_request(json).await
+    }
+}
+
+impl CompletionBackend for LlamaLink {
+    async fn complete(&self, prompt: String) -> Result<Completion, CompletionError> {
+        self.create_completion_full(prompt).await
+    }
+
+    fn complete_stream(&self, prompt: String) -> CompletionChunkStream {
+        self.create_completion_stream_full(prompt)
+    }
+
+    async fn complete_constrained(
+        &self,
+        prompt: String,
+        constraint: Constraint<'_>,
+    ) -> Result<Completion, CompletionError> {
+        self.constrained_completion(Value::String(prompt), constraint)
+            .await
+    }
+
+    /// Sends the images of `messages` as well, once the server reports vision support.
+    async fn complete_with_format(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> Result<Completion, CompletionError> {
+        self.create_completion_with_format_full(system, messages, formatter)
+            .await
+    }
+
+    /// Sends the images of `messages` as well, once the server reports vision support.
+    fn complete_stream_with_format(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> CompletionChunkStream {
+        self.create_formatted_completion_stream_full(system, messages, formatter)
+    }
+
+    /// Sends the images of `messages` as well, once the server reports vision support.
+    async fn call_function_with_format<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<Result<O, E>, FunctionCallError> {
+        LlamaLink::call_function_with_format(self, system, messages, formatter, toolbox).await
+    }
+
+    async fn tokenize(&self, content: &str) -> Result<Vec<u32>, RequestError> {
+        LlamaLink::tokenize(self, content).await
+    }
+}
+
+/// One of the supported engines, chosen at runtime, e.g. from a configuration file.
+#[derive(Clone)]
+pub enum Engine {
+    LlamaCpp(LlamaLink),
+    LlamaCppPool(LlamaPool),
+    Ollama(OllamaLink),
+}
+
+impl From<LlamaLink> for Engine {
+    fn from(link: LlamaLink) -> Self {
+        Self::LlamaCpp(link)
+    }
+}
+
+impl From<LlamaPool> for Engine {
+    fn from(pool: LlamaPool) -> Self {
+        Self::LlamaCppPool(pool)
+    }
+}
+
+impl From<OllamaLink> for Engine {
+    fn from(link: OllamaLink) -> Self {
+        Self::Ollama(link)
+    }
+}
+
+impl CompletionBackend for Engine {
+    async fn complete(&self, prompt: String) -> Result<Completion, CompletionError> {
+        match self {
+            Engine::LlamaCpp(link) => link.complete(prompt).await,
+            Engine::LlamaCppPool(pool) => pool.complete(prompt).await,
+            Engine::Ollama(link) => link.complete(prompt).await,
+        }
+    }
+
+    fn complete_stream(&self, prompt: String) -> CompletionChunkStream {
+        match self {
+            Engine::LlamaCpp(link) => link.complete_stream(prompt),
+            Engine::LlamaCppPool(pool) => pool.complete_stream(prompt),
+            Engine::Ollama(link) => link.complete_stream(prompt),
+        }
+    }
+
+    async fn complete_constrained(
+        &self,
+        prompt: String,
+        constraint: Constraint<'_>,
+    ) -> Result<Completion, CompletionError> {
+        match self {
+            Engine::LlamaCpp(link) => link.complete_constrained(prompt, constraint).await,
+            Engine::LlamaCppPool(pool) => pool.complete_constrained(prompt, constraint).await,
+            Engine::Ollama(link) => link.complete_constrained(prompt, constraint).await,
+        }
+    }
+
+    async fn complete_with_format(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> Result<Completion, CompletionError> {
+        match self {
+            Engine::LlamaCpp(link) => {
+                CompletionBackend::complete_with_format(link, system, messages, formatter).await
+            }
+            Engine::LlamaCppPool(pool) => {
+                CompletionBackend::complete_with_format(pool, system, messages, formatter).await
+            }
+            Engine::Ollama(link) => link.complete_with_format(system, messages, formatter).await,
+        }
+    }
+
+    fn complete_stream_with_format(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> CompletionChunkStream {
+        match self {
+            Engine::LlamaCpp(link) => link.complete_stream_with_format(system, messages, formatter),
+            Engine::LlamaCppPool(pool) => {
+                pool.complete_stream_with_format(system, messages, formatter)
+            }
+            Engine::Ollama(link) => link.complete_stream_with_format(system, messages, formatter),
+        }
+    }
+
+    async fn call_function_with_format<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<Result<O, E>, FunctionCallError> {
+        match self {
+            Engine::LlamaCpp(link) => {
+                CompletionBackend::call_function_with_format(
+                    link, system, messages, formatter, toolbox,
+                )
+                .await
+            }
+            Engine::LlamaCppPool(pool) => {
+                CompletionBackend::call_function_with_format(
+                    pool, system, messages, formatter, toolbox,
+                )
+                .await
+            }
+            Engine::Ollama(link) => {
+                link.call_function_with_format(system, messages, formatter, toolbox)
+                    .await
+            }
+        }
+    }
+
+    async fn tokenize(&self, content: &str) -> Result<Vec<u32>, RequestError> {
+        match self {
+            Engine::LlamaCpp(link) => CompletionBackend::tokenize(link, content).await,
+            Engine::LlamaCppPool(pool) => pool.tokenize(content).await,
+            Engine::Ollama(link) => link.tokenize(content).await,
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::cmp::Ordering;
+
+use llmtoolbox::ToolBox;
+use serde_json::{Map, Value};
+use tokio::task::JoinSet;
+
+use crate::{
+    multimodal_prompt, run_function_call, Completion, CompletionError, FunctionCallContext,
+    FunctionCallError, LlamaLink, Message, PromptFormatter,
+};
+
+/// How [`LlamaLink::best_of_n`] picks one of the candidates.
+pub enum Selection<'a> {
+    /// The most common answer wins. Completions are compared with surrounding whitespace trimmed,
+    /// function calls by their parsed json. Ties are broken by the highest mean log probability.
+    MajorityVote,
+    /// The candidate the model was most confident in, by mean log probability per token.
+    HighestMeanLogprob,
+    /// The candidate with the highest score.
+    Score(&'a (dyn Fn(&Candidate) -> f64 + Sync)),
+}
+
+/// One of the completions generated by [`LlamaLink::best_of_n`].
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub seed: u32,
+    pub completion: Completion,
+}
+
+/// All candidates generated by [`LlamaLink::best_of_n`], kept for auditing, and the one selected.
+#[derive(Debug, Clone)]
+pub struct BestOf {
+    /// The index of the selected candidate.
+    pub selected: usize,
+    pub candidates: Vec<Candidate>,
+}
+
+impl BestOf {
+    pub fn selected(&self) -> &Candidate {
+        &self.candidates[self.selected]
+    }
+}
+
+/// The result of [`LlamaLink::call_function_best_of_n`].
+pub struct FunctionCallBestOf<O, E> {
+    /// The result of calling the function with the selected candidate.
+    pub context: FunctionCallContext<O, E>,
+    pub best_of: BestOf,
+}
+
+impl LlamaLink {
+    /// Generates `n` completions concurrently, each with a different seed, and selects one. Seeds
+    /// start at the [`crate::Config`] seed, or `0`, and increase by one per candidate. Candidates
+    /// only differ when sampling with a non-zero temperature. Fails with
+    /// [`CompletionError::InvalidRequest`] if `n` is zero.
+    pub async fn best_of_n(
+        &self,
+        prompt: String,
+        n: usize,
+        selection: Selection<'_>,
+    ) -> Result<BestOf, CompletionError> {
+        self.best_of_n_prompt(Value::String(prompt), n, selection)
+            .await
+    }
+
+    /// Sends the images of `messages` as well, see [`Message::UserWithImages`].
+    pub async fn best_of_n_with_format(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+        n: usize,
+        selection: Selection<'_>,
+    ) -> Result<BestOf, CompletionError> {
+        self.check_images(messages).await?;
+        let prompt = multimodal_prompt((formatter.0)(system, messages), messages);
+        self.best_of_n_prompt(prompt, n, selection).await
+    }
+
+    /// Samples candidates for `prompt`, a string or a multimodal prompt.
+    async fn best_of_n_prompt(
+        &self,
+        prompt: Value,
+        n: usize,
+        selection: Selection<'_>,
+    ) -> Result<BestOf, CompletionError> {
+        let json = self.completion_request(prompt);
+        let candidates = self.sample_candidates(json, n).await?;
+        let selected = select(&candidates, &selection, |candidate| {
+            Some(Value::String(
+                candidate.completion.content.trim().to_owned(),
+            ))
+        })
+        .expect("Every completion is eligible, and there is at least one");
+        Ok(BestOf {
+            selected,
+            candidates,
+        })
+    }
+
+    /// Like [`LlamaLink::best_of_n`], but each candidate is a function call constrained to
+    /// `toolbox`'s schema. Only the selected candidate is called, so functions run once.
+    /// Candidates that are not a valid tool call are never selected, whatever the `selection`.
+    pub async fn call_function_best_of_n<O, E>(
+        &self,
+        prompt: String,
+        toolbox: &ToolBox<O, E>,
+        n: usize,
+        selection: Selection<'_>,
+    ) -> Result<FunctionCallBestOf<O, E>, FunctionCallError> {
+        self.call_function_best_of_n_prompt(Value::String(prompt), toolbox, n, selection)
+            .await
+    }
+
+    /// Sends the images of `messages` as well, see [`Message::UserWithImages`].
+    pub async fn call_function_best_of_n_with_format<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+        toolbox: &ToolBox<O, E>,
+        n: usize,
+        selection: Selection<'_>,
+    ) -> Result<FunctionCallBestOf<O, E>, FunctionCallError> {
+        self.check_images(messages).await?;
+        let prompt = multimodal_prompt((formatter.0)(system, messages), messages);
+        self.call_function_best_of_n_prompt(prompt, toolbox, n, selection)
+            .await
+    }
+
+    /// Samples function call candidates for `prompt`, a string or a multimodal prompt.
+    async fn call_function_best_of_n_prompt<O, E>(
+        &self,
+        prompt: Value,
+        toolbox: &ToolBox<O, E>,
+        n: usize,
+        selection: Selection<'_>,
+    ) -> Result<FunctionCallBestOf<O, E>, FunctionCallError> {
+        let mut json = self.completion_request(prompt);
+        json.insert(
+            "json_schema".to_owned(),
+            Value::Object(toolbox.schema().clone()),
+        );
+        let candidates = self.sample_candidates(json, n).await?;
+        // Only valid tool calls are eligible, so one malformed candidate does not fail the call
+        let selected = select(&candidates, &selection, |candidate| {
+            serde_json::from_str(&candidate.completion.content)
+                .ok()
+                .filter(|tool_call: &Value| {
+                    toolbox
+                        .into_function_call_from_value(tool_call.clone())
+                        .is_ok()
+                })
+        })
+        .ok_or_else(|| FunctionCallError::Parsing {
+            issue: "No candidate could be parsed as a tool call".to_owned(),
+        })?;
+        let raw_input = candidates[selected].completion.content.clone();
+        Ok(FunctionCallBestOf {
+            context: run_function_call(toolbox, raw_input).await?,
+            best_of: BestOf {
+                selected,
+                candidates,
+            },
+        })
+    }
+
+    /// Sends `json` `n` times concurrently with increasing seeds. Fails if `n` is zero, so the
+    /// candidates are never empty. Token probabilities are always requested so candidates can be
+    /// compared by log probability.
+    async fn sample_candidates(
+        &self,
+        mut json: Map<String, Value>,
+        n: usize,
+    ) -> Result<Vec<Candidate>, CompletionError> {
+        if n == 0 {
+            return Err(CompletionError::InvalidRequest {
+                issue: "At least one candidate must be generated".to_owned(),
+            });
+        }
+        let base_seed = json.get("seed").and_then(Value::as_u64).unwrap_or(0) as u32;
+        if json.get("n_probs").and_then(Value::as_u64).unwrap_or(0) == 0 {
+            json.insert("n_probs".to_owned(), Value::from(1));
+        }
+        let mut requests = JoinSet::new();
+        for index in 0..n {
+            let link = self.clone();
+            let mut json = json.clone();
+            let seed = base_seed.wrapping_add(index as u32);
+            json.insert("seed".to_owned(), Value::from(seed));
+            requests.spawn(async move {
+                let completion = link.send_completion_request(json).await?;
+                Ok::<_, CompletionError>((index, Candidate { seed, completion }))
+            });
+        }
+        let mut candidates = Vec::with_capacity(n);
+        while let Some(result) = requests.join_next().await {
+            let candidate = match result {
+                Ok(candidate) => candidate?,
+                Err(error) => std::panic::resume_unwind(error.into_panic()),
+            };
+            candidates.push(candidate);
+        }
+        candidates.sort_by_key(|(index, _)| *index);
+        Ok(candidates
+            .into_iter()
+            .map(|(_, candidate)| candidate)
+            .collect())
+    }
+}
+
+/// Returns the index of the selected candidate. `key` extracts the value voted on under
+/// [`Selection::MajorityVote`], candidates without one are never selected.
+fn select(
+    candidates: &[Candidate],
+    selection: &Selection<'_>,
+    key: impl Fn(&Candidate) -> Option<Value>,
+) -> Option<usize> {
+    let keys: Vec<Option<Value>> = candidates.iter().map(key).collect();
+    let mean_logprob = |index: usize| {
+        candidates[index]
+            .completion
+            .mean_logprob()
+            .unwrap_or(f64::NEG_INFINITY)
+    };
+    // On equal ordering the earliest candidate wins
+    let best_by = |compare: &dyn Fn(usize, usize) -> Ordering| {
+        (0..candidates.len())
+            .filter(|index| keys[*index].is_some())
+            .max_by(|a, b| compare(*a, *b).then(b.cmp(a)))
+    };
+    match selection {
+        Selection::MajorityVote => {
+            let votes = |index: usize| keys.iter().filter(|key| **key == keys[index]).count();
+            best_by(&|a, b| {
+                votes(a)
+                    .cmp(&votes(b))
+                    .then(mean_logprob(a).total_cmp(&mean_logprob(b)))
+            })
+        }
+        Selection::HighestMeanLogprob => {
+            best_by(&|a, b| mean_logprob(a).total_cmp(&mean_logprob(b)))
+        }
+        Selection::Score(score) => {
+            best_by(&|a, b| score(&candidates[a]).total_cmp(&score(&candidates[b])))
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use std::sync::Arc;
+
+use llmtoolbox::ToolBox;
+use tokio::runtime::Runtime;
+use tokio_stream::StreamExt;
+
+use crate::{
+    ChatCompletion, ChatCompletionChunk, Completion, CompletionChunk, CompletionError,
+    CompletionStreamError, Config, FunctionCallContext, FunctionCallError, LlamaLink, Message,
+    PromptFormatter, RequestError, ResultStream,
+};
+
+/// A synchronous [`LlamaLink`] for code without an async runtime, e.g. CLI tools and build
+/// scripts. Each call blocks on a runtime owned by the link, and streams are iterators.
+///
+/// Build the underlying link with [`LlamaLink::builder`] for custom settings and convert it with
+/// [`BlockingLlamaLink::from`]. Clones share the runtime.
+///
+/// # Panics
+/// Every method panics when called from within an async runtime. Use [`LlamaLink`] there instead.
+#[derive(Clone)]
+pub struct BlockingLlamaLink {
+    link: LlamaLink,
+    runtime: Arc<Runtime>,
+}
+
+/// The chunks of a streamed completion, each received on the next call to [`Iterator::next`].
+pub struct BlockingStream<T> {
+    stream: ResultStream<T>,
+    runtime: Arc<Runtime>,
+}
+
+impl<T> Iterator for BlockingStream<T> {
+    type Item = Result<T, CompletionStreamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.runtime.block_on(self.stream.next())
+    }
+}
+
+impl From<LlamaLink> for BlockingLlamaLink {
+    /// # Panics
+    /// If the runtime cannot be created.
+    fn from(link: LlamaLink) -> Self {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Could not create the runtime of the blocking link");
+        Self {
+            link,
+            runtime: Arc::new(runtime),
+        }
+    }
+}
+
+impl BlockingLlamaLink {
+    /// See [`LlamaLink::new`].
+    pub fn new(url: &str, request_config: Config) -> Self {
+        LlamaLink::new(url, request_config).into()
+    }
+
+    /// The async link, for the parts of the api without a blocking equivalent.
+    pub fn link(&self) -> &LlamaLink {
+        &self.link
+    }
+
+    fn stream<T>(&self, stream: ResultStream<T>) -> BlockingStream<T> {
+        BlockingStream {
+            stream,
+            runtime: self.runtime.clone(),
+        }
+    }
+
+    pub fn create_completion_with_format(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> Result<String, CompletionError> {
+        self.runtime.block_on(
+            self.link
+                .create_completion_with_format(system, messages, formatter),
+        )
+    }
+
+    pub fn create_completion(&self, prompt: String) -> Result<String, CompletionError> {
+        self.runtime.block_on(self.link.create_completion(prompt))
+    }
+
+    pub fn create_completion_with_format_full(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> Result<Completion, CompletionError> {
+        self.runtime.block_on(
+            self.link
+                .create_completion_with_format_full(system, messages, formatter),
+        )
+    }
+
+    /// See [`LlamaLink::create_completion_full`].
+    pub fn create_completion_full(&self, prompt: String) -> Result<Completion, CompletionError> {
+        self.runtime
+            .block_on(self.link.create_completion_full(prompt))
+    }
+
+    pub fn call_function<O, E>(
+        &self,
+        prompt: String,
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<Result<O, E>, FunctionCallError> {
+        self.runtime
+            .block_on(self.link.call_function(prompt, toolbox))
+    }
+
+    pub fn call_function_with_format<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<Result<O, E>, FunctionCallError> {
+        self.runtime.block_on(
+            self.link
+                .call_function_with_format(system, messages, formatter, toolbox),
+        )
+    }
+
+    pub fn call_function_with_format_full<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<FunctionCallContext<O, E>, FunctionCallError> {
+        self.runtime.block_on(
+            self.link
+                .call_function_with_format_full(system, messages, formatter, toolbox),
+        )
+    }
+
+    pub fn call_function_full<O, E>(
+        &self,
+        prompt: String,
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<FunctionCallContext<O, E>, FunctionCallError> {
+        self.runtime
+            .block_on(self.link.call_function_full(prompt, toolbox))
+    }
+
+    pub fn create_formatted_completion_stream(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> BlockingStream<String> {
+        self.stream(
+            self.link
+                .create_formatted_completion_stream(system, messages, formatter),
+        )
+    }
+
+    pub fn create_completion_stream(&self, prompt: String) -> BlockingStream<String> {
+        self.stream(self.link.create_completion_stream(prompt))
+    }
+
+    pub fn create_formatted_completion_stream_full(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> BlockingStream<CompletionChunk> {
+        self.stream(
+            self.link
+                .create_formatted_completion_stream_full(system, messages, formatter),
+        )
+    }
+
+    /// See [`LlamaLink::create_completion_stream_full`].
+    pub fn create_completion_stream_full(&self, prompt: String) -> BlockingStream<CompletionChunk> {
+        self.stream(self.link.create_completion_stream_full(prompt))
+    }
+
+    /// See [`LlamaLink::create_chat_completion`].
+    pub fn create_chat_completion(
+        &self,
+        system: &str,
+        messages: &[Message],
+    ) -> Result<String, CompletionError> {
+        self.runtime
+            .block_on(self.link.create_chat_completion(system, messages))
+    }
+
+    pub fn create_chat_completion_full(
+        &self,
+        system: &str,
+        messages: &[Message],
+    ) -> Result<ChatCompletion, CompletionError> {
+        self.runtime
+            .block_on(self.link.create_chat_completion_full(system, messages))
+    }
+
+    pub fn create_chat_completion_stream(
+        &self,
+        system: &str,
+        messages: &[Message],
+    ) -> BlockingStream<String> {
+        self.stream(self.link.create_chat_completion_stream(system, messages))
+    }
+
+    /// See [`LlamaLink::create_chat_completion_stream_full`].
+    pub fn create_chat_completion_stream_full(
+        &self,
+        system: &str,
+        messages: &[Message],
+    ) -> BlockingStream<ChatCompletionChunk> {
+        self.stream(
+            self.link
+                .create_chat_completion_stream_full(system, messages),
+        )
+    }
+
+    /// See [`LlamaLink::create_chat_completion_stream_with_tools`].
+    pub fn create_chat_completion_stream_with_tools<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        toolbox: &ToolBox<O, E>,
+    ) -> BlockingStream<ChatCompletionChunk> {
+        self.stream(
+            self.link
+                .create_chat_completion_stream_with_tools(system, messages, toolbox),
+        )
+    }
+
+    pub fn call_function_chat<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<Result<O, E>, FunctionCallError> {
+        self.runtime
+            .block_on(self.link.call_function_chat(system, messages, toolbox))
+    }
+
+    /// See [`LlamaLink::call_function_chat_full`].
+    pub fn call_function_chat_full<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<FunctionCallContext<O, E>, FunctionCallError> {
+        self.runtime
+            .block_on(self.link.call_function_chat_full(system, messages, toolbox))
+    }
+
+    pub fn tokenize(&self, content: &str) -> Result<Vec<u32>, RequestError> {
+        self.runtime.block_on(self.link.tokenize(content))
+    }
+
+    pub fn detokenize(&self, tokens: &[u32]) -> Result<String, RequestError> {
+        self.runtime.block_on(self.link.detokenize(tokens))
+    }
+}
@@ -0,0 +1,445 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use reqwest::{header::CONTENT_TYPE, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{CompletionStreamError, LlamaLink, RequestError, ResultStream};
+
+/// Records the traffic of a [`LlamaLink`] to a JSONL file, one [`Interaction`] per line, or
+/// replays a recording without a server. Set it with the `cassette` option of
+/// [`LlamaLink::builder`]. Clones share the same recording.
+///
+/// Streams are recorded event by event once they end. A stream dropped before its end is not
+/// recorded.
+#[derive(Clone)]
+pub struct Cassette(Arc<CassetteInner>);
+
+struct CassetteInner {
+    mode: Mode,
+    rules: MatchRules,
+    interactions: Mutex<Vec<(Interaction, bool)>>,
+}
+
+enum Mode {
+    Record(Mutex<BufWriter<File>>),
+    Replay,
+}
+
+/// A request and the response the server sent for it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Interaction {
+    pub method: String,
+    /// Relative to the base url, e.g. `completion` or `slots/0?action=save`.
+    pub path: String,
+    /// The json body of the request, `null` if there was none.
+    pub request: Value,
+    pub status: u16,
+    #[serde(flatten)]
+    pub response: RecordedResponse,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordedResponse {
+    /// The body of a non streaming response, or of a stream the server rejected.
+    Body(String),
+    Events(Vec<RecordedEvent>),
+}
+
+/// The data of a server sent event and when it arrived.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordedEvent {
+    pub data: String,
+    /// Milliseconds since the request was sent.
+    pub elapsed_ms: u64,
+}
+
+/// How a replayed request is matched to a recorded [`Interaction`]. The method and path must
+/// always be equal, the json bodies are compared after applying the rules.
+#[derive(Debug, Clone, bon::Builder)]
+pub struct MatchRules {
+    /// Ignore the sampling `seed`, so recordings stay valid when seeds change between runs.
+    #[builder(default)]
+    ignore_seed: bool,
+    /// Top level body fields to ignore, e.g. `temperature`.
+    #[builder(default)]
+    ignore_fields: Vec<String>,
+    /// Compare only the `prompt` or chat `messages`, ignoring every sampling option.
+    #[builder(default)]
+    prompt_only: bool,
+    /// Compare prompts exactly. Otherwise runs of whitespace in the prompt, also of multimodal
+    /// prompts, and in the text of chat messages are treated as a single space.
+    #[builder(default = true)]
+    exact_prompt: bool,
+    /// Serve each interaction at most once, so repeated identical requests replay the recorded
+    /// responses in order.
+    #[builder(default = true)]
+    consume: bool,
+    /// Wait between replayed stream events as long as when they were recorded.
+    #[builder(default)]
+    replay_timing: bool,
+}
+
+impl Default for MatchRules {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl MatchRules {
+    fn normalize(&self, body: &Value) -> Value {
+        let Value::Object(body) = body else {
+            return body.clone();
+        };
+        let mut body = body.clone();
+        if self.prompt_only {
+            body.retain(|field, _| field == "prompt" || field == "messages");
+        }
+        if self.ignore_seed {
+            body.remove("seed");
+        }
+        for field in &self.ignore_fields {
+            body.remove(field);
+        }
+        if !self.exact_prompt {
+            match body.get_mut("prompt") {
+                Some(Value::Object(prompt)) => collapse_whitespace(prompt.get_mut("prompt_string")),
+                prompt => collapse_whitespace(prompt),
+            }
+            if let Some(Value::Array(messages)) = body.get_mut("messages") {
+                for message in messages {
+                    match message.get_mut("content") {
+                        Some(Value::Array(parts)) => {
+                            for part in parts {
+                                collapse_whitespace(part.get_mut("text"));
+                            }
+                        }
+                        content => collapse_whitespace(content),
+                    }
+                }
+            }
+        }
+        Value::Object(body)
+    }
+
+    fn matches(&self, interaction: &Interaction, request: &RecordedRequest) -> bool {
+        interaction.method == request.method
+            && interaction.path == request.path
+            && self.normalize(&interaction.request) == self.normalize(&request.body)
+    }
+}
+
+/// Replaces runs of whitespace in `text` with a single space, if it is a string.
+fn collapse_whitespace(text: Option<&mut Value>) {
+    if let Some(Value::String(text)) = text {
+        *text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    }
+}
+
+impl Cassette {
+    /// Records to `path`, replacing any existing recording.
+    pub fn record(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self::new(
+            Mode::Record(Mutex::new(BufWriter::new(file))),
+            MatchRules::default(),
+            Vec::new(),
+        ))
+    }
+
+    /// Replays the recording at `path`. Requests without a matching interaction fail with
+    /// [`RequestError::UnmatchedRequest`].
+    pub fn replay(path: impl AsRef<Path>, rules: MatchRules) -> std::io::Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        let mut interactions = Vec::new();
+        for (index, line) in file.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let interaction = serde_json::from_str(&line).map_err(|error| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid interaction on line {}: {error}", index + 1),
+                )
+            })?;
+            interactions.push(interaction);
+        }
+        Ok(Self::from_interactions(interactions, rules))
+    }
+
+    /// Replays `interactions` without a file.
+    pub fn from_interactions(interactions: Vec<Interaction>, rules: MatchRules) -> Self {
+        Self::new(Mode::Replay, rules, interactions)
+    }
+
+    fn new(mode: Mode, rules: MatchRules, interactions: Vec<Interaction>) -> Self {
+        Self(Arc::new(CassetteInner {
+            mode,
+            rules,
+            interactions: Mutex::new(
+                interactions
+                    .into_iter()
+                    .map(|interaction| (interaction, false))
+                    .collect(),
+            ),
+        }))
+    }
+
+    /// The interactions recorded so far, or loaded for replay.
+    pub fn interactions(&self) -> Vec<Interaction> {
+        self.0
+            .interactions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(interaction, _)| interaction.clone())
+            .collect()
+    }
+
+    /// The interactions not replayed yet.
+    pub fn unplayed(&self) -> Vec<Interaction> {
+        self.0
+            .interactions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, played)| !played)
+            .map(|(interaction, _)| interaction.clone())
+            .collect()
+    }
+
+    pub(crate) fn is_replay(&self) -> bool {
+        matches!(self.0.mode, Mode::Replay)
+    }
+
+    fn find(&self, request: &RecordedRequest) -> Result<Interaction, RequestError> {
+        let rules = &self.0.rules;
+        let mut interactions = self.0.interactions.lock().unwrap();
+        let (interaction, played) = interactions
+            .iter_mut()
+            .find(|(interaction, played)| {
+                !(rules.consume && *played) && rules.matches(interaction, request)
+            })
+            .ok_or_else(|| RequestError::UnmatchedRequest {
+                method: request.method.clone(),
+                path: request.path.clone(),
+            })?;
+        *played = true;
+        Ok(interaction.clone())
+    }
+
+    fn append(&self, interaction: Interaction) -> Result<(), RequestError> {
+        let Mode::Record(file) = &self.0.mode else {
+            return Ok(());
+        };
+        let line = serde_json::to_string(&interaction)?;
+        let mut file = file.lock().unwrap();
+        writeln!(file, "{line}")
+            .and_then(|_| file.flush())
+            .map_err(|error| RequestError::Recording {
+                issue: error.to_string(),
+            })?;
+        self.0
+            .interactions
+            .lock()
+            .unwrap()
+            .push((interaction, true));
+        Ok(())
+    }
+}
+
+struct RecordedRequest {
+    method: String,
+    path: String,
+    body: Value,
+}
+
+impl RecordedRequest {
+    fn into_interaction(self, status: StatusCode, response: RecordedResponse) -> Interaction {
+        Interaction {
+            method: self.method,
+            path: self.path,
+            request: self.body,
+            status: status.as_u16(),
+            response,
+        }
+    }
+}
+
+/// Collects the events of a stream until it ends.
+pub(crate) struct StreamRecorder {
+    cassette: Cassette,
+    request: Mutex<Option<RecordedRequest>>,
+    started: Instant,
+    events: Mutex<Vec<RecordedEvent>>,
+}
+
+impl StreamRecorder {
+    pub(crate) fn event(&self, data: &str) {
+        self.events.lock().unwrap().push(RecordedEvent {
+            data: data.to_owned(),
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+        });
+    }
+
+    /// Records the stream once, with the events so far if `body` is `None`.
+    pub(crate) fn finish(&self, status: StatusCode, body: Option<String>) {
+        let Some(request) = self.request.lock().unwrap().take() else {
+            return;
+        };
+        let response = match body {
+            Some(body) => RecordedResponse::Body(body),
+            None => RecordedResponse::Events(std::mem::take(&mut self.events.lock().unwrap())),
+        };
+        #[allow(unused_variables)]
+        if let Err(error) = self
+            .cassette
+            .append(request.into_interaction(status, response))
+        {
+            #[cfg(feature = "tracing")]
+            tracing::error!("Could not record completion stream: {error}");
+        }
+    }
+}
+
+impl LlamaLink {
+    fn recorded_request(&self, request: &reqwest::Request) -> RecordedRequest {
+        let url = request.url().as_str();
+        let path = url.strip_prefix(self.base_url()).unwrap_or(url);
+        RecordedRequest {
+            method: request.method().to_string(),
+            path: path.to_owned(),
+            body: request
+                .body()
+                .and_then(|body| body.as_bytes())
+                .and_then(|body| serde_json::from_slice(body).ok())
+                .unwrap_or(Value::Null),
+        }
+    }
+
+    /// Replays the response to `request`, or sends it and records the response.
+    pub(crate) async fn send_with_cassette(
+        &self,
+        cassette: &Cassette,
+        request: &impl Fn() -> RequestBuilder,
+    ) -> Result<Response, RequestError> {
+        let recorded = self.recorded_request(&request().build()?);
+        if cassette.is_replay() {
+            let interaction = cassette.find(&recorded)?;
+            let status =
+                StatusCode::from_u16(interaction.status).map_err(|_| RequestError::Parsing {
+                    issue: format!("Invalid recorded status `{}`", interaction.status),
+                })?;
+            let body = match interaction.response {
+                RecordedResponse::Body(body) => body,
+                RecordedResponse::Events(events) => events
+                    .iter()
+                    .map(|event| format!("data: {}\n\n", event.data))
+                    .collect(),
+            };
+            return Ok(replayed_response(status, body));
+        }
+
+        let response = self.send_with_retries(request).await?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.bytes().await?;
+        cassette.append(recorded.into_interaction(
+            status,
+            RecordedResponse::Body(String::from_utf8_lossy(&body).into_owned()),
+        ))?;
+        let mut replayed = http::Response::new(body);
+        *replayed.status_mut() = status;
+        *replayed.headers_mut() = headers;
+        Ok(Response::from(replayed))
+    }
+
+    /// A recorder for the stream of `request` if recording.
+    pub(crate) fn stream_recorder(&self, request: &RequestBuilder) -> Option<Arc<StreamRecorder>> {
+        let cassette = self
+            .cassette
+            .as_ref()
+            .filter(|cassette| !cassette.is_replay())?;
+        let request = request.try_clone()?.build().ok()?;
+        Some(Arc::new(StreamRecorder {
+            cassette: cassette.clone(),
+            request: Mutex::new(Some(self.recorded_request(&request))),
+            started: Instant::now(),
+            events: Mutex::new(Vec::new()),
+        }))
+    }
+
+    /// Replays the stream of `request`, feeding each recorded event to `on_message`.
+    pub(crate) fn replay_stream<T, F>(
+        &self,
+        cassette: &Cassette,
+        request: RequestBuilder,
+        mut on_message: F,
+    ) -> ResultStream<T>
+    where
+        T: Default + Send + 'static,
+        F: FnMut(&str) -> Option<Result<T, CompletionStreamError>> + Send + 'static,
+    {
+        let interaction = request
+            .build()
+            .map_err(RequestError::from)
+            .and_then(|request| cassette.find(&self.recorded_request(&request)));
+        let interaction = match interaction {
+            Ok(interaction) => interaction,
+            Err(error) => return Box::pin(tokio_stream::once(Err(error.into()))),
+        };
+        let events = match interaction.response {
+            RecordedResponse::Events(events) if (200..300).contains(&interaction.status) => events,
+            RecordedResponse::Events(_) | RecordedResponse::Body(_) => {
+                let body = match interaction.response {
+                    RecordedResponse::Body(body) => body,
+                    RecordedResponse::Events(_) => String::new(),
+                };
+                let status = StatusCode::from_u16(interaction.status)
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                let error = RequestError::from_status(status, &body);
+                return Box::pin(tokio_stream::once(Err(error.into())));
+            }
+        };
+        let replay_timing = cassette.0.rules.replay_timing;
+        let mut previous_ms = 0;
+        let events = futures_util::StreamExt::then(tokio_stream::iter(events), move |event| {
+            let delay = event.elapsed_ms.saturating_sub(previous_ms);
+            previous_ms = event.elapsed_ms;
+            async move {
+                if replay_timing {
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                }
+                event
+            }
+        });
+        let items = tokio_stream::StreamExt::map(events, move |event| on_message(&event.data));
+        // Like a live stream, start with an empty item for opening the connection
+        let stream =
+            tokio_stream::StreamExt::chain(tokio_stream::once(Some(Ok(T::default()))), items);
+        let stream = tokio_stream::StreamExt::take_while(stream, Option::is_some);
+        Box::pin(tokio_stream::StreamExt::filter_map(stream, |item| item))
+    }
+}
+
+fn replayed_response(status: StatusCode, body: String) -> Response {
+    let content_type = if body.starts_with("data: ") {
+        "text/event-stream"
+    } else {
+        "application/json"
+    };
+    let mut response = http::Response::new(body);
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, content_type.parse().unwrap());
+    Response::from(response)
+}
@@ -0,0 +1,415 @@
+use llmtoolbox::ToolBox;
+use reqwest::Method;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tokio_stream::StreamExt;
+
+use crate::{
+    ChatChunkStream, CompletionError, CompletionStream, CompletionStreamError, FunctionCallContext,
+    FunctionCallError, LlamaLink, Message, Timings,
+};
+
+/// The result of a chat completion through the OpenAI compatible `/v1/chat/completions` endpoint.
+#[derive(Debug, Clone)]
+pub struct ChatCompletion {
+    pub content: Option<String>,
+    pub tool_calls: Vec<ToolCall>,
+    pub finish_reason: Option<FinishReason>,
+    pub usage: Option<Usage>,
+    /// See [`crate::Completion::tokens_cached`].
+    pub tokens_cached: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// A natural stop point or a stop sequence was reached.
+    Stop,
+    /// The token limit was reached.
+    Length,
+    /// The model called a tool.
+    ToolCalls,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ToolCall {
+    #[serde(default)]
+    pub id: Option<String>,
+    pub function: ToolCallFunction,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ToolCallFunction {
+    pub name: String,
+    /// The arguments as a json encoded string.
+    pub arguments: String,
+}
+
+/// A chunk of [`LlamaLink::create_chat_completion_stream_full`].
+#[derive(Debug, Clone, Default)]
+pub struct ChatCompletionChunk {
+    pub content: String,
+    /// Parts of the tool calls, to be joined by [`ToolCallDelta::index`].
+    pub tool_calls: Vec<ToolCallDelta>,
+    /// Only set on the last chunk of the message.
+    pub finish_reason: Option<FinishReason>,
+    /// Only set on the last chunk, if the server reports it.
+    pub usage: Option<Usage>,
+    /// Only set on the last chunk. See [`crate::Completion::tokens_cached`].
+    pub tokens_cached: Option<usize>,
+}
+
+/// A part of a streamed [`ToolCall`]. The first part of each call carries its `id` and name, the
+/// following parts the next piece of the json encoded arguments.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ToolCallDelta {
+    /// The position of the tool call in the message.
+    pub index: usize,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub function: ToolCallFunctionDelta,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ToolCallFunctionDelta {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The next piece of the json encoded arguments.
+    #[serde(default)]
+    pub arguments: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatResponse {
+    choices: Vec<ChatChoice>,
+    usage: Option<Usage>,
+    timings: Option<Timings>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatChoice {
+    message: ChatResponseMessage,
+    finish_reason: Option<FinishReason>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatResponseMessage {
+    content: Option<String>,
+    tool_calls: Option<Vec<ToolCall>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatChunk {
+    /// Empty in the final usage chunk of OpenAI compatible servers.
+    #[serde(default)]
+    choices: Vec<ChatChunkChoice>,
+    usage: Option<Usage>,
+    timings: Option<Timings>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatChunkChoice {
+    delta: ChatChunkDelta,
+    finish_reason: Option<FinishReason>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatChunkDelta {
+    content: Option<String>,
+    tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+impl From<ChatChunk> for ChatCompletionChunk {
+    fn from(chunk: ChatChunk) -> Self {
+        let (delta, finish_reason) = match chunk.choices.into_iter().next() {
+            Some(choice) => (Some(choice.delta), choice.finish_reason),
+            None => (None, None),
+        };
+        let (content, tool_calls) = delta.map_or_else(Default::default, |delta| {
+            (
+                delta.content.unwrap_or_default(),
+                delta.tool_calls.unwrap_or_default(),
+            )
+        });
+        Self {
+            content,
+            tool_calls,
+            finish_reason,
+            usage: chunk.usage,
+            tokens_cached: chunk.timings.and_then(|timings| timings.cache_n),
+        }
+    }
+}
+
+impl LlamaLink {
+    /// Creates a completion through the OpenAI compatible `/v1/chat/completions` endpoint, where the
+    /// server applies the model's chat template. Requires the server to be started with `--jinja`
+    /// for templates other than the built in ones.
+    pub async fn create_chat_completion(
+        &self,
+        system: &str,
+        messages: &[Message],
+    ) -> Result<String, CompletionError> {
+        self.create_chat_completion_full(system, messages)
+            .await?
+            .content
+            .ok_or_else(|| CompletionError::Parsing {
+                issue: "No `content` field in response message".to_owned(),
+            })
+    }
+
+    /// Like [`LlamaLink::create_chat_completion`], but returns the tool calls, finish reason and usage as well.
+    pub async fn create_chat_completion_full(
+        &self,
+        system: &str,
+        messages: &[Message],
+    ) -> Result<ChatCompletion, CompletionError> {
+        self.check_images(messages).await?;
+        let json = self.chat_request(system, messages);
+        self.send_chat_request(json).await
+    }
+
+    pub fn create_chat_completion_stream(
+        &self,
+        system: &str,
+        messages: &[Message],
+    ) -> CompletionStream {
+        Box::pin(
+            self.create_chat_completion_stream_full(system, messages)
+                .map(|chunk| chunk.map(|chunk| chunk.content)),
+        )
+    }
+
+    /// Like [`LlamaLink::create_chat_completion_stream`], but each chunk also carries the tool call
+    /// parts, and the last one the finish reason and usage.
+    pub fn create_chat_completion_stream_full(
+        &self,
+        system: &str,
+        messages: &[Message],
+    ) -> ChatChunkStream {
+        let json = self.chat_request(system, messages);
+        self.chat_stream(json, messages)
+    }
+
+    /// Streams a reply that may call the functions of `toolbox`, sent as OpenAI `tools`. Unlike
+    /// [`LlamaLink::call_function_chat_full`], the model may also answer with text, and the
+    /// functions are not run. The tool calls arrive as [`ToolCallDelta`]s.
+    pub fn create_chat_completion_stream_with_tools<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        toolbox: &ToolBox<O, E>,
+    ) -> ChatChunkStream {
+        let mut json = self.chat_request(system, messages);
+        json.insert(
+            "tools".to_owned(),
+            Value::Array(openai_tools(toolbox.schema())),
+        );
+        self.chat_stream(json, messages)
+    }
+
+    /// Streams `json` once [`LlamaLink::check_images`] passed for `messages`.
+    fn chat_stream(&self, mut json: Map<String, Value>, messages: &[Message]) -> ChatChunkStream {
+        json.insert("stream".to_owned(), Value::Bool(true));
+        // OpenAI compatible servers only report the usage of a stream when asked to
+        json.insert(
+            "stream_options".to_owned(),
+            json!({ "include_usage": true }),
+        );
+        let json = Value::Object(json);
+
+        self.stream_after_image_check(messages, move |link| {
+            let request = link
+                .stream_request(Method::POST, "v1/chat/completions")
+                .json(&json);
+            link.sse_stream(request, |data| {
+                if data == "[DONE]" {
+                    #[cfg(feature = "tracing")]
+                    tracing::trace!("Chat completion stream received done");
+                    return None;
+                }
+                match serde_json::from_str::<ChatChunk>(data) {
+                    Ok(chunk) => Some(Ok(ChatCompletionChunk::from(chunk))),
+                    Err(err) => {
+                        #[cfg(feature = "tracing")]
+                        tracing::error!("Error in chat completion stream: {:?}", err);
+                        Some(Err(CompletionStreamError::from(err)))
+                    }
+                }
+            })
+        })
+    }
+
+    pub async fn call_function_chat<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<Result<O, E>, FunctionCallError> {
+        self.call_function_chat_full(system, messages, toolbox)
+            .await
+            .map(|e| e.output_result)
+    }
+
+    /// Calls a function in `toolbox` through the server's native tool calling. The toolbox is sent
+    /// as OpenAI `tools` and the model is required to call one of them. `raw_input` is the tool
+    /// call in the same `function_name`/`parameters` shape used by [`LlamaLink::call_function_full`].
+    pub async fn call_function_chat_full<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<FunctionCallContext<O, E>, FunctionCallError> {
+        self.check_images(messages).await?;
+        let mut json = self.chat_request(system, messages);
+        json.insert(
+            "tools".to_owned(),
+            Value::Array(openai_tools(toolbox.schema())),
+        );
+        json.insert(
+            "tool_choice".to_owned(),
+            Value::String("required".to_owned()),
+        );
+
+        let completion = self.send_chat_request(json).await?;
+        let tool_call =
+            completion
+                .tool_calls
+                .into_iter()
+                .next()
+                .ok_or_else(|| FunctionCallError::Parsing {
+                    issue: "The response did not contain a tool call".to_owned(),
+                })?;
+        run_tool_call(toolbox, tool_call).await
+    }
+
+    /// The request for `messages`, with [`crate::Config::cache_prompt`] enabled unless set, since
+    /// every turn repeats the previous ones.
+    fn chat_request(&self, system: &str, messages: &[Message]) -> Map<String, Value> {
+        let mut json = self.request_config.clone();
+        let cache_prompt = json.entry("cache_prompt").or_insert(Value::Null);
+        if cache_prompt.is_null() {
+            *cache_prompt = Value::Bool(true);
+        }
+        json.insert(
+            "messages".to_owned(),
+            Value::Array(chat_messages(system, messages)),
+        );
+        json
+    }
+
+    async fn send_chat_request(
+        &self,
+        json: Map<String, Value>,
+    ) -> Result<ChatCompletion, CompletionError> {
+        let json = Value::Object(json);
+        let _permit = self.acquire_permit().await?;
+        let response = self
+            .send(|| {
+                self.request(Method::POST, "v1/chat/completions")
+                    .json(&json)
+            })
+            .await?;
+
+        let response_body: ChatResponse = response.json().await?;
+        let choice =
+            response_body
+                .choices
+                .into_iter()
+                .next()
+                .ok_or_else(|| CompletionError::Parsing {
+                    issue: "No `choices` in response body".to_owned(),
+                })?;
+        Ok(ChatCompletion {
+            content: choice.message.content,
+            tool_calls: choice.message.tool_calls.unwrap_or_default(),
+            finish_reason: choice.finish_reason,
+            usage: response_body.usage,
+            tokens_cached: response_body.timings.and_then(|timings| timings.cache_n),
+        })
+    }
+}
+
+/// Converts the messages into the OpenAI chat format, with `system` as the first message if not
+/// empty. Images are sent as data urls in `image_url` parts.
+pub(crate) fn chat_messages(system: &str, messages: &[Message]) -> Vec<Value> {
+    let system = (!system.is_empty()).then(|| json!({ "role": "system", "content": system }));
+    system
+        .into_iter()
+        .chain(messages.iter().map(|message| match message {
+            Message::User(text) => json!({ "role": "user", "content": text }),
+            Message::Assistant(text) => json!({ "role": "assistant", "content": text }),
+            Message::UserWithImages { text, images } => {
+                let images = images.iter().map(|image| {
+                    json!({ "type": "image_url", "image_url": { "url": image.data_url() } })
+                });
+                let content: Vec<Value> = std::iter::once(json!({ "type": "text", "text": text }))
+                    .chain(images)
+                    .collect();
+                json!({ "role": "user", "content": content })
+            }
+        }))
+        .collect()
+}
+
+/// Calls the function of `tool_call` in `toolbox`. `raw_input` is the tool call in the same
+/// `function_name`/`parameters` shape used by [`LlamaLink::call_function_full`].
+pub(crate) async fn run_tool_call<O, E>(
+    toolbox: &ToolBox<O, E>,
+    tool_call: ToolCall,
+) -> Result<FunctionCallContext<O, E>, FunctionCallError> {
+    #[cfg(feature = "tracing")]
+    tracing::debug!("Raw tool_call response:\n`{:?}`", &tool_call);
+    let parameters: Value = serde_json::from_str(&tool_call.function.arguments).map_err(|_| {
+        FunctionCallError::Parsing {
+            issue: "Could not parse tool call arguments into valid json".to_owned(),
+        }
+    })?;
+    let tool_call = json!({
+        "function_name": tool_call.function.name,
+        "parameters": parameters,
+    });
+    let raw_input = tool_call.to_string();
+    let output_result = toolbox.call_from_value(tool_call).await?;
+    Ok(FunctionCallContext {
+        output_result,
+        raw_input,
+    })
+}
+
+/// Converts a toolbox schema, a `oneOf` over `function_name`/`parameters` objects, into OpenAI `tools`.
+pub(crate) fn openai_tools(schema: &Map<String, Value>) -> Vec<Value> {
+    let Some(Value::Array(functions)) = schema.get("oneOf") else {
+        return Vec::new();
+    };
+    functions
+        .iter()
+        .filter_map(|function| {
+            let properties = function.get("properties")?;
+            let name = properties.get("function_name")?.get("const")?;
+            let mut definition = Map::new();
+            definition.insert("name".to_owned(), name.clone());
+            if let Some(description) = function.get("description") {
+                definition.insert("description".to_owned(), description.clone());
+            }
+            definition.insert(
+                "parameters".to_owned(),
+                properties
+                    .get("parameters")
+                    .cloned()
+                    .unwrap_or_else(|| json!({ "type": "object", "properties": {} })),
+            );
+            Some(json!({ "type": "function", "function": definition }))
+        })
+        .collect()
+}
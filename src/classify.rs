@@ -0,0 +1,156 @@
+use serde_json::Value;
+
+use crate::{
+    multimodal_prompt, CompletionError, LlamaLink, Message, PromptFormatter, TokenProbability,
+};
+
+/// The label chosen by [`LlamaLink::classify`] and the probability of every label.
+#[derive(Debug, Clone)]
+pub struct Classification {
+    /// The label that was generated.
+    pub label: String,
+    /// Every label with its probability, in the order given. Sums to one unless no label could be scored.
+    pub distribution: Vec<(String, f64)>,
+}
+
+impl Classification {
+    pub fn prob(&self, label: &str) -> Option<f64> {
+        self.distribution
+            .iter()
+            .find(|(other, _)| other == label)
+            .map(|(_, prob)| *prob)
+    }
+
+    /// The label with the highest probability, which may differ from [`Classification::label`]
+    /// when sampling with a non-zero temperature.
+    pub fn most_likely(&self) -> Option<&str> {
+        self.distribution
+            .iter()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(label, _)| label.as_str())
+    }
+}
+
+impl LlamaLink {
+    /// Constrains generation to exactly one of `labels` with a grammar and scores every label from
+    /// the token probabilities. `labels` must be unique. Fails with
+    /// [`CompletionError::InvalidRequest`] if `labels` is empty.
+    pub async fn classify<L: AsRef<str>>(
+        &self,
+        prompt: String,
+        labels: &[L],
+    ) -> Result<Classification, CompletionError> {
+        self.classify_prompt(Value::String(prompt), labels).await
+    }
+
+    /// Sends the images of `messages` as well, see [`Message::UserWithImages`].
+    pub async fn classify_with_format<L: AsRef<str>>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+        labels: &[L],
+    ) -> Result<Classification, CompletionError> {
+        self.check_images(messages).await?;
+        let prompt = multimodal_prompt((formatter.0)(system, messages), messages);
+        self.classify_prompt(prompt, labels).await
+    }
+
+    /// Classifies `prompt`, a string or a multimodal prompt.
+    async fn classify_prompt<L: AsRef<str>>(
+        &self,
+        prompt: Value,
+        labels: &[L],
+    ) -> Result<Classification, CompletionError> {
+        let labels: Vec<&str> = labels.iter().map(AsRef::as_ref).collect();
+        if labels.is_empty() {
+            return Err(CompletionError::InvalidRequest {
+                issue: "Classification needs at least one label".to_owned(),
+            });
+        }
+        let mut json = self.completion_request(prompt);
+        json.insert("grammar".to_owned(), Value::String(labels_grammar(&labels)));
+        // Post sampling probabilities include the grammar, so only tokens leading to a label are reported
+        json.insert("post_sampling_probs".to_owned(), Value::Bool(true));
+        let n_probs = json
+            .get("n_probs")
+            .and_then(Value::as_u64)
+            .unwrap_or(0)
+            .max(labels.len() as u64);
+        json.insert("n_probs".to_owned(), Value::from(n_probs));
+
+        let completion = self.send_completion_request(json).await?;
+        if !labels.contains(&completion.content.as_str()) {
+            return Err(CompletionError::Parsing {
+                issue: format!(
+                    "Generated `{}` which is not one of the labels",
+                    completion.content
+                ),
+            });
+        }
+        let distribution = label_distribution(&labels, &completion.probabilities);
+        Ok(Classification {
+            label: completion.content,
+            distribution,
+        })
+    }
+}
+
+/// A GBNF grammar matching exactly one of the labels.
+fn labels_grammar(labels: &[&str]) -> String {
+    let alternatives: Vec<String> = labels
+        .iter()
+        .map(|label| {
+            let escaped = label
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r");
+            format!("\"{escaped}\"")
+        })
+        .collect();
+    format!("root ::= {}", alternatives.join(" | "))
+}
+
+/// Approximates the probability of each label by following the generated tokens. A label that
+/// diverges from the generated path at some position is scored with the probability of the path up
+/// to that position times the probability of the candidates there that continue the label. The
+/// result is normalized to sum to one.
+fn label_distribution(labels: &[&str], probabilities: &[TokenProbability]) -> Vec<(String, f64)> {
+    let mut scores = vec![0.0; labels.len()];
+    let mut generated = String::new();
+    let mut path_prob = 1.0;
+    for token in probabilities {
+        for (label, score) in labels.iter().zip(scores.iter_mut()) {
+            let Some(rest) = label.strip_prefix(generated.as_str()) else {
+                continue;
+            };
+            if rest.starts_with(token.token.as_str()) && !token.token.is_empty() {
+                continue;
+            }
+            *score += token
+                .top
+                .iter()
+                .filter(|candidate| {
+                    !candidate.token.is_empty() && rest.starts_with(&candidate.token)
+                })
+                .map(|candidate| path_prob * candidate.prob())
+                .sum::<f64>();
+        }
+        generated.push_str(&token.token);
+        path_prob *= token.prob();
+    }
+    if let Some(index) = labels.iter().position(|label| *label == generated) {
+        scores[index] += path_prob;
+    }
+
+    let total: f64 = scores.iter().sum();
+    labels
+        .iter()
+        .zip(scores)
+        .map(|(label, score)| {
+            let prob = if total > 0.0 { score / total } else { 0.0 };
+            (label.to_string(), prob)
+        })
+        .collect()
+}
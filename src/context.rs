@@ -0,0 +1,253 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{CompletionError, Config, Conversation, LlamaLink, Message, ServerError};
+
+/// Keeps the prompt of a [`Conversation`] within the model's context window, leaving room for the
+/// reply. Checked before every request of the conversation. The tokens of images are not counted.
+#[derive(Serialize, Deserialize, Debug, Clone, bon::Builder)]
+pub struct ContextManagement {
+    #[builder(default)]
+    strategy: TruncationStrategy,
+    #[builder(default)]
+    counter: TokenCounter,
+    /// The context size in tokens. Fetched from `n_ctx` of `GET /props` if not set.
+    n_ctx: Option<usize>,
+    /// The tokens left for the reply when the config does not set `n_predict`.
+    #[builder(default = 512)]
+    reserve: usize,
+}
+
+/// Which history is dropped once the prompt does not fit. History is always dropped in whole turns,
+/// a user message and the replies to it. If the prompt still does not fit once nothing more can be
+/// dropped, the request fails with [`CompletionError::ContextOverflow`] without being sent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub enum TruncationStrategy {
+    #[default]
+    DropOldest,
+    /// Keeps the first `first` turns, e.g. instructions or examples, and at least the last `last`
+    /// turns, dropping the oldest turns in between.
+    KeepFirstLast { first: usize, last: usize },
+    /// Drops the oldest turns like [`TruncationStrategy::DropOldest`], but has the model summarize
+    /// them, along with any previous summary, into at most `max_tokens` tokens. The summary is
+    /// appended to the system prompt, and room for `max_tokens` is reserved whenever it is
+    /// regenerated.
+    Summarize { max_tokens: usize },
+}
+
+/// How the tokens of a prompt are counted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub enum TokenCounter {
+    /// Tokenizes every checked prompt with `POST /tokenize`. Exact, but one request per check.
+    #[default]
+    Server,
+    /// Tokenizes once with `POST /tokenize` to measure the characters per token of the
+    /// conversation, then estimates from that ratio.
+    Calibrated,
+    /// Estimates from a fixed ratio, e.g. `4.0` for English text, without any request.
+    Estimate { chars_per_token: f32 },
+}
+
+#[derive(Deserialize)]
+struct Props {
+    default_generation_settings: GenerationSettings,
+}
+
+#[derive(Deserialize)]
+struct GenerationSettings {
+    n_ctx: usize,
+}
+
+impl Conversation {
+    /// The system prompt including the summary of dropped turns.
+    pub(crate) fn effective_system(&self) -> String {
+        self.system_with_summary(self.summary.as_deref())
+    }
+
+    fn system_with_summary(&self, summary: Option<&str>) -> String {
+        match summary {
+            Some(summary) => format!(
+                "{}\n\nSummary of the earlier conversation:\n{summary}",
+                self.system
+            ),
+            None => self.system.clone(),
+        }
+    }
+
+    /// The summary of the turns dropped by [`TruncationStrategy::Summarize`].
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    /// Drops history until the prompt for `message` fits the context window, if
+    /// [`ContextManagement`] is configured. Called by every request of the conversation. The
+    /// history is left unchanged if it cannot fit or the summary fails.
+    pub async fn fit_context(
+        &mut self,
+        link: &LlamaLink,
+        message: &str,
+    ) -> Result<(), CompletionError> {
+        let Some(context) = self.context.clone() else {
+            return Ok(());
+        };
+        let link = self.link(link);
+        let n_ctx = match context.n_ctx.or(self.n_ctx) {
+            Some(n_ctx) => n_ctx,
+            None => {
+                let props: Props = link.get_json("props").await?;
+                let n_ctx = props.default_generation_settings.n_ctx;
+                self.n_ctx = Some(n_ctx);
+                n_ctx
+            }
+        };
+        let reserve = link
+            .request_config
+            .get("n_predict")
+            .and_then(Value::as_u64)
+            .map_or(context.reserve, |n_predict| n_predict as usize);
+        let summary_reserve = match context.strategy {
+            TruncationStrategy::Summarize { max_tokens } => max_tokens,
+            _ => 0,
+        };
+        let budget = n_ctx.saturating_sub(reserve);
+
+        let mut history = self.history.clone();
+        let mut dropped = Vec::new();
+        loop {
+            // Once a turn is dropped the summary is regenerated, so room for the longest new
+            // summary is reserved instead of counting the current one
+            let regenerate_summary = summary_reserve > 0 && !dropped.is_empty();
+            let summary = if regenerate_summary {
+                Some("")
+            } else {
+                self.summary.as_deref()
+            };
+            let prompt = self.prompt_with(&history, summary, message);
+            let mut tokens = self.count_tokens(&link, &context.counter, &prompt).await?;
+            if regenerate_summary {
+                tokens += summary_reserve;
+            }
+            if tokens <= budget {
+                break;
+            }
+            let Some(turn) = droppable_turn(&history, &context.strategy) else {
+                return Err(CompletionError::ContextOverflow {
+                    server_error: ServerError {
+                        message: format!(
+                            "The prompt needs {tokens} tokens but only {budget} of the {n_ctx} \
+                             tokens of the context are left after reserving {reserve} for the \
+                             reply, and no more history can be dropped"
+                        ),
+                        ..Default::default()
+                    },
+                    n_prompt_tokens: Some(tokens),
+                    n_ctx: Some(n_ctx),
+                });
+            };
+            #[cfg(feature = "tracing")]
+            tracing::debug!("Dropping a turn from a prompt of {tokens} tokens to fit {budget}");
+            dropped.extend(history.drain(turn));
+        }
+
+        if let (TruncationStrategy::Summarize { max_tokens }, false) =
+            (&context.strategy, dropped.is_empty())
+        {
+            self.summary = Some(self.summarize(&link, &dropped, *max_tokens).await?);
+        }
+        self.history = history;
+        Ok(())
+    }
+
+    /// The prompt for `history` followed by `message`, with `summary` in the system prompt.
+    fn prompt_with(&self, history: &[Message], summary: Option<&str>, message: &str) -> String {
+        let mut messages = history.to_vec();
+        messages.push(Message::User(message.to_owned()));
+        (self.formatter.0)(&self.system_with_summary(summary), &messages)
+    }
+
+    async fn count_tokens(
+        &mut self,
+        link: &LlamaLink,
+        counter: &TokenCounter,
+        prompt: &str,
+    ) -> Result<usize, CompletionError> {
+        let chars = prompt.chars().count();
+        match counter {
+            TokenCounter::Server => Ok(link.tokenize(prompt).await?.len()),
+            TokenCounter::Calibrated => {
+                let chars_per_token = match self.chars_per_token {
+                    Some(chars_per_token) => chars_per_token,
+                    None => {
+                        let tokens = link.tokenize(prompt).await?.len().max(1);
+                        let chars_per_token = chars as f32 / tokens as f32;
+                        self.chars_per_token = Some(chars_per_token);
+                        chars_per_token
+                    }
+                };
+                Ok(estimate(chars, chars_per_token))
+            }
+            TokenCounter::Estimate { chars_per_token } => Ok(estimate(chars, *chars_per_token)),
+        }
+    }
+
+    async fn summarize(
+        &self,
+        link: &LlamaLink,
+        dropped: &[Message],
+        max_tokens: usize,
+    ) -> Result<String, CompletionError> {
+        let mut transcript = String::new();
+        if let Some(summary) = &self.summary {
+            transcript.push_str(&format!(
+                "Summary of the earlier conversation:\n{summary}\n\n"
+            ));
+        }
+        for message in dropped {
+            let role = if message.is_user() {
+                "User"
+            } else {
+                "Assistant"
+            };
+            transcript.push_str(&format!("{role}: {}\n", message.text()));
+        }
+        let prompt = (self.formatter.0)(
+            "Summarize the conversation below in a few sentences. Keep the facts, names and \
+             decisions needed to continue it.",
+            &[Message::User(transcript)],
+        );
+        let summary = link
+            .with_overrides(Config::builder().n_predict(max_tokens).build())
+            .create_completion(prompt)
+            .await?;
+        Ok(summary.trim().to_owned())
+    }
+}
+
+/// The range of the next turn of `history` to drop, `None` if the strategy keeps everything left.
+fn droppable_turn(
+    history: &[Message],
+    strategy: &TruncationStrategy,
+) -> Option<std::ops::Range<usize>> {
+    let starts: Vec<usize> = history
+        .iter()
+        .enumerate()
+        .filter(|(index, message)| *index == 0 || message.is_user())
+        .map(|(index, _)| index)
+        .collect();
+    let turn = match strategy {
+        TruncationStrategy::DropOldest | TruncationStrategy::Summarize { .. } => 0,
+        TruncationStrategy::KeepFirstLast { first, last } => {
+            if starts.len() <= first + last {
+                return None;
+            }
+            *first
+        }
+    };
+    let start = *starts.get(turn)?;
+    let end = starts.get(turn + 1).copied().unwrap_or(history.len());
+    Some(start..end)
+}
+
+fn estimate(chars: usize, chars_per_token: f32) -> usize {
+    (chars as f32 / chars_per_token.max(f32::EPSILON)).ceil() as usize
+}
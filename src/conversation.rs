@@ -0,0 +1,306 @@
+use std::{
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use llmtoolbox::ToolBox;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio_stream::Stream;
+
+use crate::{
+    affinity::new_session, CompletionChunkStream, CompletionError, CompletionStreamError, Config,
+    ContextManagement, FunctionCallContext, FunctionCallError, LlamaLink, Message, PromptFormatter,
+    SlotAffinity,
+};
+
+/// A chat session owning the system prompt, the message history, the formatter and config
+/// overrides, so callers do not re-pass them on every call. Replies are appended to the history
+/// automatically, and only once the request succeeded.
+///
+/// Saved as json with [`Conversation::to_json`] or [`Conversation::save`]. The formatter is a
+/// function and is not saved, so it is passed again when loading.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Conversation {
+    pub(crate) system: String,
+    pub(crate) history: Vec<Message>,
+    /// The non null fields of the [`Config`] overrides.
+    #[serde(default)]
+    overrides: Map<String, Value>,
+    #[serde(default)]
+    max_messages: Option<usize>,
+    #[serde(default)]
+    pub(crate) context: Option<ContextManagement>,
+    /// The summary of the turns dropped to fit the context.
+    #[serde(default)]
+    pub(crate) summary: Option<String>,
+    #[serde(default = "new_session")]
+    pub(crate) session: String,
+    #[serde(default)]
+    pub(crate) affinity: SlotAffinity,
+    #[serde(skip)]
+    pub(crate) formatter: PromptFormatter,
+    /// The context size fetched from the server.
+    #[serde(skip)]
+    pub(crate) n_ctx: Option<usize>,
+    /// Measured by [`crate::TokenCounter::Calibrated`].
+    #[serde(skip)]
+    pub(crate) chars_per_token: Option<f32>,
+    #[serde(skip)]
+    pub(crate) tokens_cached: Option<usize>,
+}
+
+#[bon::bon]
+impl Conversation {
+    /// - `system`: The system prompt. Empty by default.
+    /// - `formatter`: Formats the history into a prompt. Defaults to [`PromptFormatter::default`].
+    /// - `overrides`: Applied over the link's config for every request of the conversation, see
+    ///   [`LlamaLink::with_overrides`].
+    /// - `max_messages`: The budget of messages kept in the history. The oldest messages are
+    ///   dropped once it is exceeded, so the history still starts with a user message.
+    /// - `context`: Keeps the prompt within the model's context window, counted in tokens.
+    /// - `history`: Messages to resume from.
+    /// - `session`: The key the slot is derived from, e.g. a user or chat id. Random by default.
+    /// - `affinity`: Which slot the requests are sent to, so the server reuses the cached prompt
+    ///   of the previous turns. Defaults to [`SlotAffinity::Session`].
+    #[builder]
+    pub fn new(
+        #[builder(into, default)] system: String,
+        #[builder(default)] formatter: PromptFormatter,
+        overrides: Option<Config>,
+        max_messages: Option<usize>,
+        context: Option<ContextManagement>,
+        #[builder(default)] history: Vec<Message>,
+        #[builder(into)] session: Option<String>,
+        #[builder(default)] affinity: SlotAffinity,
+    ) -> Self {
+        let overrides = overrides
+            .map(Config::into_map)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(_, value)| !value.is_null())
+            .collect();
+        let mut conversation = Self {
+            system,
+            history,
+            overrides,
+            max_messages,
+            context,
+            summary: None,
+            session: session.unwrap_or_else(new_session),
+            affinity,
+            formatter,
+            n_ctx: None,
+            chars_per_token: None,
+            tokens_cached: None,
+        };
+        conversation.enforce_budget();
+        conversation
+    }
+
+    pub fn system(&self) -> &str {
+        &self.system
+    }
+
+    pub fn set_system(&mut self, system: impl Into<String>) {
+        self.system = system.into();
+    }
+
+    pub fn history(&self) -> &[Message] {
+        &self.history
+    }
+
+    /// Appends `message` without sending anything, e.g. to seed the conversation with examples.
+    pub fn push(&mut self, message: Message) {
+        self.history.push(message);
+        self.enforce_budget();
+    }
+
+    /// Clears the history and its summary.
+    pub fn clear(&mut self) {
+        self.history.clear();
+        self.summary = None;
+    }
+
+    pub fn formatter(&self) -> &PromptFormatter {
+        &self.formatter
+    }
+
+    pub fn set_formatter(&mut self, formatter: PromptFormatter) {
+        self.formatter = formatter;
+    }
+
+    /// The prompt for the history followed by `message`.
+    pub fn prompt(&self, message: &str) -> String {
+        let messages = self.messages(Message::User(message.to_owned()));
+        (self.formatter.0)(&self.effective_system(), &messages)
+    }
+
+    /// The history followed by `message`.
+    fn messages(&self, message: Message) -> Vec<Message> {
+        let mut messages = self.history.clone();
+        messages.push(message);
+        messages
+    }
+
+    pub(crate) fn link(&self, link: &LlamaLink) -> LlamaLink {
+        link.with_override_map(self.overrides.clone())
+    }
+
+    fn append_turn(&mut self, message: Message, reply: String) {
+        self.history.push(message);
+        self.history.push(Message::Assistant(reply));
+        self.enforce_budget();
+    }
+
+    fn enforce_budget(&mut self) {
+        let Some(max_messages) = self.max_messages else {
+            return;
+        };
+        let mut excess = self.history.len().saturating_sub(max_messages);
+        // The formatters expect the history to start with a user message
+        while excess < self.history.len()
+            && matches!(self.history.get(excess), Some(Message::Assistant(_)))
+        {
+            excess += 1;
+        }
+        self.history.drain(..excess);
+    }
+
+    /// Sends the user message `message`, text or a [`Message::UserWithImages`], and appends it and
+    /// the reply to the history.
+    pub async fn send(
+        &mut self,
+        link: &LlamaLink,
+        message: impl Into<Message>,
+    ) -> Result<String, CompletionError> {
+        let message = message.into();
+        self.fit_context(link, message.text()).await?;
+        let completion = self
+            .request_link(link)
+            .await
+            .create_completion_with_format_full(
+                &self.effective_system(),
+                &self.messages(message.clone()),
+                &self.formatter,
+            )
+            .await?;
+        self.tokens_cached = completion.tokens_cached;
+        self.append_turn(message, completion.content.clone());
+        Ok(completion.content)
+    }
+
+    /// Streams the reply to `message`. Both are appended to the history once the stream ends
+    /// without an error. Fails before streaming if the prompt cannot fit the context.
+    pub async fn send_stream(
+        &mut self,
+        link: &LlamaLink,
+        message: impl Into<Message>,
+    ) -> Result<ConversationStream<'_>, CompletionError> {
+        let message = message.into();
+        self.fit_context(link, message.text()).await?;
+        let stream = self
+            .request_link(link)
+            .await
+            .create_formatted_completion_stream_full(
+                &self.effective_system(),
+                &self.messages(message.clone()),
+                &self.formatter,
+            );
+        Ok(ConversationStream {
+            conversation: self,
+            message: Some(message),
+            reply: String::new(),
+            stream,
+        })
+    }
+
+    pub async fn call_function<O, E>(
+        &mut self,
+        link: &LlamaLink,
+        message: impl Into<Message>,
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<Result<O, E>, FunctionCallError> {
+        self.call_function_full(link, message, toolbox)
+            .await
+            .map(|e| e.output_result)
+    }
+
+    /// Calls a function for `message`. The generated tool call is appended as the reply.
+    pub async fn call_function_full<O, E>(
+        &mut self,
+        link: &LlamaLink,
+        message: impl Into<Message>,
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<FunctionCallContext<O, E>, FunctionCallError> {
+        let message = message.into();
+        self.fit_context(link, message.text()).await?;
+        let context = self
+            .request_link(link)
+            .await
+            .call_function_with_format_full(
+                &self.effective_system(),
+                &self.messages(message.clone()),
+                &self.formatter,
+                toolbox,
+            )
+            .await?;
+        self.append_turn(message, context.raw_input.clone());
+        Ok(context)
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    pub fn from_json(json: &str, formatter: PromptFormatter) -> serde_json::Result<Self> {
+        let mut conversation: Self = serde_json::from_str(json)?;
+        conversation.formatter = formatter;
+        Ok(conversation)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_json()?)
+    }
+
+    pub fn load(path: impl AsRef<Path>, formatter: PromptFormatter) -> std::io::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(Self::from_json(&json, formatter)?)
+    }
+}
+
+/// The reply of [`Conversation::send_stream`]. The turn is appended to the conversation when the
+/// stream ends.
+pub struct ConversationStream<'a> {
+    conversation: &'a mut Conversation,
+    /// Taken once the turn is appended, or the stream failed.
+    message: Option<Message>,
+    reply: String,
+    stream: CompletionChunkStream,
+}
+
+impl Stream for ConversationStream<'_> {
+    type Item = Result<String, CompletionStreamError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = self.stream.as_mut().poll_next(cx);
+        match &item {
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.reply.push_str(&chunk.content);
+                if chunk.tokens_cached.is_some() {
+                    self.conversation.tokens_cached = chunk.tokens_cached;
+                }
+            }
+            Poll::Ready(Some(Err(_))) => self.message = None,
+            Poll::Ready(None) => {
+                if let Some(message) = self.message.take() {
+                    let reply = std::mem::take(&mut self.reply);
+                    self.conversation.append_turn(message, reply);
+                }
+            }
+            Poll::Pending => {}
+        }
+        item.map(|item| item.map(|chunk| chunk.map(|chunk| chunk.content)))
+    }
+}
@@ -1,46 +1,321 @@
+use std::fmt;
+
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::NodeId;
+
 error_set::error_set! {
-    CompletionError = {
-        #[display("ApiError: An error occurred related to calling llama.cpp: {issue}")]
-        Api {
-            issue: String
+    /// An error from a llama.cpp server endpoint that is not a completion, e.g. slot management.
+    RequestError = {
+        /// The request could not be sent or the response could not be received, e.g. the server is
+        /// not reachable.
+        #[display("TransportError: Could not reach the llama.cpp server: {0}")]
+        Transport(reqwest::Error),
+        #[display("TimeoutError: The llama.cpp server did not respond in time: {0}")]
+        Timeout(reqwest::Error),
+        /// The server responded with an error status not covered by a more specific variant.
+        #[display("HttpError: The llama.cpp server responded with `{status}`: {server_error}")]
+        Http {
+            status: StatusCode,
+            server_error: ServerError,
         },
+        /// The prompt does not fit in the context window of a slot. `n_prompt_tokens` and `n_ctx`, the
+        /// context size of a slot, are set if the server reports them.
+        #[display("ContextOverflowError: The prompt does not fit in the context window: {server_error}")]
+        ContextOverflow {
+            server_error: ServerError,
+            n_prompt_tokens: Option<usize>,
+            n_ctx: Option<usize>,
+        },
+        /// The server is still loading the model, or has no free slot.
+        #[display("UnavailableError: The llama.cpp server is unavailable: {server_error}")]
+        Unavailable {
+            server_error: ServerError,
+        },
+        #[display("UnauthorizedError: The llama.cpp server rejected the api key")]
+        Unauthorized,
+        #[display("QueueTimeoutError: Timed out waiting for a place in the request queue")]
+        QueueTimeout,
         #[display("ParsingError: {issue}")]
         Parsing {
             issue: String,
-        }
+        },
+        /// A replaying [`crate::Cassette`] has no interaction matching the request.
+        #[display("UnmatchedRequestError: No recorded interaction matches `{method} {path}`")]
+        UnmatchedRequest {
+            method: String,
+            path: String,
+        },
+        #[display("RecordingError: Could not record the interaction: {issue}")]
+        Recording {
+            issue: String,
+        },
+        /// The request was rejected before being sent, e.g. classifying into no labels or an invalid
+        /// url.
+        #[display("InvalidRequestError: {issue}")]
+        InvalidRequest {
+            issue: String,
+        },
+        /// The backend cannot perform the operation, e.g. grammar constraints on Ollama.
+        #[display("UnsupportedError: {operation} is not supported by {backend}")]
+        Unsupported {
+            operation: String,
+            backend: String,
+        },
     };
+    CompletionError = RequestError;
     FunctionCallError = {
         #[display("The function with name `{function_name}` was not found in the toolbox")]
         FunctionNotFound {
             function_name: String,
         },
     } || CompletionError;
+    /// An error of a [`crate::ConversationTree`] operation.
+    BranchError = {
+        #[display("No node with id `{id}` in the conversation tree")]
+        UnknownNode {
+            id: NodeId,
+        },
+        #[display("Node `{id}` is not a reply to a user message")]
+        NotAReply {
+            id: NodeId,
+        },
+        #[display("Node `{id}` is not a user message")]
+        NotAUserMessage {
+            id: NodeId,
+        },
+    } || CompletionError;
 
     CompletionStreamError = {
         Deserialization(serde_json::Error),
-        SSE(reqwest_eventsource::Error)
+        SSE(Box<reqwest_eventsource::Error>),
+        #[display("Timed out waiting for the next token of the completion stream")]
+        StreamTimeout,
+    } || RequestError;
+
+    /// An error building a [`crate::LlamaLink`] or [`crate::LlamaPool`].
+    BuildError = {
+        #[display("Invalid llama.cpp server url `{url}`: {issue}")]
+        InvalidUrl {
+            url: String,
+            issue: String,
+        },
+        #[display("`connect_timeout` cannot be applied to a provided client, configure it on the client instead")]
+        ConnectTimeoutWithClient,
+        #[display("Invalid user agent `{user_agent}`")]
+        InvalidUserAgent {
+            user_agent: String,
+        },
+        #[display("Could not build the http client: {0}")]
+        Client(reqwest::Error),
+        #[display("A pool needs at least one server")]
+        NoServers,
+    };
+
+    /// An error loading a [`crate::Image`].
+    ImageError = {
+        #[display("Could not read the image: {0}")]
+        Io(std::io::Error),
+        #[display("Unsupported image format, expected png, jpeg, gif, webp or bmp")]
+        UnsupportedFormat,
+        #[display("Invalid data url: {issue}")]
+        InvalidDataUrl {
+            issue: String,
+        },
     };
 }
 
-impl From<serde_json::Error> for CompletionError {
-    fn from(error: serde_json::Error) -> Self {
-        Self::Parsing {
-            issue: error.to_string(),
+/// The error body returned by the llama.cpp server, e.g.
+/// `{"error": {"code": 400, "message": "...", "type": "exceed_context_size_error"}}`.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ServerError {
+    /// The error type, e.g. `invalid_request_error`. Empty if the body was not a llama.cpp error.
+    #[serde(default, rename = "type")]
+    pub kind: String,
+    /// The error message, or the raw body if it was not a llama.cpp error. Ollama errors only have a
+    /// message.
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub code: Option<u16>,
+    /// Fields not modeled by this crate, e.g. `n_prompt_tokens` of a context overflow.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl ServerError {
+    pub(crate) fn parse(body: &str) -> Self {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum ErrorField {
+            LlamaCpp(ServerError),
+            Ollama(String),
+        }
+        #[derive(Deserialize)]
+        struct ErrorBody {
+            error: ErrorField,
+        }
+        match serde_json::from_str::<ErrorBody>(body) {
+            Ok(ErrorBody {
+                error: ErrorField::LlamaCpp(error),
+            }) => error,
+            Ok(ErrorBody {
+                error: ErrorField::Ollama(message),
+            }) => ServerError {
+                message,
+                ..Default::default()
+            },
+            Err(_) => ServerError {
+                message: body.trim().to_owned(),
+                ..Default::default()
+            },
         }
     }
+
+    fn extra_usize(&self, key: &str) -> Option<usize> {
+        self.extra
+            .get(key)
+            .and_then(Value::as_u64)
+            .map(|value| value as usize)
+    }
 }
 
-impl From<reqwest::Error> for CompletionError {
-    fn from(error: reqwest::Error) -> Self {
-        Self::Api {
-            issue: error.to_string(),
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.kind.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.kind, self.message)
+        }
+    }
+}
+
+impl RequestError {
+    /// Classifies an error response of the server.
+    pub(crate) fn from_status(status: StatusCode, body: &str) -> Self {
+        let server_error = ServerError::parse(body);
+        if status == StatusCode::UNAUTHORIZED {
+            return Self::Unauthorized;
+        }
+        // Older servers report an overflow as an `invalid_request_error`
+        if server_error.kind == "exceed_context_size_error"
+            || server_error
+                .message
+                .contains("exceeds the available context size")
+        {
+            return Self::ContextOverflow {
+                n_prompt_tokens: server_error.extra_usize("n_prompt_tokens"),
+                n_ctx: server_error.extra_usize("n_ctx"),
+                server_error,
+            };
+        }
+        if status == StatusCode::SERVICE_UNAVAILABLE || server_error.kind == "unavailable_error" {
+            return Self::Unavailable { server_error };
+        }
+        Self::Http {
+            status,
+            server_error,
+        }
+    }
+}
+
+/// Implements the classification of [`RequestError`] for it and every error set containing it.
+/// `retryable` and `not_retryable` list the variants a set adds, so each match stays exhaustive,
+/// and `client_timeouts` the retryable ones raised by a timeout of the client.
+macro_rules! classify_errors {
+    ($($error:ident {
+        retryable: [$($retryable:pat),*],
+        not_retryable: [$($not_retryable:pat),*]
+        $(, client_timeouts: [$($client_timeout:pat),*])?
+    })*) => {$(
+        impl $error {
+            /// Whether the same request may succeed when sent again, possibly to another server.
+            pub fn is_retryable(&self) -> bool {
+                match self {
+                    Self::Transport(_)
+                    | Self::Timeout(_)
+                    | Self::Unavailable { .. }
+                    | Self::QueueTimeout
+                    $(| $retryable)* => true,
+                    Self::Http { status, .. } => retryable_status(*status),
+                    Self::ContextOverflow { .. }
+                    | Self::Unauthorized
+                    | Self::Parsing { .. }
+                    | Self::UnmatchedRequest { .. }
+                    | Self::Recording { .. }
+                    | Self::InvalidRequest { .. }
+                    | Self::Unsupported { .. }
+                    $(| $not_retryable)* => false,
+                }
+            }
+
+            /// Whether a timeout of the client caused the error, e.g. waiting in the queue of the
+            /// [`crate::ConcurrencyLimit`]. It says nothing about the health of the server.
+            pub fn is_client_timeout(&self) -> bool {
+                match self {
+                    // A connect timeout means the server could not be reached
+                    Self::Timeout(error) => !error.is_connect(),
+                    Self::QueueTimeout $($(| $client_timeout)*)? => true,
+                    _ => false,
+                }
+            }
         }
+    )*};
+}
+
+classify_errors! {
+    RequestError { retryable: [], not_retryable: [] }
+    CompletionError { retryable: [], not_retryable: [] }
+    FunctionCallError { retryable: [], not_retryable: [Self::FunctionNotFound { .. }] }
+    BranchError {
+        retryable: [],
+        not_retryable: [
+            Self::UnknownNode { .. },
+            Self::NotAReply { .. },
+            Self::NotAUserMessage { .. }
+        ]
+    }
+    CompletionStreamError {
+        retryable: [Self::StreamTimeout],
+        not_retryable: [Self::Deserialization(_), Self::SSE(_)],
+        client_timeouts: [Self::StreamTimeout]
     }
 }
 
+fn retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
 //************************************************************************//
 
-impl From<serde_json::Error> for FunctionCallError {
+impl From<reqwest::Error> for RequestError {
+    fn from(error: reqwest::Error) -> Self {
+        // The request could not be built, e.g. an invalid url or header value, and never will be
+        if error.is_builder() {
+            Self::InvalidRequest {
+                issue: error.to_string(),
+            }
+        } else if error.is_timeout() {
+            Self::Timeout(error)
+        } else if error.is_decode() {
+            Self::Parsing {
+                issue: error.to_string(),
+            }
+        } else {
+            Self::Transport(error)
+        }
+    }
+}
+
+impl From<serde_json::Error> for RequestError {
     fn from(error: serde_json::Error) -> Self {
         Self::Parsing {
             issue: error.to_string(),
@@ -48,11 +323,31 @@ impl From<serde_json::Error> for FunctionCallError {
     }
 }
 
+//************************************************************************//
+
+impl From<reqwest::Error> for CompletionError {
+    fn from(error: reqwest::Error) -> Self {
+        RequestError::from(error).into()
+    }
+}
+
+impl From<serde_json::Error> for CompletionError {
+    fn from(error: serde_json::Error) -> Self {
+        RequestError::from(error).into()
+    }
+}
+
+//************************************************************************//
+
 impl From<reqwest::Error> for FunctionCallError {
     fn from(error: reqwest::Error) -> Self {
-        Self::Api {
-            issue: error.to_string(),
-        }
+        RequestError::from(error).into()
+    }
+}
+
+impl From<serde_json::Error> for FunctionCallError {
+    fn from(error: serde_json::Error) -> Self {
+        RequestError::from(error).into()
     }
 }
 
@@ -66,3 +361,18 @@ impl From<llmtoolbox::FunctionCallError> for FunctionCallError {
         }
     }
 }
+
+//************************************************************************//
+
+impl From<reqwest_eventsource::Error> for CompletionStreamError {
+    fn from(error: reqwest_eventsource::Error) -> Self {
+        match error {
+            reqwest_eventsource::Error::Transport(error) => RequestError::from(error).into(),
+            // The body is only available asynchronously, so streams read it themselves
+            reqwest_eventsource::Error::InvalidStatusCode(status, _) => {
+                RequestError::from_status(status, "").into()
+            }
+            error => Self::SSE(Box::new(error)),
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::{LlamaLink, RequestError};
+
+/// The server status reported by `GET /health`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Health {
+    /// `ok` once the model is loaded.
+    pub status: String,
+    /// The number of idle slots. Only reported by older server versions.
+    #[serde(default)]
+    pub slots_idle: Option<usize>,
+    /// The number of busy slots. Only reported by older server versions.
+    #[serde(default)]
+    pub slots_processing: Option<usize>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl LlamaLink {
+    /// Checks `GET /health`. Fails while the server is still loading the model.
+    pub async fn health(&self) -> Result<Health, RequestError> {
+        self.get_json("health").await
+    }
+}
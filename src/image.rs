@@ -0,0 +1,184 @@
+use std::path::Path;
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{ImageError, LlamaLink, Message, RequestError, ResultStream};
+
+/// The marker the llama.cpp server replaces with the next image of `multimodal_data`. The
+/// formatters emit one for each image of a [`Message::UserWithImages`], see
+/// [`Message::text_with_media_markers`].
+pub const MEDIA_MARKER: &str = "<__media__>";
+
+/// An image attached to a [`Message::UserWithImages`], for models served with a multimodal
+/// projector.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    /// The MIME type, e.g. `image/png`.
+    mime: String,
+    /// The base64 encoded bytes.
+    data: String,
+}
+
+impl Image {
+    /// Detects the format from the leading bytes. Png, jpeg, gif, webp and bmp are recognized.
+    pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Result<Self, ImageError> {
+        let bytes = bytes.as_ref();
+        let mime = detect_mime(bytes).ok_or(ImageError::UnsupportedFormat)?;
+        Ok(Self {
+            mime: mime.to_owned(),
+            data: STANDARD.encode(bytes),
+        })
+    }
+
+    /// Reads the file at `path`, see [`Image::from_bytes`].
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, ImageError> {
+        Self::from_bytes(std::fs::read(path)?)
+    }
+
+    /// Parses a base64 data url, e.g. `data:image/png;base64,iVBORw0...`. The format is detected
+    /// from the bytes if the url has no MIME type.
+    pub fn from_data_url(url: &str) -> Result<Self, ImageError> {
+        let invalid = |issue: &str| ImageError::InvalidDataUrl {
+            issue: issue.to_owned(),
+        };
+        let url = url
+            .strip_prefix("data:")
+            .ok_or_else(|| invalid("The url must start with `data:`"))?;
+        let (header, data) = url
+            .split_once(',')
+            .ok_or_else(|| invalid("The url has no `,` before the data"))?;
+        let mime = header
+            .strip_suffix(";base64")
+            .ok_or_else(|| invalid("The data must be base64 encoded"))?;
+        let bytes = STANDARD
+            .decode(data.trim())
+            .map_err(|error| invalid(&error.to_string()))?;
+        if mime.is_empty() {
+            return Self::from_bytes(bytes);
+        }
+        if !mime.starts_with("image/") {
+            return Err(invalid(&format!("`{mime}` is not an image type")));
+        }
+        Ok(Self {
+            mime: mime.to_owned(),
+            data: STANDARD.encode(bytes),
+        })
+    }
+
+    pub fn mime(&self) -> &str {
+        &self.mime
+    }
+
+    /// The base64 encoded bytes, as sent in `multimodal_data`.
+    pub fn base64(&self) -> &str {
+        &self.data
+    }
+
+    /// The image as a data url, as sent in the `image_url` parts of chat messages.
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.mime, self.data)
+    }
+}
+
+fn detect_mime(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [b'B', b'M', ..] => Some("image/bmp"),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize)]
+struct Props {
+    modalities: Option<Modalities>,
+}
+
+#[derive(Deserialize)]
+struct Modalities {
+    #[serde(default)]
+    vision: bool,
+}
+
+/// The `prompt` of a completion request: the plain prompt, or with `multimodal_data` if the
+/// messages have images.
+pub(crate) fn multimodal_prompt(prompt: String, messages: &[Message]) -> Value {
+    let images: Vec<&str> = messages
+        .iter()
+        .flat_map(Message::images)
+        .map(Image::base64)
+        .collect();
+    if images.is_empty() {
+        return Value::String(prompt);
+    }
+    json!({ "prompt_string": prompt, "multimodal_data": images })
+}
+
+impl LlamaLink {
+    /// Whether the server accepts images, from `modalities` of `GET /props`. Requires the server
+    /// to be started with a multimodal projector, e.g. `--mmproj`. Fetched once per link and its
+    /// clones.
+    pub async fn supports_images(&self) -> Result<bool, RequestError> {
+        self.supports_images
+            .get_or_try_init(|| async {
+                let props: Props = self.get_json("props").await?;
+                Ok(props.modalities.is_some_and(|modalities| modalities.vision))
+            })
+            .await
+            .copied()
+    }
+
+    /// Fails with [`RequestError::Unsupported`] if `messages` have images the server cannot accept.
+    pub(crate) async fn check_images(&self, messages: &[Message]) -> Result<(), RequestError> {
+        if !has_images(messages) {
+            return Ok(());
+        }
+        self.require_images().await
+    }
+
+    async fn require_images(&self) -> Result<(), RequestError> {
+        if self.supports_images().await? {
+            return Ok(());
+        }
+        Err(RequestError::Unsupported {
+            operation: "Image input".to_owned(),
+            backend: "this llama.cpp server, which reports no vision support in `/props`"
+                .to_owned(),
+        })
+    }
+
+    /// Starts the stream once [`LlamaLink::check_images`] passed for `messages`.
+    pub(crate) fn stream_after_image_check<T, F>(
+        &self,
+        messages: &[Message],
+        start: F,
+    ) -> ResultStream<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&LlamaLink) -> ResultStream<T> + Send + 'static,
+    {
+        if !has_images(messages) {
+            return start(self);
+        }
+        let link = self.clone();
+        let mut start = Some(start);
+        let checked =
+            futures_util::stream::once(async move { link.require_images().await.map(|()| link) });
+        Box::pin(futures_util::StreamExt::flat_map(
+            checked,
+            move |link| match (link, start.take()) {
+                (Ok(link), Some(start)) => start(&link),
+                (Err(error), _) => Box::pin(tokio_stream::once(Err(error.into()))),
+                (Ok(_), None) => unreachable!("The check yields a single item"),
+            },
+        ))
+    }
+}
+
+pub(crate) fn has_images(messages: &[Message]) -> bool {
+    messages.iter().any(|message| !message.images().is_empty())
+}
@@ -1,40 +1,253 @@
+mod affinity;
+mod auth;
+mod backend;
+mod best_of;
+#[cfg(feature = "blocking")]
+mod blocking;
+mod cassette;
+mod chat;
+mod classify;
+mod context;
+mod conversation;
 mod errors;
+mod health;
+mod image;
+mod limiter;
+mod logit_bias;
+mod lora;
+mod metrics;
+#[cfg(feature = "mock")]
+mod mock;
+mod ollama;
+mod pool;
+mod probs;
+mod retry;
+mod slots;
+mod stream_timeout;
+mod template;
+mod tokenize;
+mod tree;
 
-use errors::CompletionStreamError;
-pub use errors::{CompletionError, FunctionCallError};
+pub use affinity::SlotAffinity;
+pub use auth::ApiKey;
+use backend::run_function_call;
+pub use backend::{CompletionBackend, Constraint, Engine};
+pub use best_of::{BestOf, Candidate, FunctionCallBestOf, Selection};
+#[cfg(feature = "blocking")]
+pub use blocking::{BlockingLlamaLink, BlockingStream};
+pub use cassette::{Cassette, Interaction, MatchRules, RecordedEvent, RecordedResponse};
+pub use chat::{
+    ChatCompletion, ChatCompletionChunk, FinishReason, ToolCall, ToolCallDelta, ToolCallFunction,
+    ToolCallFunctionDelta, Usage,
+};
+pub use classify::Classification;
+pub use context::{ContextManagement, TokenCounter, TruncationStrategy};
+pub use conversation::{Conversation, ConversationStream};
+pub use errors::{
+    BranchError, BuildError, CompletionError, CompletionStreamError, FunctionCallError, ImageError,
+    RequestError, ServerError,
+};
+pub use health::Health;
+use image::multimodal_prompt;
+pub use image::{Image, MEDIA_MARKER};
+pub use limiter::ConcurrencyLimit;
+use limiter::{Limiter, QueuedStream};
+pub use logit_bias::{Bias, BiasTarget, LogitBias};
+pub use lora::{LoraAdapter, LoraScale};
+pub use metrics::{MetricSample, ServerMetrics};
+#[cfg(feature = "mock")]
+pub use mock::{CapturedRequest, MockResponse, MockServer};
+pub use ollama::OllamaLink;
+pub use pool::{LlamaPool, Routing};
+use probs::RawTokenProbability;
+pub use probs::{log_likelihood, mean_logprob, TokenCandidate, TokenProbability};
+pub use retry::RetryPolicy;
+pub use slots::{Slot, SlotEraseResult, SlotNextToken, SlotRestoreResult, SlotSaveResult};
+use stream_timeout::{TimedOut, TimeoutEventSource};
+pub use template::TemplateDivergence;
+pub use tree::{ConversationTree, Node, NodeId};
+
+use std::{sync::Arc, time::Duration};
 
 use llmtoolbox::ToolBox;
-use reqwest::Client;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, USER_AGENT},
+    Client, Method, RequestBuilder, Url,
+};
 use reqwest_eventsource::{Event, EventSource};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{Map, Value};
 use tokio_stream::StreamExt;
 
-#[derive(Serialize, bon::Builder)]
+#[derive(Serialize, Clone, bon::Builder)]
 pub struct Config {
     n_predict: Option<usize>,
     temperature: Option<f32>,
     top_k: Option<usize>,
     top_p: Option<f32>,
+    /// The RNG seed used for sampling.
+    seed: Option<u32>,
     stop: Option<Vec<String>>,
+    /// Pins the request to a specific server slot, so its KV cache can be reused or persisted.
+    id_slot: Option<usize>,
+    /// Reuses the KV cache of the slot for the prefix the prompt shares with the previous request,
+    /// so only the rest is evaluated. Chat requests and conversations enable it unless set.
+    cache_prompt: Option<bool>,
+    /// Per-request LoRA adapter scales, overriding the server's global scales.
+    lora: Option<Vec<LoraScale>>,
+    /// The number of top candidates to report for each generated token. See [`LlamaLink::create_completion_full`].
+    n_probs: Option<usize>,
+    /// Report the probabilities after sampling (e.g. after `top_k`/`top_p` filtering) instead of the raw model probabilities.
+    post_sampling_probs: Option<bool>,
+    /// Adjusts the likelihood of specific tokens or strings appearing in the completion.
+    logit_bias: Option<LogitBias>,
     // json_schema: Value,
 }
 
+impl Config {
+    fn into_map(self) -> Map<String, Value> {
+        match serde_json::to_value(self).unwrap() {
+            Value::Object(map) => map,
+            _ => unreachable!("RequestConfig should always be created as an object"),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct CompletionResponse {
     content: Option<String>,
     stop: Option<bool>,
+    #[serde(default)]
+    completion_probabilities: Vec<RawTokenProbability>,
+    timings: Option<Timings>,
+}
+
+/// The `timings` of a llama.cpp server response.
+#[derive(Deserialize, Debug)]
+struct Timings {
+    /// The prompt tokens reused from the slot's KV cache.
+    cache_n: Option<usize>,
+}
+
+/// A completion along with the token probabilities reported by the server.
+#[derive(Debug, Clone, Default)]
+pub struct Completion {
+    pub content: String,
+    /// Empty unless [`Config`] sets `n_probs`.
+    pub probabilities: Vec<TokenProbability>,
+    /// The prompt tokens reused from the slot's KV cache instead of being evaluated again, see
+    /// `cache_prompt` of [`Config`]. `None` if the server does not report it.
+    pub tokens_cached: Option<usize>,
 }
 
+impl Completion {
+    /// The log likelihood of the generated tokens. See [`log_likelihood`].
+    pub fn log_likelihood(&self) -> f64 {
+        log_likelihood(&self.probabilities)
+    }
+
+    /// The mean log probability per generated token. See [`mean_logprob`].
+    pub fn mean_logprob(&self) -> Option<f64> {
+        mean_logprob(&self.probabilities)
+    }
+
+    /// The entropy of each generated token. See [`TokenProbability::entropy`].
+    pub fn entropies(&self) -> Vec<f64> {
+        self.probabilities
+            .iter()
+            .map(TokenProbability::entropy)
+            .collect()
+    }
+}
+
+/// A piece of a streamed completion.
+#[derive(Debug, Clone, Default)]
+pub struct CompletionChunk {
+    pub content: String,
+    /// Empty unless [`Config`] sets `n_probs`.
+    pub probabilities: Vec<TokenProbability>,
+    /// Only set on the last chunk. See [`Completion::tokens_cached`].
+    pub tokens_cached: Option<usize>,
+}
+
+#[derive(Clone)]
 pub struct LlamaLink {
     client: Client,
-    completion_url: String,
+    /// The normalized base url, always ending in `/`. The url as given if [`LlamaLink::new`] could
+    /// not parse it, so every request fails when sent.
+    url: Result<Url, String>,
+    headers: HeaderMap,
+    api_key: Option<ApiKey>,
+    retry_policy: RetryPolicy,
+    request_timeout: Option<Duration>,
+    first_token_timeout: Option<Duration>,
+    stream_idle_timeout: Option<Duration>,
+    limiter: Option<Arc<Limiter>>,
+    priority: i32,
+    cassette: Option<Cassette>,
+    /// Whether the server accepts images, once fetched. Shared by clones.
+    supports_images: Arc<tokio::sync::OnceCell<bool>>,
+    /// The `total_slots` of the server, once fetched. Shared by clones.
+    total_slots: Arc<tokio::sync::OnceCell<Option<usize>>>,
     request_config: Map<String, Value>,
 }
 
+/// A chat message. Serialized as `{"role": "user", "content": "..."}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "role", content = "content", rename_all = "lowercase")]
 pub enum Message {
     User(String),
     Assistant(String),
+    /// A user message with images, for vision models. [`LlamaLink`] and [`LlamaPool`] send them with
+    /// every method taking messages, including [`Conversation`], once `GET /props` reports that the
+    /// server supports them. [`OllamaLink`] only sends them with its chat methods; its formatted
+    /// [`CompletionBackend`] methods fail with [`RequestError::Unsupported`].
+    #[serde(rename = "user_with_images")]
+    UserWithImages {
+        text: String,
+        images: Vec<Image>,
+    },
+}
+
+impl Message {
+    pub fn text(&self) -> &str {
+        match self {
+            Message::User(text)
+            | Message::Assistant(text)
+            | Message::UserWithImages { text, .. } => text,
+        }
+    }
+
+    pub fn images(&self) -> &[Image] {
+        match self {
+            Message::UserWithImages { images, .. } => images,
+            Message::User(_) | Message::Assistant(_) => &[],
+        }
+    }
+
+    pub fn is_user(&self) -> bool {
+        !matches!(self, Message::Assistant(_))
+    }
+
+    /// The text preceded by a [`MEDIA_MARKER`] line for each image, for formatting a prompt of
+    /// the completion endpoint.
+    pub fn text_with_media_markers(&self) -> String {
+        let mut text = format!("{MEDIA_MARKER}\n").repeat(self.images().len());
+        text.push_str(self.text());
+        text
+    }
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Self {
+        Message::User(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Self {
+        Message::User(text.to_owned())
+    }
 }
 
 /// The result from calling the function and the raw input used for the function call.
@@ -47,50 +260,272 @@ pub type CompletionStream = std::pin::Pin<
     Box<dyn tokio_stream::Stream<Item = Result<String, CompletionStreamError>> + Send>,
 >;
 
+type ResultStream<T> =
+    std::pin::Pin<Box<dyn tokio_stream::Stream<Item = Result<T, CompletionStreamError>> + Send>>;
+
+pub type CompletionChunkStream = std::pin::Pin<
+    Box<dyn tokio_stream::Stream<Item = Result<CompletionChunk, CompletionStreamError>> + Send>,
+>;
+
+pub type ChatChunkStream = std::pin::Pin<
+    Box<dyn tokio_stream::Stream<Item = Result<ChatCompletionChunk, CompletionStreamError>> + Send>,
+>;
+
+#[bon::bon]
 impl LlamaLink {
+    /// `http://` is assumed if `url` has no scheme. An invalid url does not fail here, but every
+    /// request does. Use [`LlamaLink::builder`] to validate the url up front.
     pub fn new(url: &str, request_config: Config) -> Self {
-        let request_config = match serde_json::to_value(request_config).unwrap() {
-            Value::Object(map) => map,
-            _ => unreachable!("RequestConfig should always be created as an object"),
+        let url = lenient_url(url);
+        let mut link = Self::builder()
+            .url(url.as_ref().map_or(PLACEHOLDER_URL, Url::as_str))
+            .config(request_config)
+            .build()
+            .expect("A valid url with the default client always builds");
+        link.url = url;
+        link
+    }
+
+    /// Builds a link with a custom client, timeouts and headers.
+    ///
+    /// - `url`: The base url of the server. A path prefix, e.g. for a server behind a reverse
+    ///   proxy, is kept and trailing slashes are ignored.
+    /// - `client`: A preconfigured client, e.g. with proxy settings. Defaults to [`Client::new`].
+    /// - `connect_timeout`: Only applies to the default client.
+    /// - `request_timeout`: The timeout of each non streaming request.
+    /// - `first_token_timeout`: How long a stream may wait for its first token.
+    /// - `stream_idle_timeout`: How long a stream may wait between tokens.
+    /// - `default_headers`: Sent with every request.
+    /// - `api_key`: For servers started with `--api-key`. Either a static key or an [`ApiKey::provider`].
+    /// - `retry_policy`: How non streaming requests are retried. Defaults to [`RetryPolicy::none`].
+    /// - `concurrency_limit`: Queues completions beyond the limit on the client, instead of on the server.
+    /// - `queue_timeout`: How long a completion may wait in the queue of the [`ConcurrencyLimit`].
+    /// - `cassette`: Records every request and response, or replays them without a server.
+    #[builder(
+        start_fn(name = builder, vis = "pub"),
+        finish_fn(name = build, vis = "pub"),
+        builder_type(name = LlamaLinkBuilder, vis = "pub")
+    )]
+    fn from_builder(
+        #[builder(into)] url: String,
+        config: Option<Config>,
+        client: Option<Client>,
+        connect_timeout: Option<Duration>,
+        request_timeout: Option<Duration>,
+        first_token_timeout: Option<Duration>,
+        stream_idle_timeout: Option<Duration>,
+        default_headers: Option<HeaderMap>,
+        #[builder(into)] user_agent: Option<String>,
+        #[builder(into)] api_key: Option<ApiKey>,
+        retry_policy: Option<RetryPolicy>,
+        concurrency_limit: Option<ConcurrencyLimit>,
+        queue_timeout: Option<Duration>,
+        cassette: Option<Cassette>,
+    ) -> Result<Self, BuildError> {
+        let url = Ok(normalize_url(&url)?);
+        let client = match (client, connect_timeout) {
+            (Some(_), Some(_)) => return Err(BuildError::ConnectTimeoutWithClient),
+            (Some(client), None) => client,
+            (None, connect_timeout) => {
+                let mut builder = Client::builder();
+                if let Some(connect_timeout) = connect_timeout {
+                    builder = builder.connect_timeout(connect_timeout);
+                }
+                builder.build()?
+            }
         };
-        Self {
-            client: Client::new(),
-            completion_url: format!("{url}/completion"),
-            request_config,
+        let mut headers = default_headers.unwrap_or_default();
+        if let Some(user_agent) = user_agent {
+            let value = HeaderValue::from_str(&user_agent)
+                .map_err(|_| BuildError::InvalidUserAgent { user_agent })?;
+            headers.insert(USER_AGENT, value);
+        }
+        Ok(Self {
+            client,
+            url,
+            headers,
+            api_key,
+            retry_policy: retry_policy.unwrap_or_else(RetryPolicy::none),
+            request_timeout,
+            first_token_timeout,
+            stream_idle_timeout,
+            limiter: concurrency_limit.map(|limit| Arc::new(Limiter::new(limit, queue_timeout))),
+            priority: 0,
+            cassette,
+            supports_images: Arc::default(),
+            total_slots: Arc::default(),
+            request_config: config.map(Config::into_map).unwrap_or_default(),
+        })
+    }
+
+    /// The normalized base url of the server. `None` if the url given to [`LlamaLink::new`] is
+    /// invalid.
+    pub fn url(&self) -> Option<&Url> {
+        self.url.as_ref().ok()
+    }
+
+    /// The base url, as given if it is invalid.
+    pub(crate) fn base_url(&self) -> &str {
+        match &self.url {
+            Ok(url) => url.as_str(),
+            Err(url) => url,
+        }
+    }
+
+    /// Creates a link sharing this link's client, where every field set in `overrides` replaces
+    /// the corresponding field of this link's config. Useful for per-request settings, e.g.
+    /// pinning a request to a slot with `id_slot` of [`Config`].
+    pub fn with_overrides(&self, overrides: Config) -> Self {
+        self.with_override_map(overrides.into_map())
+    }
+
+    pub(crate) fn with_override_map(&self, overrides: Map<String, Value>) -> Self {
+        let mut link = self.clone();
+        for (key, value) in overrides {
+            if !value.is_null() {
+                link.request_config.insert(key, value);
+            }
+        }
+        link
+    }
+
+    /// The url of `path`. For an invalid base url the request fails when sent.
+    fn endpoint(&self, path: &str) -> String {
+        endpoint(&self.url, path)
+    }
+
+    /// Starts a request to `path` with the default headers and request timeout.
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self.stream_request(method, path);
+        match self.request_timeout {
+            Some(timeout) => request.timeout(timeout),
+            None => request,
         }
     }
 
+    /// Starts a request to `path` with the default headers. Streams are bounded by the first
+    /// token and idle timeouts instead of the request timeout.
+    fn stream_request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self
+            .client
+            .request(method, self.endpoint(path))
+            .headers(self.headers.clone());
+        match &self.api_key {
+            Some(api_key) => request.bearer_auth(api_key.key()),
+            None => request,
+        }
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, RequestError> {
+        let response = self.send(|| self.request(Method::GET, path)).await?;
+        Self::parse_json_response(response).await
+    }
+
+    async fn post_json<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &Value,
+    ) -> Result<T, RequestError> {
+        let response = self
+            .send(|| self.request(Method::POST, path).json(body))
+            .await?;
+        Self::parse_json_response(response).await
+    }
+
+    async fn get_text(&self, path: &str) -> Result<String, RequestError> {
+        let response = self.send(|| self.request(Method::GET, path)).await?;
+        Ok(response.text().await?)
+    }
+
+    async fn parse_json_response<T: DeserializeOwned>(
+        response: reqwest::Response,
+    ) -> Result<T, RequestError> {
+        let body = response.bytes().await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Turns an error status into the matching [`RequestError`], reading the server's error body.
+    async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, RequestError> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.text().await.unwrap_or_default();
+        Err(RequestError::from_status(status, &body))
+    }
+
     pub async fn create_completion_with_format(
         &self,
         system: &str,
         messages: &[Message],
         formatter: &PromptFormatter,
     ) -> Result<String, CompletionError> {
-        let prompt = (formatter.0)(system, messages);
-        self.create_completion(prompt).await
+        self.create_completion_with_format_full(system, messages, formatter)
+            .await
+            .map(|completion| completion.content)
     }
 
     pub async fn create_completion(&self, prompt: String) -> Result<String, CompletionError> {
+        self.create_completion_full(prompt)
+            .await
+            .map(|completion| completion.content)
+    }
+
+    pub async fn create_completion_with_format_full(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> Result<Completion, CompletionError> {
+        self.check_images(messages).await?;
+        let prompt = multimodal_prompt((formatter.0)(system, messages), messages);
+        self.send_completion_request(self.completion_request(prompt))
+            .await
+    }
+
+    /// Like [`LlamaLink::create_completion`], but also returns the token probabilities when
+    /// [`Config`] sets `n_probs`.
+    pub async fn create_completion_full(
+        &self,
+        prompt: String,
+    ) -> Result<Completion, CompletionError> {
+        self.send_completion_request(self.completion_request(Value::String(prompt)))
+            .await
+    }
+
+    /// The config with `prompt`, a string or a multimodal prompt.
+    fn completion_request(&self, prompt: Value) -> Map<String, Value> {
         let mut json = self.request_config.clone();
-        json.insert("prompt".to_owned(), Value::String(prompt));
+        json.insert("prompt".to_owned(), prompt);
+        json
+    }
+
+    /// Sends `json` to the completion endpoint. `json` must contain the `prompt`.
+    async fn send_completion_request(
+        &self,
+        json: Map<String, Value>,
+    ) -> Result<Completion, CompletionError> {
         let json = Value::Object(json);
 
+        let _permit = self.acquire_permit().await?;
         let response = self
-            .client
-            .post(&self.completion_url)
-            .json(&json)
-            .send()
+            .send(|| self.request(Method::POST, "completion").json(&json))
             .await?;
 
-        if !response.status().is_success() {
-            return Err(CompletionError::Api {
-                issue: format!("HTTP Error: {}", response.status()),
-            });
-        }
-
         let response_body: CompletionResponse = response.json().await?;
-        response_body.content.ok_or_else(|| CompletionError::Api {
-            issue: "No `content` field in response body".to_owned(),
+        let content = response_body
+            .content
+            .ok_or_else(|| CompletionError::Parsing {
+                issue: "No `content` field in response body".to_owned(),
+            })?;
+        Ok(Completion {
+            content,
+            probabilities: response_body
+                .completion_probabilities
+                .into_iter()
+                .map(TokenProbability::from)
+                .collect(),
+            tokens_cached: response_body.timings.and_then(|timings| timings.cache_n),
         })
     }
 
@@ -111,8 +546,9 @@ impl LlamaLink {
         formatter: &PromptFormatter,
         toolbox: &ToolBox<O, E>,
     ) -> Result<Result<O, E>, FunctionCallError> {
-        let prompt = (formatter.0)(system, messages);
-        self.call_function(prompt, toolbox).await
+        self.call_function_with_format_full(system, messages, formatter, toolbox)
+            .await
+            .map(|e| e.output_result)
     }
 
     pub async fn call_function_with_format_full<O, E>(
@@ -122,8 +558,13 @@ impl LlamaLink {
         formatter: &PromptFormatter,
         toolbox: &ToolBox<O, E>,
     ) -> Result<FunctionCallContext<O, E>, FunctionCallError> {
-        let prompt = (formatter.0)(system, messages);
-        self.call_function_full(prompt, toolbox).await
+        self.check_images(messages).await?;
+        let prompt = multimodal_prompt((formatter.0)(system, messages), messages);
+        let content = self
+            .constrained_completion(prompt, Constraint::JsonSchema(toolbox.schema()))
+            .await?
+            .content;
+        run_function_call(toolbox, content).await
     }
 
     pub async fn call_function_full<O, E>(
@@ -131,42 +572,7 @@ impl LlamaLink {
         prompt: String,
         toolbox: &ToolBox<O, E>,
     ) -> Result<FunctionCallContext<O, E>, FunctionCallError> {
-        let mut json = self.request_config.clone();
-        json.insert("prompt".to_owned(), Value::String(prompt));
-        json.insert(
-            "json_schema".to_owned(),
-            Value::Object(toolbox.schema().clone()),
-        );
-        let json = Value::Object(json);
-
-        let response = self
-            .client
-            .post(&self.completion_url)
-            .json(&json)
-            .send()
-            .await?;
-
-        if !response.status().is_success() {
-            return Err(FunctionCallError::Api {
-                issue: format!("HTTP Error Calling: {}", response.status()),
-            });
-        }
-
-        let response_body: CompletionResponse = response.json().await?;
-        let content = response_body.content.ok_or_else(|| FunctionCallError::Api {
-            issue: "No `content` field in response body".to_owned(),
-        })?;
-        #[cfg(feature = "tracing")]
-        tracing::debug!("Raw tool_call response:\n`{}`", &content);
-        let tool_call = serde_json::from_str(&content).map_err(|_| FunctionCallError::Parsing {
-            issue: "Could not parse tool call response into valid json".to_owned(),
-        })?;
-        let tool_call_result: Result<Result<O, E>, FunctionCallError> = 
-            toolbox.call_from_value(tool_call).await.map_err(|error| error.into());
-        tool_call_result.map(|e| FunctionCallContext {
-            output_result: e,
-            raw_input: content,
-        })
+        CompletionBackend::call_function_full(self, prompt, toolbox).await
     }
 
     pub fn create_formatted_completion_stream(
@@ -175,117 +581,291 @@ impl LlamaLink {
         messages: &[Message],
         formatter: &PromptFormatter,
     ) -> CompletionStream {
-        let prompt = (formatter.0)(system, messages);
-        self.create_completion_stream(prompt)
+        Box::pin(
+            self.create_formatted_completion_stream_full(system, messages, formatter)
+                .map(|chunk| chunk.map(|chunk| chunk.content)),
+        )
     }
 
     pub fn create_completion_stream(&self, prompt: String) -> CompletionStream {
-        let mut json = self.request_config.clone();
-        json.insert("prompt".to_owned(), Value::String(prompt));
+        Box::pin(
+            self.create_completion_stream_full(prompt)
+                .map(|chunk| chunk.map(|chunk| chunk.content)),
+        )
+    }
+
+    pub fn create_formatted_completion_stream_full(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> CompletionChunkStream {
+        let prompt = multimodal_prompt((formatter.0)(system, messages), messages);
+        self.stream_after_image_check(messages, move |link| link.completion_stream(prompt))
+    }
+
+    /// Like [`LlamaLink::create_completion_stream`], but each chunk also contains the token
+    /// probabilities when [`Config`] sets `n_probs`.
+    pub fn create_completion_stream_full(&self, prompt: String) -> CompletionChunkStream {
+        self.completion_stream(Value::String(prompt))
+    }
+
+    fn completion_stream(&self, prompt: Value) -> CompletionChunkStream {
+        let mut json = self.completion_request(prompt);
         json.insert("stream".to_owned(), Value::Bool(true));
         let json = Value::Object(json);
 
-        let request = self.client.post(&self.completion_url).json(&json);
-
-        // Why SSE: https://github.com/ggerganov/llama.cpp/blob/89d604f2c87af9db657d8a27a1528bc4b7579c29/examples/server/README.md?plain=1#L450
-        let es: Result<EventSource, reqwest_eventsource::CannotCloneRequestError> =
-            EventSource::new(request);
-        let es = match es {
-            Ok(value) => value,
-            Err(_) => {
-                // Dev Note: We do not expose this since this should never be possible, but would rather log and be safe, then unwrap and panic, or make the caller handle
-                #[cfg(feature = "tracing")]
-                tracing::error!("Could not create event source for SSE in completion stream");
-                return Box::pin(tokio_stream::empty());
+        let request = self.stream_request(Method::POST, "completion").json(&json);
+
+        self.sse_stream(request, |data| {
+            let response = serde_json::from_str::<CompletionResponse>(data);
+            match response {
+                Ok(response) => {
+                    let tokens_cached = response.timings.and_then(|timings| timings.cache_n);
+                    let content = response.content.unwrap_or_default();
+                    if response.stop.unwrap_or(false) {
+                        #[cfg(feature = "tracing")]
+                        tracing::trace!("Completion stream received stop");
+                        // The server closes the stream after the stop event, so anything it reports is passed on as a last chunk
+                        if content.is_empty() && tokens_cached.is_none() {
+                            return None;
+                        }
+                    }
+                    Some(Ok(CompletionChunk {
+                        content,
+                        probabilities: response
+                            .completion_probabilities
+                            .into_iter()
+                            .map(TokenProbability::from)
+                            .collect(),
+                        tokens_cached,
+                    }))
+                }
+                Err(err) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::error!("Error in completion stream: {:?}", err);
+                    Some(Err(CompletionStreamError::from(err)))
+                }
             }
-        };
-        let stream = es
-            .map(|event| match event {
-                Ok(Event::Open) => {
+        })
+    }
+
+    /// Streams the server sent events of `request`, mapping each message's data with `on_message`.
+    /// The stream ends when `on_message` returns `None`.
+    fn sse_stream<T, F>(&self, request: RequestBuilder, mut on_message: F) -> ResultStream<T>
+    where
+        T: Default + Send + 'static,
+        F: FnMut(&str) -> Option<Result<T, CompletionStreamError>> + Send + 'static,
+    {
+        if let Some(cassette) = self
+            .cassette
+            .as_ref()
+            .filter(|cassette| cassette.is_replay())
+        {
+            return self.replay_stream(cassette, request, on_message);
+        }
+        let recorder = self.stream_recorder(&request);
+        let first_token_timeout = self.first_token_timeout;
+        let stream_idle_timeout = self.stream_idle_timeout;
+        // Built only once the stream has a place in the queue, so the wait does not count towards the timeouts
+        let start = move || -> ResultStream<T> {
+            // Why SSE: https://github.com/ggerganov/llama.cpp/blob/89d604f2c87af9db657d8a27a1528bc4b7579c29/examples/server/README.md?plain=1#L450
+            let es: Result<EventSource, reqwest_eventsource::CannotCloneRequestError> =
+                EventSource::new(request);
+            let es = match es {
+                Ok(value) => value,
+                Err(_) => {
+                    // Dev Note: We do not expose this since this should never be possible, but would rather log and be safe, then unwrap and panic, or make the caller handle
+                    #[cfg(feature = "tracing")]
+                    tracing::error!("Could not create event source for SSE in completion stream");
+                    return Box::pin(tokio_stream::empty());
+                }
+            };
+            let es = TimeoutEventSource::new(es, first_token_timeout, stream_idle_timeout);
+            let message_recorder = recorder.clone();
+            let stream = es.map(move |event| match event {
+                Err(TimedOut) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::error!("Completion stream timed out.");
+                    StreamStep::Item(Err(CompletionStreamError::StreamTimeout))
+                }
+                Ok(Ok(Event::Open)) => {
                     #[cfg(feature = "tracing")]
                     tracing::trace!("Completion stream SSE connection open.");
-                    Some(Ok(String::new()))
+                    StreamStep::Item(Ok(T::default()))
                 }
-                Ok(Event::Message(message)) => {
-                    let response = serde_json::from_str::<CompletionResponse>(&message.data);
-                    match response {
-                        Ok(response) => {
-                            if response.stop.unwrap_or(false) {
-                                #[cfg(feature = "tracing")]
-                                tracing::trace!("Completion stream received stop");
-                                return None;
-                            }
-                            Some(Ok(response.content.unwrap_or_else(|| String::new())))
-                        }
-                        #[allow(unused_variables)]
-                        Err(err) => {
-                            #[cfg(feature = "tracing")]
-                            tracing::error!("Error in completion stream: {:?}", e);
-                            Some(Err(CompletionStreamError::from(err)))
-                        }
+                Ok(Ok(Event::Message(message))) => {
+                    if let Some(recorder) = &message_recorder {
+                        recorder.event(&message.data);
                     }
-                }
-                Err(err) => {
-                    if matches!(err, reqwest_eventsource::Error::StreamEnded) {
-                        #[cfg(feature = "tracing")]
-                        tracing::trace!("Completion stream ended.");
-                        return None;
+                    match on_message(&message.data) {
+                        Some(item) => StreamStep::Item(item),
+                        None => StreamStep::End,
                     }
+                }
+                Ok(Err(reqwest_eventsource::Error::StreamEnded)) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::trace!("Completion stream ended.");
+                    StreamStep::End
+                }
+                Ok(Err(err)) => {
                     #[cfg(feature = "tracing")]
                     tracing::error!("Error in completion stream: {}", err);
-                    Some(Err(CompletionStreamError::from(err)))
+                    StreamStep::Error(err)
+                }
+            });
+            // Reading the error body of an invalid status is async, so it cannot happen in `map`
+            let stream = futures_util::StreamExt::then(stream, move |step| {
+                let recorder = recorder.clone();
+                async move {
+                    match step {
+                        StreamStep::Item(item) => Some(item),
+                        StreamStep::End => {
+                            if let Some(recorder) = recorder {
+                                recorder.finish(reqwest::StatusCode::OK, None);
+                            }
+                            None
+                        }
+                        StreamStep::Error(reqwest_eventsource::Error::InvalidStatusCode(
+                            status,
+                            response,
+                        )) => {
+                            let body = response.text().await.unwrap_or_default();
+                            if let Some(recorder) = recorder {
+                                recorder.finish(status, Some(body.clone()));
+                            }
+                            Some(Err(RequestError::from_status(status, &body).into()))
+                        }
+                        StreamStep::Error(err) => Some(Err(err.into())),
+                    }
                 }
             })
-            .take_while(|e: &Option<Result<String, CompletionStreamError>>| e.is_some())
+            .take_while(|e: &Option<Result<T, CompletionStreamError>>| e.is_some())
             .filter_map(|e| e);
-        Box::pin(stream)
+            Box::pin(stream)
+        };
+        match &self.limiter {
+            Some(_) => Box::pin(QueuedStream::new(self, start)),
+            None => start(),
+        }
+    }
+}
+
+/// What an event of the SSE stream turns into.
+enum StreamStep<T> {
+    Item(Result<T, CompletionStreamError>),
+    End,
+    Error(reqwest_eventsource::Error),
+}
+
+/// Stands in for an invalid url passed to a `new` constructor until the link is built.
+const PLACEHOLDER_URL: &str = "http://localhost/";
+
+/// Parses `url` for the `new` constructors, which do not fail: `http://` is assumed without a
+/// scheme, and an invalid url is kept as given.
+fn lenient_url(url: &str) -> Result<Url, String> {
+    let with_scheme = if url.contains("://") {
+        url.to_owned()
+    } else {
+        format!("http://{url}")
+    };
+    normalize_url(&with_scheme).map_err(|_| url.trim_end_matches('/').to_owned())
+}
+
+fn endpoint(url: &Result<Url, String>, path: &str) -> String {
+    match url {
+        Ok(url) => url
+            .join(path)
+            .expect("Endpoint paths are always valid relative urls")
+            .into(),
+        Err(url) => format!("{url}/{path}"),
     }
 }
 
+/// Validates `url` as an http(s) base url and ensures it ends in `/`, so endpoints are joined
+/// onto any path prefix.
+fn normalize_url(url: &str) -> Result<Url, BuildError> {
+    let invalid = |issue: &str| BuildError::InvalidUrl {
+        url: url.to_owned(),
+        issue: issue.to_owned(),
+    };
+    let mut parsed = Url::parse(url).map_err(|error| invalid(&error.to_string()))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(invalid("The scheme must be `http` or `https`"));
+    }
+    if parsed.query().is_some() || parsed.fragment().is_some() {
+        return Err(invalid("The url must not have a query or fragment"));
+    }
+    let path = format!("{}/", parsed.path().trim_end_matches('/'));
+    parsed.set_path(&path);
+    Ok(parsed)
+}
+
 /// The formatter used to create the prompt for the llm
-pub struct PromptFormatter(fn(&str, &[Message]) -> String);
+#[derive(Clone, Copy)]
+pub struct PromptFormatter(fn(&str, &[Message]) -> String, &'static [&'static str]);
 
 impl PromptFormatter {
     pub fn new(formatter: fn(&str, &[Message]) -> String) -> Self {
-        Self(formatter)
+        Self(formatter, &[])
+    }
+
+    /// Sets the special tokens of the template that should never appear in a reply, e.g. role
+    /// headers. End of turn tokens should not be included, since the model needs them to stop.
+    pub const fn with_special_tokens(self, special_tokens: &'static [&'static str]) -> Self {
+        Self(self.0, special_tokens)
+    }
+
+    /// The special tokens of the template that should never appear in a reply.
+    /// See [`LlamaLink::special_tokens_logit_bias`].
+    pub fn special_tokens(&self) -> &'static [&'static str] {
+        self.1
     }
 
     // https://www.llama.com/docs/model-cards-and-prompt-formats/meta-llama-3/
+    #[allow(clippy::len_zero)]
     pub const fn default_const() -> Self {
-        Self(|system, messages| {
-            debug_assert!(messages.len() > 0, "Messages must not be empty");
-            debug_assert!(
-                matches!(messages.first().unwrap(), Message::User(_)),
-                "First message must be a user message"
-            );
-            debug_assert!(
-                matches!(messages.last().unwrap(), Message::User(_)),
-                "Last message must be a user message"
-            );
-            let mut formatted = String::new();
-            formatted.push_str(&format!(
-                "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\n{}<|eot_id|>",
-                system
-            ));
-            for message in messages {
-                match message {
-                    Message::User(text) => {
-                        formatted.push_str(&format!(
-                            "<|start_header_id|>user<|end_header_id|>\n\n{}<|eot_id|>",
-                            text
-                        ));
-                    }
-                    Message::Assistant(text) => {
-                        formatted.push_str(&format!(
-                            "<|start_header_id|>assistant<|end_header_id|>\n\n{}<|eot_id|>",
-                            text
-                        ));
+        Self(
+            |system, messages| {
+                debug_assert!(messages.len() > 0, "Messages must not be empty");
+                debug_assert!(
+                    messages.first().unwrap().is_user(),
+                    "First message must be a user message"
+                );
+                debug_assert!(
+                    messages.last().unwrap().is_user(),
+                    "Last message must be a user message"
+                );
+                let mut formatted = String::new();
+                formatted.push_str(&format!(
+                    "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\n{}<|eot_id|>",
+                    system
+                ));
+                for message in messages {
+                    match message {
+                        Message::User(_) | Message::UserWithImages { .. } => {
+                            formatted.push_str(&format!(
+                                "<|start_header_id|>user<|end_header_id|>\n\n{}<|eot_id|>",
+                                message.text_with_media_markers()
+                            ));
+                        }
+                        Message::Assistant(text) => {
+                            formatted.push_str(&format!(
+                                "<|start_header_id|>assistant<|end_header_id|>\n\n{}<|eot_id|>",
+                                text
+                            ));
+                        }
                     }
                 }
-            }
-            formatted.push_str("<|start_header_id|>assistant<|end_header_id|>\n\n");
-            formatted
-        })
+                formatted.push_str("<|start_header_id|>assistant<|end_header_id|>\n\n");
+                formatted
+            },
+            &[
+                "<|begin_of_text|>",
+                "<|start_header_id|>",
+                "<|end_header_id|>",
+            ],
+        )
     }
 }
 
@@ -0,0 +1,281 @@
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::sync::oneshot;
+use tokio_stream::Stream;
+
+use crate::{CompletionStreamError, LlamaLink, RequestError, ResultStream};
+
+/// How many completions a [`LlamaLink`] sends to the server at once. Further completions wait in a
+/// queue, in order of priority and then arrival. See [`LlamaLink::with_priority`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConcurrencyLimit {
+    Fixed(usize),
+    /// The number of server slots, from `total_slots` of `GET /props`. Fetched before the first
+    /// completion. Completions are not limited if the server does not report it, e.g. behind a
+    /// proxy without `/props`, or while it cannot be fetched.
+    ServerSlots,
+}
+
+/// Shared by every clone of a [`LlamaLink`], so all of them draw from the same queue.
+pub(crate) struct Limiter {
+    limit: ConcurrencyLimit,
+    queue_timeout: Option<Duration>,
+    state: Mutex<QueueState>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    active: usize,
+    next_ticket: u64,
+    /// Ordered by highest priority first, then earliest arrival.
+    waiters: BTreeMap<(Reverse<i32>, u64), oneshot::Sender<()>>,
+}
+
+/// Holds one of the limited places until dropped.
+pub(crate) struct Permit(Arc<Limiter>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// Removes an abandoned waiter from the queue, or passes on the place it was handed.
+struct Waiting<'a> {
+    limiter: &'a Arc<Limiter>,
+    key: (Reverse<i32>, u64),
+    granted: bool,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if self.granted {
+            return;
+        }
+        let handed_over = self
+            .limiter
+            .state
+            .lock()
+            .unwrap()
+            .waiters
+            .remove(&self.key)
+            .is_none();
+        if handed_over {
+            self.limiter.release();
+        }
+    }
+}
+
+impl Limiter {
+    pub(crate) fn new(limit: ConcurrencyLimit, queue_timeout: Option<Duration>) -> Self {
+        Self {
+            limit,
+            queue_timeout,
+            state: Mutex::new(QueueState::default()),
+        }
+    }
+
+    /// Waits for a place. Places are handed directly to the next waiter when released, so a new
+    /// request never overtakes a queued one of the same priority.
+    async fn acquire(
+        self: &Arc<Self>,
+        limit: usize,
+        priority: i32,
+    ) -> Result<Permit, RequestError> {
+        let (key, receiver) = {
+            let mut state = self.state.lock().unwrap();
+            if state.active < limit && state.waiters.is_empty() {
+                state.active += 1;
+                return Ok(Permit(self.clone()));
+            }
+            let key = (Reverse(priority), state.next_ticket);
+            state.next_ticket += 1;
+            let (sender, receiver) = oneshot::channel();
+            state.waiters.insert(key, sender);
+            (key, receiver)
+        };
+        let mut waiting = Waiting {
+            limiter: self,
+            key,
+            granted: false,
+        };
+        let granted = match self.queue_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, receiver).await.ok(),
+            None => Some(receiver.await),
+        };
+        match granted {
+            Some(Ok(())) => {
+                waiting.granted = true;
+                Ok(Permit(self.clone()))
+            }
+            _ => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!("Timed out waiting in the request queue");
+                Err(RequestError::QueueTimeout)
+            }
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        match state.waiters.pop_first() {
+            // If the waiter gave up in the meantime, it passes the place on itself. See `Waiting`
+            Some((_, sender)) => {
+                let _ = sender.send(());
+            }
+            None => state.active -= 1,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TotalSlots {
+    total_slots: Option<usize>,
+}
+
+impl LlamaLink {
+    /// Waits for a place in the queue if a [`ConcurrencyLimit`] is configured.
+    pub(crate) async fn acquire_permit(&self) -> Result<Option<Permit>, RequestError> {
+        let Some(limiter) = &self.limiter else {
+            return Ok(None);
+        };
+        let limit = match limiter.limit {
+            ConcurrencyLimit::Fixed(limit) => limit,
+            ConcurrencyLimit::ServerSlots => match self.total_slots().await {
+                Some(total_slots) => total_slots,
+                None => return Ok(None),
+            },
+        };
+        limiter.acquire(limit.max(1), self.priority).await.map(Some)
+    }
+
+    /// The `total_slots` of `GET /props`, fetched once per link and its clones. `None` if the
+    /// server does not report it. The slots only limit and route requests, so a failed fetch is
+    /// `None` as well, and only fetched again if the error is retryable.
+    pub(crate) async fn total_slots(&self) -> Option<usize> {
+        let fetched = self
+            .total_slots
+            .get_or_try_init(|| async {
+                let total_slots = match self.get_json::<TotalSlots>("props").await {
+                    Ok(props) => props.total_slots,
+                    Err(error) if error.is_retryable() => return Err(error),
+                    Err(_) => None,
+                };
+                #[cfg(feature = "tracing")]
+                if total_slots.is_none() {
+                    tracing::warn!(
+                        "The server does not report `total_slots`, so requests are neither \
+                         limited nor routed by slot"
+                    );
+                }
+                Ok(total_slots)
+            })
+            .await;
+        match fetched {
+            Ok(total_slots) => *total_slots,
+            #[allow(unused_variables)]
+            Err(error) => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!("Could not fetch `total_slots`: {error}");
+                None
+            }
+        }
+    }
+
+    /// The completions waiting for a place, `0` without a [`ConcurrencyLimit`].
+    pub fn queue_depth(&self) -> usize {
+        self.limiter
+            .as_ref()
+            .map_or(0, |limiter| limiter.state.lock().unwrap().waiters.len())
+    }
+
+    /// The completions currently sent to the server, `0` without a [`ConcurrencyLimit`].
+    pub fn active_requests(&self) -> usize {
+        self.limiter
+            .as_ref()
+            .map_or(0, |limiter| limiter.state.lock().unwrap().active)
+    }
+
+    /// The resolved [`ConcurrencyLimit`], `None` if there is none, the server slots were not
+    /// fetched yet or the server does not report them.
+    pub fn concurrency_limit(&self) -> Option<usize> {
+        let limiter = self.limiter.as_ref()?;
+        match limiter.limit {
+            ConcurrencyLimit::Fixed(limit) => Some(limit.max(1)),
+            ConcurrencyLimit::ServerSlots => self
+                .total_slots
+                .get()
+                .copied()
+                .flatten()
+                .map(|slots| slots.max(1)),
+        }
+    }
+
+    /// Returns a link sharing this link's queue whose completions are queued ahead of those with a
+    /// lower priority. The default priority is `0`.
+    pub fn with_priority(&self, priority: i32) -> Self {
+        let mut link = self.clone();
+        link.priority = priority;
+        link
+    }
+}
+
+type Acquire = Pin<Box<dyn Future<Output = Result<Option<Permit>, RequestError>> + Send>>;
+
+/// Waits for a place in the queue before starting the stream, and holds it until the stream ends.
+pub(crate) struct QueuedStream<T> {
+    acquire: Option<Acquire>,
+    start: Option<Box<dyn FnOnce() -> ResultStream<T> + Send>>,
+    inner: Option<(ResultStream<T>, Option<Permit>)>,
+}
+
+impl<T> QueuedStream<T> {
+    pub(crate) fn new(
+        link: &LlamaLink,
+        start: impl FnOnce() -> ResultStream<T> + Send + 'static,
+    ) -> Self {
+        let link = link.clone();
+        Self {
+            acquire: Some(Box::pin(async move { link.acquire_permit().await })),
+            start: Some(Box::new(start)),
+            inner: None,
+        }
+    }
+}
+
+impl<T> Stream for QueuedStream<T> {
+    type Item = Result<T, CompletionStreamError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(acquire) = &mut self.acquire {
+            let permit = match acquire.as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(permit) => permit,
+            };
+            self.acquire = None;
+            let start = self.start.take();
+            match (permit, start) {
+                (Ok(permit), Some(start)) => self.inner = Some((start(), permit)),
+                (Ok(_), None) => return Poll::Ready(None),
+                (Err(error), _) => return Poll::Ready(Some(Err(error.into()))),
+            }
+        }
+        let Some((inner, _)) = &mut self.inner else {
+            return Poll::Ready(None);
+        };
+        let item = inner.as_mut().poll_next(cx);
+        if let Poll::Ready(None) = item {
+            // Release the place as soon as the stream ends, not when it is dropped
+            self.inner = None;
+        }
+        item
+    }
+}
@@ -0,0 +1,113 @@
+use serde::{Serialize, Serializer};
+
+use crate::{LlamaLink, PromptFormatter, RequestError};
+
+/// Adjustments to the likelihood of tokens appearing in a completion, set through [`crate::Config`].
+///
+/// Strings are tokenized by the server and the bias is applied to each of their tokens. Use
+/// [`LlamaLink::resolve_logit_bias`] to tokenize them up front, e.g. for servers that only accept
+/// token ids or to check how a string tokenizes.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct LogitBias(Vec<(BiasTarget, Bias)>);
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum BiasTarget {
+    Token(u32),
+    Text(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bias {
+    /// Added to the logit of the token. Positive values make the token more likely.
+    Value(f32),
+    /// The token is never generated.
+    Ban,
+}
+
+impl Serialize for Bias {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Bias::Value(value) => serializer.serialize_f32(*value),
+            Bias::Ban => serializer.serialize_bool(false),
+        }
+    }
+}
+
+impl LogitBias {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bias_token(mut self, token: u32, bias: f32) -> Self {
+        self.0.push((BiasTarget::Token(token), Bias::Value(bias)));
+        self
+    }
+
+    pub fn ban_token(mut self, token: u32) -> Self {
+        self.0.push((BiasTarget::Token(token), Bias::Ban));
+        self
+    }
+
+    /// Adds `bias` to every token of `text`. Negative values discourage it.
+    pub fn boost_string(mut self, text: impl Into<String>, bias: f32) -> Self {
+        self.0
+            .push((BiasTarget::Text(text.into()), Bias::Value(bias)));
+        self
+    }
+
+    /// Bans every token of `text`.
+    pub fn ban_string(mut self, text: impl Into<String>) -> Self {
+        self.0.push((BiasTarget::Text(text.into()), Bias::Ban));
+        self
+    }
+
+    /// Adds all entries of `other` to this bias.
+    pub fn extend(mut self, other: LogitBias) -> Self {
+        self.0.extend(other.0);
+        self
+    }
+
+    pub fn entries(&self) -> &[(BiasTarget, Bias)] {
+        &self.0
+    }
+}
+
+impl LlamaLink {
+    /// Tokenizes every string entry of `logit_bias` through the server, so only token ids remain.
+    pub async fn resolve_logit_bias(
+        &self,
+        logit_bias: &LogitBias,
+    ) -> Result<LogitBias, RequestError> {
+        let mut resolved = LogitBias::new();
+        for (target, bias) in logit_bias.entries() {
+            match target {
+                BiasTarget::Token(token) => resolved.0.push((BiasTarget::Token(*token), *bias)),
+                BiasTarget::Text(text) => {
+                    for token in self.tokenize(text).await? {
+                        resolved.0.push((BiasTarget::Token(token), *bias));
+                    }
+                }
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Bans the [`PromptFormatter::special_tokens`] of `formatter`, e.g. so role headers never leak
+    /// into user facing completions. Special tokens are resolved to ids through the server, since
+    /// the server tokenizes string biases as plain text. Entries that do not map to a single token
+    /// in the model's vocabulary are skipped.
+    pub async fn special_tokens_logit_bias(
+        &self,
+        formatter: &PromptFormatter,
+    ) -> Result<LogitBias, RequestError> {
+        let mut logit_bias = LogitBias::new();
+        for special_token in formatter.special_tokens() {
+            if let [token] = self.tokenize(special_token).await?.as_slice() {
+                logit_bias = logit_bias.ban_token(*token);
+            }
+        }
+        Ok(logit_bias)
+    }
+}
@@ -0,0 +1,45 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{LlamaLink, RequestError};
+
+/// A LoRA adapter loaded by the server, as reported by `GET /lora-adapters`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LoraAdapter {
+    pub id: usize,
+    pub path: String,
+    /// The global scale applied to requests that do not set their own.
+    pub scale: f32,
+    /// Fields not modeled by this crate.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The scale of a LoRA adapter, either globally or for a single request through [`crate::Config`].
+/// Adapters not listed in a request are disabled for that request.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LoraScale {
+    pub id: usize,
+    pub scale: f32,
+}
+
+impl LoraScale {
+    pub fn new(id: usize, scale: f32) -> Self {
+        Self { id, scale }
+    }
+}
+
+impl LlamaLink {
+    /// Returns the LoRA adapters loaded by the server.
+    pub async fn lora_adapters(&self) -> Result<Vec<LoraAdapter>, RequestError> {
+        self.get_json("lora-adapters").await
+    }
+
+    /// Sets the global scale of the given adapters. Adapters not listed are set to a scale of `0`.
+    pub async fn set_lora_adapters(&self, scales: &[LoraScale]) -> Result<(), RequestError> {
+        let _: Value = self
+            .post_json("lora-adapters", &serde_json::to_value(scales)?)
+            .await?;
+        Ok(())
+    }
+}
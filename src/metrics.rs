@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+
+use crate::{LlamaLink, RequestError};
+
+/// A single sample from the Prometheus text exposition format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricSample {
+    /// The metric name, e.g. `llamacpp:requests_processing`.
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+    /// Milliseconds since the unix epoch, if the exporter provided one.
+    pub timestamp: Option<i64>,
+}
+
+/// Server statistics scraped from `GET /metrics`. Requires the server to be started with `--metrics`.
+///
+/// Fields are `None` when the server does not export the metric, e.g. older server versions.
+/// Every sample, including ones not modeled here, is available through [`ServerMetrics::samples`].
+#[derive(Debug, Clone, Default)]
+pub struct ServerMetrics {
+    /// Number of prompt tokens processed.
+    pub prompt_tokens_total: Option<f64>,
+    /// Prompt processing time in seconds.
+    pub prompt_seconds_total: Option<f64>,
+    /// Number of generated tokens.
+    pub tokens_predicted_total: Option<f64>,
+    /// Generation time in seconds.
+    pub tokens_predicted_seconds_total: Option<f64>,
+    /// Total number of decode calls.
+    pub n_decode_total: Option<f64>,
+    /// Average number of busy slots per decode call.
+    pub n_busy_slots_per_decode: Option<f64>,
+    /// Average prompt throughput in tokens per second.
+    pub prompt_tokens_seconds: Option<f64>,
+    /// Average generation throughput in tokens per second.
+    pub predicted_tokens_seconds: Option<f64>,
+    /// KV cache usage, where `1.0` is full.
+    pub kv_cache_usage_ratio: Option<f64>,
+    /// Number of tokens in the KV cache.
+    pub kv_cache_tokens: Option<f64>,
+    /// Number of requests currently being processed.
+    pub requests_processing: Option<f64>,
+    /// Number of requests waiting for a free slot.
+    pub requests_deferred: Option<f64>,
+    pub samples: Vec<MetricSample>,
+}
+
+impl ServerMetrics {
+    /// Parses the Prometheus text exposition format.
+    pub fn parse(text: &str) -> Result<Self, RequestError> {
+        let mut metrics = ServerMetrics::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let sample = parse_sample(line).ok_or_else(|| RequestError::Parsing {
+                issue: format!("Invalid metrics line `{line}`"),
+            })?;
+            let name = sample
+                .name
+                .strip_prefix("llamacpp:")
+                .unwrap_or(&sample.name);
+            let field = match name {
+                "prompt_tokens_total" => &mut metrics.prompt_tokens_total,
+                "prompt_seconds_total" => &mut metrics.prompt_seconds_total,
+                "tokens_predicted_total" => &mut metrics.tokens_predicted_total,
+                "tokens_predicted_seconds_total" => &mut metrics.tokens_predicted_seconds_total,
+                "n_decode_total" => &mut metrics.n_decode_total,
+                "n_busy_slots_per_decode" => &mut metrics.n_busy_slots_per_decode,
+                "prompt_tokens_seconds" => &mut metrics.prompt_tokens_seconds,
+                "predicted_tokens_seconds" => &mut metrics.predicted_tokens_seconds,
+                "kv_cache_usage_ratio" => &mut metrics.kv_cache_usage_ratio,
+                "kv_cache_tokens" => &mut metrics.kv_cache_tokens,
+                "requests_processing" => &mut metrics.requests_processing,
+                "requests_deferred" => &mut metrics.requests_deferred,
+                _ => {
+                    metrics.samples.push(sample);
+                    continue;
+                }
+            };
+            *field = Some(sample.value);
+            metrics.samples.push(sample);
+        }
+        Ok(metrics)
+    }
+
+    /// Returns the first sample with the given name.
+    pub fn sample(&self, name: &str) -> Option<&MetricSample> {
+        self.samples.iter().find(|sample| sample.name == name)
+    }
+}
+
+fn parse_sample(line: &str) -> Option<MetricSample> {
+    let name_end = line
+        .find(|c: char| c == '{' || c.is_whitespace())
+        .unwrap_or(line.len());
+    let name = &line[..name_end];
+    if name.is_empty() {
+        return None;
+    }
+    let mut rest = &line[name_end..];
+    let mut labels = BTreeMap::new();
+    if let Some(label_part) = rest.strip_prefix('{') {
+        rest = parse_labels(label_part, &mut labels)?;
+    }
+    let mut parts = rest.split_whitespace();
+    let value = parse_value(parts.next()?)?;
+    let timestamp = match parts.next() {
+        Some(timestamp) => Some(timestamp.parse().ok()?),
+        None => None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(MetricSample {
+        name: name.to_owned(),
+        labels,
+        value,
+        timestamp,
+    })
+}
+
+/// Parses `key="value",...}` and returns the remainder after the closing brace.
+fn parse_labels<'a>(mut input: &'a str, labels: &mut BTreeMap<String, String>) -> Option<&'a str> {
+    loop {
+        input = input.trim_start();
+        if let Some(rest) = input.strip_prefix('}') {
+            return Some(rest);
+        }
+        let (key, rest) = input.split_once('=')?;
+        let mut chars = rest.trim_start().strip_prefix('"')?.char_indices();
+        let mut value = String::new();
+        let end = loop {
+            match chars.next()? {
+                (index, '"') => break index,
+                (_, '\\') => match chars.next()?.1 {
+                    'n' => value.push('\n'),
+                    other => value.push(other),
+                },
+                (_, other) => value.push(other),
+            }
+        };
+        labels.insert(key.trim().to_owned(), value);
+        // Skip past the opening and closing quotes
+        input = rest.trim_start()[end + 2..].trim_start();
+        input = input.strip_prefix(',').unwrap_or(input);
+    }
+}
+
+fn parse_value(value: &str) -> Option<f64> {
+    match value {
+        "+Inf" => Some(f64::INFINITY),
+        "-Inf" => Some(f64::NEG_INFINITY),
+        "NaN" => Some(f64::NAN),
+        _ => value.parse().ok(),
+    }
+}
+
+impl LlamaLink {
+    /// Scrapes `GET /metrics`. Requires the server to be started with `--metrics`.
+    pub async fn metrics(&self) -> Result<ServerMetrics, RequestError> {
+        let text = self.get_text("metrics").await?;
+        ServerMetrics::parse(&text)
+    }
+}
@@ -0,0 +1,548 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::{Config, LlamaLink, MEDIA_MARKER};
+
+/// A fake llama.cpp server on a random local port, for testing code built on [`LlamaLink`]
+/// without a model. Implements `/completion`, blocking and streaming, `/tokenize`, `/detokenize`,
+/// `/health` and `/props`. Other paths respond with `404` unless a response is scripted for them.
+///
+/// Responses are scripted per path with [`MockServer::enqueue`] and served in order. Once a path
+/// has no scripted response left, it falls back to its default behaviour, e.g. `/completion`
+/// generates [`MockServer::set_default_completion`]. Tokens are the bytes of the text.
+///
+/// Each slot remembers its last prompt and completion, and reports the prefix a new prompt shares
+/// with them as cached in `timings.cache_n`, unless the request disables `cache_prompt`. Requests
+/// without an `id_slot` use slot `0`.
+///
+/// Unscripted multimodal prompts are rejected unless [`MockServer::set_vision`] is enabled, or if
+/// the number of images does not match the number of [`MEDIA_MARKER`]s.
+///
+/// The server stops when dropped.
+pub struct MockServer {
+    url: String,
+    state: Arc<Mutex<MockState>>,
+    task: JoinHandle<()>,
+}
+
+struct MockState {
+    scripts: HashMap<String, VecDeque<MockResponse>>,
+    requests: Vec<CapturedRequest>,
+    latency: Duration,
+    default_completion: String,
+    total_slots: usize,
+    n_ctx: usize,
+    vision: bool,
+    /// The prompt and completion last processed by each slot.
+    slot_caches: HashMap<usize, String>,
+}
+
+/// A request received by a [`MockServer`].
+#[derive(Debug, Clone)]
+pub struct CapturedRequest {
+    pub method: String,
+    /// The path without the query, e.g. `/completion`.
+    pub path: String,
+    pub query: Option<String>,
+    /// Header names are lowercase.
+    pub headers: Vec<(String, String)>,
+    /// The json body, `Value::Null` if there was none or it was not json.
+    pub body: Value,
+}
+
+impl CapturedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A scripted response of a [`MockServer`].
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    reply: Reply,
+    delay: Duration,
+    chunk_delay: Duration,
+}
+
+#[derive(Debug, Clone)]
+enum Reply {
+    /// Streamed chunk by chunk if the request asks for a stream, otherwise joined.
+    Completion(Vec<String>),
+    Json {
+        status: u16,
+        body: Value,
+    },
+    Ndjson(Vec<Value>),
+    Events(Vec<Value>),
+}
+
+impl MockResponse {
+    /// A completion generating `content`, streamed word by word.
+    pub fn completion(content: &str) -> Self {
+        Self::chunks(content.split_inclusive(' '))
+    }
+
+    /// A completion streamed as exactly these chunks.
+    pub fn chunks<S: Into<String>>(chunks: impl IntoIterator<Item = S>) -> Self {
+        Self::from_reply(Reply::Completion(
+            chunks.into_iter().map(Into::into).collect(),
+        ))
+    }
+
+    /// Responds with `body` as json.
+    pub fn json(status: u16, body: Value) -> Self {
+        Self::from_reply(Reply::Json { status, body })
+    }
+
+    /// Streams `lines` as newline delimited json, like the Ollama endpoints.
+    pub fn ndjson(lines: impl IntoIterator<Item = Value>) -> Self {
+        Self::from_reply(Reply::Ndjson(lines.into_iter().collect()))
+    }
+
+    /// Streams `events` as server sent events followed by `[DONE]`, like the OpenAI compatible
+    /// endpoints, e.g. the chunks of `/v1/chat/completions`.
+    pub fn events(events: impl IntoIterator<Item = Value>) -> Self {
+        Self::from_reply(Reply::Events(events.into_iter().collect()))
+    }
+
+    /// An error in the format of the llama.cpp server, e.g. `error(400, "invalid_request_error", "...")`.
+    pub fn error(status: u16, kind: &str, message: &str) -> Self {
+        Self::json(
+            status,
+            json!({ "error": { "code": status, "message": message, "type": kind } }),
+        )
+    }
+
+    /// The error the server responds with when the prompt does not fit in the context window.
+    pub fn context_overflow(n_prompt_tokens: usize, n_ctx: usize) -> Self {
+        Self::json(
+            400,
+            json!({ "error": {
+                "code": 400,
+                "message": format!("request ({n_prompt_tokens} tokens) exceeds the available context size ({n_ctx} tokens), try increasing it"),
+                "type": "exceed_context_size_error",
+                "n_prompt_tokens": n_prompt_tokens,
+                "n_ctx": n_ctx,
+            } }),
+        )
+    }
+
+    /// The error the server responds with while loading the model.
+    pub fn unavailable() -> Self {
+        Self::error(503, "unavailable_error", "Loading model")
+    }
+
+    /// Waits before responding.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Waits before each streamed chunk.
+    pub fn with_chunk_delay(mut self, delay: Duration) -> Self {
+        self.chunk_delay = delay;
+        self
+    }
+
+    fn from_reply(reply: Reply) -> Self {
+        Self {
+            reply,
+            delay: Duration::ZERO,
+            chunk_delay: Duration::ZERO,
+        }
+    }
+}
+
+impl MockServer {
+    /// Starts the server on `127.0.0.1` with a random port.
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Could not bind the mock server");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(MockState {
+            scripts: HashMap::new(),
+            requests: Vec::new(),
+            latency: Duration::ZERO,
+            default_completion: "Hello from the mock server".to_owned(),
+            total_slots: 1,
+            n_ctx: 4096,
+            vision: false,
+            slot_caches: HashMap::new(),
+        }));
+        let task = tokio::spawn({
+            let state = state.clone();
+            async move {
+                while let Ok((socket, _)) = listener.accept().await {
+                    tokio::spawn(handle_connection(socket, state.clone()));
+                }
+            }
+        });
+        Self { url, state, task }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// A link to this server with the default [`Config`].
+    pub fn link(&self) -> LlamaLink {
+        LlamaLink::new(&self.url, Config::builder().build())
+    }
+
+    /// Serves `response` to the next request to `path` that has no earlier scripted response.
+    pub fn enqueue(&self, path: &str, response: MockResponse) {
+        self.state()
+            .scripts
+            .entry(normalize_path(path))
+            .or_default()
+            .push_back(response);
+    }
+
+    /// Every request received so far, in order.
+    pub fn requests(&self) -> Vec<CapturedRequest> {
+        self.state().requests.clone()
+    }
+
+    /// The requests received so far to `path`.
+    pub fn requests_to(&self, path: &str) -> Vec<CapturedRequest> {
+        let path = normalize_path(path);
+        self.state()
+            .requests
+            .iter()
+            .filter(|request| request.path == path)
+            .cloned()
+            .collect()
+    }
+
+    /// Waits before every response, in addition to any [`MockResponse::with_delay`].
+    pub fn set_latency(&self, latency: Duration) {
+        self.state().latency = latency;
+    }
+
+    /// What `/completion` generates when no response is scripted.
+    pub fn set_default_completion(&self, content: &str) {
+        self.state().default_completion = content.to_owned();
+    }
+
+    /// The `total_slots` reported by `/props`. Defaults to `1`.
+    pub fn set_total_slots(&self, total_slots: usize) {
+        self.state().total_slots = total_slots;
+    }
+
+    /// The context size reported by `/props`. Defaults to `4096`.
+    pub fn set_n_ctx(&self, n_ctx: usize) {
+        self.state().n_ctx = n_ctx;
+    }
+
+    /// Whether `/props` reports vision support. Disabled by default.
+    pub fn set_vision(&self, vision: bool) {
+        self.state().vision = vision;
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn normalize_path(path: &str) -> String {
+    format!("/{}", path.trim_start_matches('/'))
+}
+
+async fn handle_connection(mut socket: TcpStream, state: Arc<Mutex<MockState>>) {
+    let Some(request) = read_request(&mut socket).await else {
+        return;
+    };
+    let (response, latency) = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(request.clone());
+        let scripted = state
+            .scripts
+            .get_mut(&request.path)
+            .and_then(VecDeque::pop_front);
+        let response = scripted.unwrap_or_else(|| default_response(&state, &request));
+        (response, state.latency)
+    };
+    tokio::time::sleep(latency + response.delay).await;
+    let _ = match response.reply {
+        Reply::Completion(chunks) => {
+            let prompt = request.body.get("prompt").map_or(&Value::Null, |prompt| {
+                prompt.get("prompt_string").unwrap_or(prompt)
+            });
+            let prompt = prompt.as_str().unwrap_or_default();
+            let content = chunks.concat();
+            let cached = state.lock().unwrap().cache(&request.body, prompt, &content);
+            if request.body.get("stream").and_then(Value::as_bool) == Some(true) {
+                write_stream(
+                    &mut socket,
+                    &chunks,
+                    prompt.len(),
+                    cached,
+                    response.chunk_delay,
+                )
+                .await
+            } else {
+                let body = completion_body(&content, prompt.len(), cached, content.len(), true);
+                write_json(&mut socket, 200, &body).await
+            }
+        }
+        Reply::Json { status, body } => write_json(&mut socket, status, &body).await,
+        Reply::Ndjson(lines) => write_ndjson(&mut socket, &lines, response.chunk_delay).await,
+        Reply::Events(events) => write_events(&mut socket, &events, response.chunk_delay).await,
+    };
+    let _ = socket.shutdown().await;
+}
+
+impl MockState {
+    /// Stores the prompt and completion in the request's slot, returning the cached prefix.
+    fn cache(&mut self, body: &Value, prompt: &str, content: &str) -> usize {
+        let slot = body.get("id_slot").and_then(Value::as_u64).unwrap_or(0) as usize;
+        let previous = self
+            .slot_caches
+            .insert(slot, format!("{prompt}{content}"))
+            .unwrap_or_default();
+        if body.get("cache_prompt").and_then(Value::as_bool) == Some(false) {
+            return 0;
+        }
+        previous
+            .bytes()
+            .zip(prompt.bytes())
+            .take_while(|(cached, token)| cached == token)
+            .count()
+    }
+}
+
+fn default_response(state: &MockState, request: &CapturedRequest) -> MockResponse {
+    match request.path.as_str() {
+        "/completion" => {
+            let prompt = request.body.get("prompt");
+            let images = prompt
+                .and_then(|prompt| prompt.get("multimodal_data"))
+                .and_then(Value::as_array)
+                .map_or(0, Vec::len);
+            let markers = prompt
+                .and_then(|prompt| prompt.get("prompt_string"))
+                .and_then(Value::as_str)
+                .map_or(0, |prompt| prompt.matches(MEDIA_MARKER).count());
+            if images > 0 && !state.vision {
+                MockResponse::error(500, "server_error", "image input is not supported - hint: if this is unexpected, you may need to provide the mmproj")
+            } else if images != markers {
+                MockResponse::error(
+                    400,
+                    "invalid_request_error",
+                    &format!(
+                        "number of bitmaps ({images}) does not match number of markers ({markers})"
+                    ),
+                )
+            } else {
+                MockResponse::completion(&state.default_completion)
+            }
+        }
+        "/tokenize" => {
+            let content = request
+                .body
+                .get("content")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            let tokens: Vec<u32> = content.bytes().map(u32::from).collect();
+            MockResponse::json(200, json!({ "tokens": tokens }))
+        }
+        "/detokenize" => {
+            let bytes: Vec<u8> = request
+                .body
+                .get("tokens")
+                .and_then(Value::as_array)
+                .map(|tokens| {
+                    tokens
+                        .iter()
+                        .filter_map(Value::as_u64)
+                        .map(|token| token as u8)
+                        .collect()
+                })
+                .unwrap_or_default();
+            let content = String::from_utf8_lossy(&bytes);
+            MockResponse::json(200, json!({ "content": content }))
+        }
+        "/health" => MockResponse::json(200, json!({ "status": "ok" })),
+        "/props" => MockResponse::json(
+            200,
+            json!({
+                "total_slots": state.total_slots,
+                "default_generation_settings": { "n_ctx": state.n_ctx },
+                "modalities": { "vision": state.vision, "audio": false },
+                "chat_template": "",
+                "bos_token": "<|begin_of_text|>",
+            }),
+        ),
+        _ => MockResponse::error(404, "not_found_error", "File Not Found"),
+    }
+}
+
+fn completion_body(
+    content: &str,
+    prompt_tokens: usize,
+    cached_tokens: usize,
+    predicted_tokens: usize,
+    stop: bool,
+) -> Value {
+    json!({
+        "content": content,
+        "stop": stop,
+        "tokens_evaluated": prompt_tokens,
+        "tokens_predicted": predicted_tokens,
+        "tokens_cached": prompt_tokens + predicted_tokens,
+        "timings": {
+            "cache_n": cached_tokens,
+            "prompt_n": prompt_tokens - cached_tokens,
+            "predicted_n": predicted_tokens,
+        },
+    })
+}
+
+async fn read_request(socket: &mut TcpStream) -> Option<CapturedRequest> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 4096];
+    let header_end = loop {
+        if let Some(index) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break index + 4;
+        }
+        let read = socket.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+    let head = String::from_utf8(buffer[..header_end].to_vec()).ok()?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_owned();
+    let target = request_line.next()?;
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_owned())),
+        None => (target, None),
+    };
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_owned()))
+        .collect();
+    let content_length = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    while buffer.len() < header_end + content_length {
+        let read = socket.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+    let body = serde_json::from_slice(&buffer[header_end..header_end + content_length])
+        .unwrap_or(Value::Null);
+    Some(CapturedRequest {
+        method,
+        path: normalize_path(path),
+        query,
+        headers,
+        body,
+    })
+}
+
+async fn write_json(socket: &mut TcpStream, status: u16, body: &Value) -> std::io::Result<()> {
+    let body = body.to_string();
+    let head = format!(
+        "HTTP/1.1 {status} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        reason(status),
+        body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body.as_bytes()).await
+}
+
+async fn write_stream(
+    socket: &mut TcpStream,
+    chunks: &[String],
+    prompt_tokens: usize,
+    cached_tokens: usize,
+    chunk_delay: Duration,
+) -> std::io::Result<()> {
+    socket
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n")
+        .await?;
+    for chunk in chunks {
+        tokio::time::sleep(chunk_delay).await;
+        let event = json!({ "content": chunk, "stop": false });
+        socket
+            .write_all(format!("data: {event}\n\n").as_bytes())
+            .await?;
+        socket.flush().await?;
+    }
+    let predicted_tokens = chunks.iter().map(String::len).sum();
+    let event = completion_body("", prompt_tokens, cached_tokens, predicted_tokens, true);
+    socket
+        .write_all(format!("data: {event}\n\n").as_bytes())
+        .await
+}
+
+async fn write_ndjson(
+    socket: &mut TcpStream,
+    lines: &[Value],
+    line_delay: Duration,
+) -> std::io::Result<()> {
+    socket
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n",
+        )
+        .await?;
+    for line in lines {
+        tokio::time::sleep(line_delay).await;
+        socket.write_all(format!("{line}\n").as_bytes()).await?;
+        socket.flush().await?;
+    }
+    Ok(())
+}
+
+async fn write_events(
+    socket: &mut TcpStream,
+    events: &[Value],
+    event_delay: Duration,
+) -> std::io::Result<()> {
+    socket
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n")
+        .await?;
+    for event in events {
+        tokio::time::sleep(event_delay).await;
+        socket
+            .write_all(format!("data: {event}\n\n").as_bytes())
+            .await?;
+        socket.flush().await?;
+    }
+    socket.write_all(b"data: [DONE]\n\n").await
+}
+
+fn reason(status: u16) -> &'static str {
+    StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("Unknown")
+}
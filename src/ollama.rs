@@ -0,0 +1,553 @@
+use std::time::Duration;
+
+use llmtoolbox::ToolBox;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, USER_AGENT},
+    Client, Method, RequestBuilder, Response, StatusCode, Url,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tokio_stream::StreamExt;
+
+use crate::{
+    chat::{chat_messages, openai_tools, run_tool_call},
+    ApiKey, BuildError, ChatCompletion, Completion, CompletionBackend, CompletionChunk,
+    CompletionChunkStream, CompletionError, CompletionStream, CompletionStreamError, Config,
+    Constraint, FinishReason, FunctionCallContext, FunctionCallError, Image, LlamaLink, Message,
+    RequestError, ResultStream, ToolCall, ToolCallFunction, Usage,
+};
+
+/// A client for an Ollama server, through its native `/api/generate` and `/api/chat` endpoints.
+///
+/// [`Config`] fields are sent as Ollama `options`, with `n_predict` as `num_predict`. The llama.cpp
+/// specific fields `id_slot`, `lora`, `n_probs`, `post_sampling_probs` and `logit_bias` have no
+/// Ollama equivalent and are not sent. Code generic over [`CompletionBackend`], or using
+/// [`crate::Engine`], can switch between Ollama and llama.cpp by configuration.
+#[derive(Clone)]
+pub struct OllamaLink {
+    client: Client,
+    /// The normalized base url, always ending in `/`. The url as given if [`OllamaLink::new`] could
+    /// not parse it, so every request fails when sent.
+    url: Result<Url, String>,
+    model: String,
+    headers: HeaderMap,
+    api_key: Option<ApiKey>,
+    request_timeout: Option<Duration>,
+    options: Map<String, Value>,
+    keep_alive: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GenerateResponse {
+    #[serde(default)]
+    response: String,
+    #[serde(default)]
+    done: bool,
+    /// Set instead of the other fields if generation failed after the response started.
+    error: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatResponse {
+    message: Option<ChatResponseMessage>,
+    #[serde(default)]
+    done: bool,
+    done_reason: Option<String>,
+    prompt_eval_count: Option<usize>,
+    eval_count: Option<usize>,
+    /// Set instead of the other fields if generation failed after the response started.
+    error: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatResponseMessage {
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    tool_calls: Vec<OllamaToolCall>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaToolCall {
+    function: OllamaToolCallFunction,
+}
+
+/// Unlike OpenAI, Ollama sends the arguments as a json object instead of an encoded string.
+#[derive(Deserialize, Debug)]
+struct OllamaToolCallFunction {
+    name: String,
+    arguments: Value,
+}
+
+#[bon::bon]
+impl OllamaLink {
+    /// `http://` is assumed if `url` has no scheme. An invalid url does not fail here, but every
+    /// request does. Use [`OllamaLink::builder`] to validate the url up front.
+    pub fn new(url: &str, model: &str, config: Config) -> Self {
+        let url = crate::lenient_url(url);
+        let mut link = Self::builder()
+            .url(url.as_ref().map_or(crate::PLACEHOLDER_URL, Url::as_str))
+            .model(model)
+            .config(config)
+            .build()
+            .expect("A valid url always builds");
+        link.url = url;
+        link
+    }
+
+    /// Builds a link with a custom client, timeouts and headers.
+    ///
+    /// - `url`: The base url of the server, e.g. `http://localhost:11434`.
+    /// - `model`: The model to run, e.g. `llama3.2`.
+    /// - `client`: A preconfigured client. Defaults to [`Client::new`].
+    /// - `request_timeout`: The timeout of each non streaming request.
+    /// - `keep_alive`: How long Ollama keeps the model loaded after a request, e.g. `5m`.
+    /// - `default_headers`: Sent with every request.
+    /// - `api_key`: For servers behind an authenticating proxy.
+    #[builder(
+        start_fn(name = builder, vis = "pub"),
+        finish_fn(name = build, vis = "pub"),
+        builder_type(name = OllamaLinkBuilder, vis = "pub")
+    )]
+    fn from_builder(
+        #[builder(into)] url: String,
+        #[builder(into)] model: String,
+        config: Option<Config>,
+        client: Option<Client>,
+        request_timeout: Option<Duration>,
+        #[builder(into)] keep_alive: Option<String>,
+        default_headers: Option<HeaderMap>,
+        #[builder(into)] user_agent: Option<String>,
+        #[builder(into)] api_key: Option<ApiKey>,
+    ) -> Result<Self, BuildError> {
+        let url = Ok(crate::normalize_url(&url)?);
+        let mut headers = default_headers.unwrap_or_default();
+        if let Some(user_agent) = user_agent {
+            let value = HeaderValue::from_str(&user_agent)
+                .map_err(|_| BuildError::InvalidUserAgent { user_agent })?;
+            headers.insert(USER_AGENT, value);
+        }
+        Ok(Self {
+            client: client.unwrap_or_default(),
+            url,
+            model,
+            headers,
+            api_key,
+            request_timeout,
+            options: config.map(ollama_options).unwrap_or_default(),
+            keep_alive,
+        })
+    }
+
+    /// The normalized base url of the server. `None` if the url given to [`OllamaLink::new`] is
+    /// invalid.
+    pub fn url(&self) -> Option<&Url> {
+        self.url.as_ref().ok()
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn stream_request(&self, path: &str) -> RequestBuilder {
+        let request = self
+            .client
+            .request(Method::POST, crate::endpoint(&self.url, path))
+            .headers(self.headers.clone());
+        match &self.api_key {
+            Some(api_key) => request.bearer_auth(api_key.key()),
+            None => request,
+        }
+    }
+
+    fn request(&self, path: &str) -> RequestBuilder {
+        let request = self.stream_request(path);
+        match self.request_timeout {
+            Some(timeout) => request.timeout(timeout),
+            None => request,
+        }
+    }
+
+    /// The fields shared by every request. `format` is a json schema the output must match.
+    fn request_body(
+        &self,
+        stream: bool,
+        format: Option<&Map<String, Value>>,
+    ) -> Map<String, Value> {
+        let mut json = Map::new();
+        json.insert("model".to_owned(), Value::String(self.model.clone()));
+        json.insert("stream".to_owned(), Value::Bool(stream));
+        if !self.options.is_empty() {
+            json.insert("options".to_owned(), Value::Object(self.options.clone()));
+        }
+        if let Some(keep_alive) = &self.keep_alive {
+            json.insert("keep_alive".to_owned(), Value::String(keep_alive.clone()));
+        }
+        if let Some(format) = format {
+            json.insert("format".to_owned(), Value::Object(format.clone()));
+        }
+        json
+    }
+
+    /// The body of `/api/generate`. The prompt is sent `raw`, since like with [`LlamaLink`] it is
+    /// expected to be formatted already, e.g. by a [`crate::PromptFormatter`].
+    fn generate_body(
+        &self,
+        prompt: String,
+        stream: bool,
+        format: Option<&Map<String, Value>>,
+    ) -> Value {
+        let mut json = self.request_body(stream, format);
+        json.insert("prompt".to_owned(), Value::String(prompt));
+        json.insert("raw".to_owned(), Value::Bool(true));
+        Value::Object(json)
+    }
+
+    fn chat_body(&self, system: &str, messages: &[Message], stream: bool) -> Map<String, Value> {
+        let mut json = self.request_body(stream, None);
+        json.insert(
+            "messages".to_owned(),
+            Value::Array(ollama_messages(system, messages)),
+        );
+        json
+    }
+
+    async fn send(&self, path: &str, json: &Value) -> Result<Response, RequestError> {
+        let response = self.request(path).json(json).send().await?;
+        LlamaLink::check_status(response).await
+    }
+
+    pub async fn create_completion(&self, prompt: String) -> Result<String, CompletionError> {
+        self.create_completion_full(prompt)
+            .await
+            .map(|completion| completion.content)
+    }
+
+    /// Completes `prompt` through `/api/generate`. Ollama does not report token probabilities, so
+    /// they are always empty.
+    pub async fn create_completion_full(
+        &self,
+        prompt: String,
+    ) -> Result<Completion, CompletionError> {
+        self.generate(prompt, None).await
+    }
+
+    async fn generate(
+        &self,
+        prompt: String,
+        format: Option<&Map<String, Value>>,
+    ) -> Result<Completion, CompletionError> {
+        let json = self.generate_body(prompt, false, format);
+        let response = self.send("api/generate", &json).await?;
+        let response_body: GenerateResponse = response.json().await?;
+        Ok(Completion {
+            content: response_body.response,
+            probabilities: Vec::new(),
+            tokens_cached: None,
+        })
+    }
+
+    pub fn create_completion_stream(&self, prompt: String) -> CompletionStream {
+        Box::pin(
+            self.create_completion_stream_full(prompt)
+                .map(|chunk| chunk.map(|chunk| chunk.content)),
+        )
+    }
+
+    /// Streams `prompt` through `/api/generate`. Each chunk has empty token probabilities.
+    pub fn create_completion_stream_full(&self, prompt: String) -> CompletionChunkStream {
+        let json = self.generate_body(prompt, true, None);
+        let request = self.stream_request("api/generate").json(&json);
+        ndjson_stream(request, |line| {
+            match serde_json::from_str::<GenerateResponse>(line) {
+                Ok(GenerateResponse {
+                    error: Some(message),
+                    ..
+                }) => Some(Err(stream_error(message).into())),
+                Ok(response) if response.done && response.response.is_empty() => None,
+                Ok(response) => Some(Ok(CompletionChunk {
+                    content: response.response,
+                    probabilities: Vec::new(),
+                    tokens_cached: None,
+                })),
+                Err(err) => Some(Err(CompletionStreamError::from(err))),
+            }
+        })
+    }
+
+    /// Creates a completion through `/api/chat`, where Ollama applies the model's chat template.
+    pub async fn create_chat_completion(
+        &self,
+        system: &str,
+        messages: &[Message],
+    ) -> Result<String, CompletionError> {
+        self.create_chat_completion_full(system, messages)
+            .await?
+            .content
+            .ok_or_else(|| CompletionError::Parsing {
+                issue: "No `content` field in response message".to_owned(),
+            })
+    }
+
+    /// Like [`OllamaLink::create_chat_completion`], but returns the tool calls, finish reason and
+    /// usage as well.
+    pub async fn create_chat_completion_full(
+        &self,
+        system: &str,
+        messages: &[Message],
+    ) -> Result<ChatCompletion, CompletionError> {
+        let json = self.chat_body(system, messages, false);
+        self.send_chat_request(json).await
+    }
+
+    pub fn create_chat_completion_stream(
+        &self,
+        system: &str,
+        messages: &[Message],
+    ) -> CompletionStream {
+        let json = Value::Object(self.chat_body(system, messages, true));
+        let request = self.stream_request("api/chat").json(&json);
+        ndjson_stream(request, |line| {
+            match serde_json::from_str::<ChatResponse>(line) {
+                Ok(ChatResponse {
+                    error: Some(message),
+                    ..
+                }) => Some(Err(stream_error(message).into())),
+                Ok(response) => {
+                    let content = response.message.map(|message| message.content);
+                    match content {
+                        Some(content) if !content.is_empty() => Some(Ok(content)),
+                        _ if response.done => None,
+                        _ => Some(Ok(String::new())),
+                    }
+                }
+                Err(err) => Some(Err(CompletionStreamError::from(err))),
+            }
+        })
+    }
+
+    pub async fn call_function_chat<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<Result<O, E>, FunctionCallError> {
+        self.call_function_chat_full(system, messages, toolbox)
+            .await
+            .map(|e| e.output_result)
+    }
+
+    /// Calls a function in `toolbox` through Ollama's native tool calling. Ollama cannot require a
+    /// tool call, so a reply without one fails with [`FunctionCallError::Parsing`]. `raw_input` is
+    /// the tool call in the same `function_name`/`parameters` shape used by
+    /// [`LlamaLink::call_function_full`].
+    pub async fn call_function_chat_full<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<FunctionCallContext<O, E>, FunctionCallError> {
+        let mut json = self.chat_body(system, messages, false);
+        json.insert(
+            "tools".to_owned(),
+            Value::Array(openai_tools(toolbox.schema())),
+        );
+        let completion = self.send_chat_request(json).await?;
+        let tool_call =
+            completion
+                .tool_calls
+                .into_iter()
+                .next()
+                .ok_or_else(|| FunctionCallError::Parsing {
+                    issue: "The response did not contain a tool call".to_owned(),
+                })?;
+        run_tool_call(toolbox, tool_call).await
+    }
+
+    async fn send_chat_request(
+        &self,
+        json: Map<String, Value>,
+    ) -> Result<ChatCompletion, CompletionError> {
+        let response = self.send("api/chat", &Value::Object(json)).await?;
+        let response_body: ChatResponse = response.json().await?;
+        let message = response_body
+            .message
+            .ok_or_else(|| CompletionError::Parsing {
+                issue: "No `message` in response body".to_owned(),
+            })?;
+        let tool_calls: Vec<ToolCall> = message
+            .tool_calls
+            .into_iter()
+            .map(|tool_call| ToolCall {
+                id: None,
+                function: ToolCallFunction {
+                    name: tool_call.function.name,
+                    arguments: tool_call.function.arguments.to_string(),
+                },
+            })
+            .collect();
+        let finish_reason = if tool_calls.is_empty() {
+            response_body
+                .done_reason
+                .map(|reason| serde_json::from_value(Value::String(reason)))
+                .transpose()?
+        } else {
+            Some(FinishReason::ToolCalls)
+        };
+        let usage = match (response_body.prompt_eval_count, response_body.eval_count) {
+            (Some(prompt_tokens), Some(completion_tokens)) => Some(Usage {
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+            }),
+            _ => None,
+        };
+        Ok(ChatCompletion {
+            content: Some(message.content),
+            tool_calls,
+            finish_reason,
+            usage,
+            tokens_cached: None,
+        })
+    }
+
+    fn unsupported(operation: &str) -> RequestError {
+        RequestError::Unsupported {
+            operation: operation.to_owned(),
+            backend: "Ollama".to_owned(),
+        }
+    }
+}
+
+impl CompletionBackend for OllamaLink {
+    async fn complete(&self, prompt: String) -> Result<Completion, CompletionError> {
+        self.create_completion_full(prompt).await
+    }
+
+    fn complete_stream(&self, prompt: String) -> CompletionChunkStream {
+        self.create_completion_stream_full(prompt)
+    }
+
+    /// Json schemas are sent as the Ollama `format`. Grammars are not supported.
+    async fn complete_constrained(
+        &self,
+        prompt: String,
+        constraint: Constraint<'_>,
+    ) -> Result<Completion, CompletionError> {
+        match constraint {
+            Constraint::JsonSchema(schema) => self.generate(prompt, Some(schema)).await,
+            Constraint::Grammar(_) => {
+                Err(Self::unsupported("Grammar constrained generation").into())
+            }
+        }
+    }
+
+    /// Ollama has no tokenize endpoint.
+    async fn tokenize(&self, _content: &str) -> Result<Vec<u32>, RequestError> {
+        Err(Self::unsupported("Tokenizing"))
+    }
+}
+
+/// Maps the [`Config`] fields that Ollama supports to its `options`.
+fn ollama_options(config: Config) -> Map<String, Value> {
+    config
+        .into_map()
+        .into_iter()
+        .filter(|(_, value)| !value.is_null())
+        .filter_map(|(key, value)| {
+            let key = match key.as_str() {
+                "n_predict" => "num_predict",
+                "temperature" | "top_k" | "top_p" | "seed" | "stop" => key.as_str(),
+                _ => return None,
+            };
+            Some((key.to_owned(), value))
+        })
+        .collect()
+}
+
+/// Streams the newline delimited json of `request`, mapping each line with `on_line`. The stream
+/// ends when `on_line` returns `None` or the body ends.
+fn ndjson_stream<T, F>(request: RequestBuilder, on_line: F) -> ResultStream<T>
+where
+    T: Send + 'static,
+    F: FnMut(&str) -> Option<Result<T, CompletionStreamError>> + Send + 'static,
+{
+    struct State<F> {
+        request: Option<RequestBuilder>,
+        response: Option<Response>,
+        buffer: Vec<u8>,
+        on_line: F,
+    }
+    let state = State {
+        request: Some(request),
+        response: None,
+        buffer: Vec::new(),
+        on_line,
+    };
+    Box::pin(futures_util::stream::unfold(
+        state,
+        |mut state| async move {
+            loop {
+                if let Some(request) = state.request.take() {
+                    let response = match request.send().await {
+                        Ok(response) => LlamaLink::check_status(response).await,
+                        Err(error) => Err(RequestError::from(error)),
+                    };
+                    match response {
+                        Ok(response) => state.response = Some(response),
+                        Err(error) => return Some((Err(error.into()), state)),
+                    }
+                }
+                if let Some(end) = state.buffer.iter().position(|byte| *byte == b'\n') {
+                    let line: Vec<u8> = state.buffer.drain(..=end).collect();
+                    let line = String::from_utf8_lossy(&line);
+                    let line = line.trim();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    let item = (state.on_line)(line)?;
+                    return Some((item, state));
+                }
+                let response = state.response.as_mut()?;
+                match response.chunk().await {
+                    Ok(Some(chunk)) => state.buffer.extend_from_slice(&chunk),
+                    Ok(None) => {
+                        state.response = None;
+                        if state.buffer.is_empty() {
+                            return None;
+                        }
+                        // The last line may not end in a newline
+                        state.buffer.push(b'\n');
+                    }
+                    Err(error) => {
+                        state.response = None;
+                        state.buffer.clear();
+                        return Some((Err(RequestError::from(error).into()), state));
+                    }
+                }
+            }
+        },
+    ))
+}
+
+/// Ollama reports a failure after the `200` status was sent as a `{"error": "..."}` line of the
+/// stream. It is classified like the same error sent with an internal server error status.
+fn stream_error(message: String) -> RequestError {
+    RequestError::from_status(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        &json!({ "error": message }).to_string(),
+    )
+}
+
+/// The messages in the chat format of Ollama, which takes images as base64 strings in `images`.
+fn ollama_messages(system: &str, messages: &[Message]) -> Vec<Value> {
+    let mut chat = chat_messages(system, messages);
+    let offset = chat.len() - messages.len();
+    for (message, json) in messages.iter().zip(&mut chat[offset..]) {
+        if let Message::UserWithImages { text, images } = message {
+            let images: Vec<&str> = images.iter().map(Image::base64).collect();
+            *json = json!({ "role": "user", "content": text, "images": images });
+        }
+    }
+    chat
+}
@@ -0,0 +1,522 @@
+use std::{
+    cmp::Reverse,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use llmtoolbox::ToolBox;
+use tokio::task::JoinSet;
+use tokio_stream::StreamExt;
+
+use crate::{
+    BuildError, Completion, CompletionBackend, CompletionChunkStream, CompletionError,
+    CompletionStream, Config, Constraint, FunctionCallContext, FunctionCallError, LlamaLink,
+    Message, PromptFormatter, RequestError,
+};
+
+/// How [`LlamaPool`] picks the server for a request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Routing {
+    /// Each request goes to the next server in turn.
+    #[default]
+    RoundRobin,
+    /// The server with the fewest requests in flight from this pool.
+    LeastInFlight,
+    /// The server with the most idle slots, as reported by `GET /slots`, or `GET /health` on
+    /// servers without the slots endpoint. Servers reporting neither rank below those with idle
+    /// slots and above those with none. Ties fall back to [`Routing::LeastInFlight`]. A report is
+    /// reused for the pool's `free_slots_ttl`, less the requests the pool sent to the server since.
+    /// Streams are always routed by [`Routing::LeastInFlight`], since they are created without
+    /// awaiting.
+    FreeSlots,
+}
+
+/// Distributes requests over several llama.cpp servers with the same api as [`LlamaLink`].
+///
+/// A server that fails a request with a retryable error, e.g. because it cannot be reached or is
+/// still loading the model, is marked unhealthy and the request is retried on the next server. Unhealthy servers are
+/// only used again after the cooldown, or once every other server is unhealthy as well. Each
+/// server still applies its own [`crate::RetryPolicy`] before the pool moves on. Timeouts of the
+/// client, e.g. of the [`crate::ConcurrencyLimit`] queue or `request_timeout`, also move on to the
+/// next server, but do not mark the server unhealthy.
+///
+/// The pool covers completions, function calls and streams, and implements [`CompletionBackend`].
+/// Chat, classification, best-of-N sampling and the server management endpoints, e.g. slots or
+/// metrics, have no pool equivalent, call them on one of the [`LlamaPool::links`] instead.
+#[derive(Clone)]
+pub struct LlamaPool {
+    servers: Arc<[Arc<PoolServer>]>,
+    routing: Routing,
+    unhealthy_cooldown: Duration,
+    free_slots_ttl: Duration,
+    next: Arc<AtomicUsize>,
+}
+
+struct PoolServer {
+    link: LlamaLink,
+    in_flight: AtomicUsize,
+    unhealthy_until: Mutex<Option<Instant>>,
+    free_slots: Mutex<Option<FreeSlots>>,
+}
+
+/// The idle slots a server last reported for [`Routing::FreeSlots`].
+#[derive(Clone, Copy)]
+struct FreeSlots {
+    checked: Instant,
+    /// `None` if the server reports neither `/slots` nor `slots_idle`.
+    idle: Option<usize>,
+    /// The requests in flight from the pool when checked.
+    in_flight: usize,
+}
+
+impl PoolServer {
+    fn is_healthy(&self) -> bool {
+        let unhealthy_until = self.unhealthy_until.lock().unwrap();
+        unhealthy_until.is_none_or(|until| Instant::now() >= until)
+    }
+
+    fn mark_unhealthy(&self, cooldown: Duration) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            "Marking `{}` unhealthy for {cooldown:?}",
+            self.link.base_url()
+        );
+        *self.unhealthy_until.lock().unwrap() = Some(Instant::now() + cooldown);
+    }
+
+    fn mark_healthy(&self) {
+        *self.unhealthy_until.lock().unwrap() = None;
+    }
+
+    /// The idle slots if checked within `ttl`, less the requests the pool started since.
+    fn cached_free_slots(&self, ttl: Duration) -> Option<Option<usize>> {
+        let cached = (*self.free_slots.lock().unwrap())?;
+        if cached.checked.elapsed() > ttl {
+            return None;
+        }
+        let started = self
+            .in_flight
+            .load(Ordering::Relaxed)
+            .saturating_sub(cached.in_flight);
+        Some(cached.idle.map(|idle| idle.saturating_sub(started)))
+    }
+
+    fn start(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self.clone())
+    }
+}
+
+/// Counts a request as in flight until dropped.
+struct InFlight(Arc<PoolServer>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Orders servers with idle slots first, most first, then those that reported neither, then those
+/// with none idle.
+fn free_slots_rank(free_slots: Option<usize>) -> (u8, usize) {
+    match free_slots {
+        Some(0) => (0, 0),
+        None => (1, 0),
+        Some(idle) => (2, idle),
+    }
+}
+
+/// Runs `$request` with `$link` bound to each server in routing order until one succeeds or fails
+/// with an error that is not retryable. Client timeouts move on without marking the server.
+macro_rules! failover {
+    ($pool:expr, |$link:ident| $request:expr) => {{
+        let pool = $pool;
+        let mut last_error = None;
+        for server in pool.route().await {
+            let _in_flight = server.start();
+            let $link = &server.link;
+            match $request.await {
+                Ok(value) => {
+                    server.mark_healthy();
+                    return Ok(value);
+                }
+                Err(error) if error.is_retryable() => {
+                    if !error.is_client_timeout() {
+                        server.mark_unhealthy(pool.unhealthy_cooldown);
+                    }
+                    last_error = Some(error);
+                }
+                Err(error) => return Err(error),
+            }
+        }
+        Err(last_error.expect("A pool has at least one server"))
+    }};
+}
+
+#[bon::bon]
+impl LlamaPool {
+    /// # Panics
+    /// If `links` is empty. Use [`LlamaPool::from_urls`] to handle this as an error.
+    #[builder]
+    pub fn new(
+        links: Vec<LlamaLink>,
+        #[builder(default)] routing: Routing,
+        /// How long a failed server is avoided.
+        #[builder(default = Duration::from_secs(30))]
+        unhealthy_cooldown: Duration,
+        /// How long the idle slots reported for [`Routing::FreeSlots`] are reused.
+        #[builder(default = Duration::from_secs(1))]
+        free_slots_ttl: Duration,
+    ) -> Self {
+        assert!(!links.is_empty(), "{}", BuildError::NoServers);
+        let servers = links
+            .into_iter()
+            .map(|link| {
+                Arc::new(PoolServer {
+                    link,
+                    in_flight: AtomicUsize::new(0),
+                    unhealthy_until: Mutex::new(None),
+                    free_slots: Mutex::new(None),
+                })
+            })
+            .collect();
+        Self {
+            servers,
+            routing,
+            unhealthy_cooldown,
+            free_slots_ttl,
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// A round robin pool of servers at `urls` sharing `config`.
+    pub fn from_urls(urls: &[&str], config: Config) -> Result<Self, BuildError> {
+        if urls.is_empty() {
+            return Err(BuildError::NoServers);
+        }
+        let links = urls
+            .iter()
+            .map(|url| {
+                LlamaLink::builder()
+                    .url(*url)
+                    .config(config.clone())
+                    .build()
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self::builder().links(links).build())
+    }
+
+    pub fn links(&self) -> impl Iterator<Item = &LlamaLink> {
+        self.servers.iter().map(|server| &server.link)
+    }
+
+    /// The number of requests in flight from this pool for each server.
+    pub fn in_flight(&self) -> Vec<usize> {
+        self.servers
+            .iter()
+            .map(|server| server.in_flight.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Whether each server is currently considered healthy.
+    pub fn healthy(&self) -> Vec<bool> {
+        self.servers
+            .iter()
+            .map(|server| server.is_healthy())
+            .collect()
+    }
+
+    /// Checks `GET /health` on every server concurrently and updates whether it is considered
+    /// healthy. Returns the new health of each server.
+    pub async fn check_health(&self) -> Vec<bool> {
+        let mut checks = JoinSet::new();
+        for (index, server) in self.servers.iter().enumerate() {
+            let server = server.clone();
+            checks.spawn(async move { (index, server.link.health().await.is_ok()) });
+        }
+        while let Some(result) = checks.join_next().await {
+            let Ok((index, healthy)) = result else {
+                continue;
+            };
+            let server = &self.servers[index];
+            if healthy {
+                server.mark_healthy();
+            } else {
+                server.mark_unhealthy(self.unhealthy_cooldown);
+            }
+        }
+        self.healthy()
+    }
+
+    /// All servers in the order they should be tried. Unhealthy servers come last.
+    async fn route(&self) -> Vec<Arc<PoolServer>> {
+        match self.routing {
+            Routing::FreeSlots => {
+                let free_slots = self.free_slots().await;
+                let mut order: Vec<usize> = (0..self.servers.len()).collect();
+                // Stable, so ties keep the least in flight order
+                order.sort_by_key(|index| self.servers[*index].in_flight.load(Ordering::Relaxed));
+                order.sort_by_key(|index| Reverse(free_slots_rank(free_slots[*index])));
+                self.healthy_first(order)
+            }
+            _ => self.route_now(),
+        }
+    }
+
+    /// Like [`LlamaPool::route`], without querying the servers.
+    fn route_now(&self) -> Vec<Arc<PoolServer>> {
+        let len = self.servers.len();
+        let order = match self.routing {
+            Routing::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % len.max(1);
+                (0..len).map(|offset| (start + offset) % len).collect()
+            }
+            Routing::LeastInFlight | Routing::FreeSlots => {
+                let mut order: Vec<usize> = (0..len).collect();
+                order.sort_by_key(|index| self.servers[*index].in_flight.load(Ordering::Relaxed));
+                order
+            }
+        };
+        self.healthy_first(order)
+    }
+
+    fn healthy_first(&self, order: Vec<usize>) -> Vec<Arc<PoolServer>> {
+        let (healthy, unhealthy): (Vec<_>, Vec<_>) = order
+            .into_iter()
+            .map(|index| self.servers[index].clone())
+            .partition(|server| server.is_healthy());
+        healthy.into_iter().chain(unhealthy).collect()
+    }
+
+    /// The number of idle slots of each healthy server, `None` if unknown. Only servers without a
+    /// report from the last `free_slots_ttl` are queried.
+    async fn free_slots(&self) -> Vec<Option<usize>> {
+        let mut free_slots = vec![None; self.servers.len()];
+        let mut queries = JoinSet::new();
+        for (index, server) in self.servers.iter().enumerate() {
+            if !server.is_healthy() {
+                continue;
+            }
+            if let Some(free) = server.cached_free_slots(self.free_slots_ttl) {
+                free_slots[index] = free;
+                continue;
+            }
+            let server = server.clone();
+            queries.spawn(async move {
+                let in_flight = server.in_flight.load(Ordering::Relaxed);
+                let link = &server.link;
+                let idle = match link.slots().await {
+                    Ok(slots) => Some(slots.iter().filter(|slot| !slot.is_processing).count()),
+                    Err(_) => link
+                        .health()
+                        .await
+                        .ok()
+                        .and_then(|health| health.slots_idle),
+                };
+                *server.free_slots.lock().unwrap() = Some(FreeSlots {
+                    checked: Instant::now(),
+                    idle,
+                    in_flight,
+                });
+                (index, idle)
+            });
+        }
+        while let Some(result) = queries.join_next().await {
+            if let Ok((index, free)) = result {
+                free_slots[index] = free;
+            }
+        }
+        free_slots
+    }
+
+    pub async fn create_completion_with_format(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> Result<String, CompletionError> {
+        self.create_completion_with_format_full(system, messages, formatter)
+            .await
+            .map(|completion| completion.content)
+    }
+
+    pub async fn create_completion(&self, prompt: String) -> Result<String, CompletionError> {
+        self.create_completion_full(prompt)
+            .await
+            .map(|completion| completion.content)
+    }
+
+    pub async fn create_completion_with_format_full(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> Result<Completion, CompletionError> {
+        failover!(self, |link| link
+            .create_completion_with_format_full(system, messages, formatter))
+    }
+
+    pub async fn create_completion_full(
+        &self,
+        prompt: String,
+    ) -> Result<Completion, CompletionError> {
+        failover!(self, |link| link.create_completion_full(prompt.clone()))
+    }
+
+    pub async fn call_function<O, E>(
+        &self,
+        prompt: String,
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<Result<O, E>, FunctionCallError> {
+        self.call_function_full(prompt, toolbox)
+            .await
+            .map(|e| e.output_result)
+    }
+
+    pub async fn call_function_with_format<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<Result<O, E>, FunctionCallError> {
+        self.call_function_with_format_full(system, messages, formatter, toolbox)
+            .await
+            .map(|e| e.output_result)
+    }
+
+    pub async fn call_function_with_format_full<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<FunctionCallContext<O, E>, FunctionCallError> {
+        failover!(self, |link| link.call_function_with_format_full(
+            system, messages, formatter, toolbox
+        ))
+    }
+
+    pub async fn call_function_full<O, E>(
+        &self,
+        prompt: String,
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<FunctionCallContext<O, E>, FunctionCallError> {
+        failover!(self, |link| link
+            .call_function_full(prompt.clone(), toolbox))
+    }
+
+    pub fn create_formatted_completion_stream(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> CompletionStream {
+        Box::pin(
+            self.create_formatted_completion_stream_full(system, messages, formatter)
+                .map(|chunk| chunk.map(|chunk| chunk.content)),
+        )
+    }
+
+    pub fn create_completion_stream(&self, prompt: String) -> CompletionStream {
+        Box::pin(
+            self.create_completion_stream_full(prompt)
+                .map(|chunk| chunk.map(|chunk| chunk.content)),
+        )
+    }
+
+    pub fn create_formatted_completion_stream_full(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> CompletionChunkStream {
+        self.stream(|link| {
+            link.create_formatted_completion_stream_full(system, messages, formatter)
+        })
+    }
+
+    /// Streams from the first server in routing order. A stream cannot fail over once started, but
+    /// a server whose stream fails with a retryable error is marked unhealthy for the next request.
+    pub fn create_completion_stream_full(&self, prompt: String) -> CompletionChunkStream {
+        self.stream(|link| link.create_completion_stream_full(prompt))
+    }
+
+    fn stream(
+        &self,
+        start: impl FnOnce(&LlamaLink) -> CompletionChunkStream,
+    ) -> CompletionChunkStream {
+        let server = self.route_now().swap_remove(0);
+        let in_flight = server.start();
+        let unhealthy_cooldown = self.unhealthy_cooldown;
+        Box::pin(start(&server.link).map(move |chunk| {
+            let server = &in_flight.0;
+            match &chunk {
+                Ok(_) => server.mark_healthy(),
+                Err(error) if error.is_retryable() && !error.is_client_timeout() => {
+                    server.mark_unhealthy(unhealthy_cooldown)
+                }
+                Err(_) => {}
+            }
+            chunk
+        }))
+    }
+}
+
+impl CompletionBackend for LlamaPool {
+    async fn complete(&self, prompt: String) -> Result<Completion, CompletionError> {
+        self.create_completion_full(prompt).await
+    }
+
+    fn complete_stream(&self, prompt: String) -> CompletionChunkStream {
+        self.create_completion_stream_full(prompt)
+    }
+
+    async fn complete_constrained(
+        &self,
+        prompt: String,
+        constraint: Constraint<'_>,
+    ) -> Result<Completion, CompletionError> {
+        failover!(self, |link| link
+            .complete_constrained(prompt.clone(), constraint))
+    }
+
+    async fn tokenize(&self, content: &str) -> Result<Vec<u32>, RequestError> {
+        failover!(self, |link| link.tokenize(content))
+    }
+
+    /// Sends the images of `messages` as well, once the server reports vision support.
+    async fn complete_with_format(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> Result<Completion, CompletionError> {
+        self.create_completion_with_format_full(system, messages, formatter)
+            .await
+    }
+
+    /// Sends the images of `messages` as well, once the server reports vision support.
+    fn complete_stream_with_format(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> CompletionChunkStream {
+        self.create_formatted_completion_stream_full(system, messages, formatter)
+    }
+
+    /// Sends the images of `messages` as well, once the server reports vision support.
+    async fn call_function_with_format<O, E>(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+        toolbox: &ToolBox<O, E>,
+    ) -> Result<Result<O, E>, FunctionCallError> {
+        LlamaPool::call_function_with_format(self, system, messages, formatter, toolbox).await
+    }
+}
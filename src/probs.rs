@@ -0,0 +1,111 @@
+use serde::Deserialize;
+
+/// A generated token with its log probability and the top candidates considered at its position.
+/// Only returned when [`crate::Config`] sets `n_probs`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenProbability {
+    pub id: Option<u32>,
+    pub token: String,
+    pub logprob: f64,
+    /// The `n_probs` most likely candidates, most likely first.
+    pub top: Vec<TokenCandidate>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenCandidate {
+    pub id: Option<u32>,
+    pub token: String,
+    pub logprob: f64,
+}
+
+impl TokenProbability {
+    pub fn prob(&self) -> f64 {
+        self.logprob.exp()
+    }
+
+    /// The entropy in nats of the distribution over the top candidates, renormalized to sum to one.
+    /// Higher values mean the model was less certain at this position.
+    pub fn entropy(&self) -> f64 {
+        let total: f64 = self.top.iter().map(TokenCandidate::prob).sum();
+        if total <= 0.0 {
+            return 0.0;
+        }
+        self.top
+            .iter()
+            .map(|candidate| candidate.prob() / total)
+            .filter(|p| *p > 0.0)
+            .map(|p| -p * p.ln())
+            .sum()
+    }
+}
+
+impl TokenCandidate {
+    pub fn prob(&self) -> f64 {
+        self.logprob.exp()
+    }
+}
+
+/// The sum of the log probabilities of the tokens, i.e. the log likelihood of the sequence.
+pub fn log_likelihood(probabilities: &[TokenProbability]) -> f64 {
+    probabilities.iter().map(|token| token.logprob).sum()
+}
+
+/// The mean log probability per token, or `None` if there are no tokens.
+pub fn mean_logprob(probabilities: &[TokenProbability]) -> Option<f64> {
+    if probabilities.is_empty() {
+        return None;
+    }
+    Some(log_likelihood(probabilities) / probabilities.len() as f64)
+}
+
+/// The wire format of `completion_probabilities`. Depending on `post_sampling_probs`, the server
+/// reports either `logprob`/`top_logprobs` or `prob`/`top_probs`.
+#[derive(Deserialize, Debug)]
+pub(crate) struct RawTokenProbability {
+    #[serde(default)]
+    id: Option<u32>,
+    #[serde(default)]
+    token: String,
+    logprob: Option<f64>,
+    prob: Option<f64>,
+    top_logprobs: Option<Vec<RawTokenCandidate>>,
+    top_probs: Option<Vec<RawTokenCandidate>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RawTokenCandidate {
+    #[serde(default)]
+    id: Option<u32>,
+    #[serde(default)]
+    token: String,
+    logprob: Option<f64>,
+    prob: Option<f64>,
+}
+
+fn to_logprob(logprob: Option<f64>, prob: Option<f64>) -> f64 {
+    logprob
+        .or_else(|| prob.map(f64::ln))
+        .unwrap_or(f64::NEG_INFINITY)
+}
+
+impl From<RawTokenProbability> for TokenProbability {
+    fn from(raw: RawTokenProbability) -> Self {
+        let top = raw
+            .top_logprobs
+            .or(raw.top_probs)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|candidate| TokenCandidate {
+                id: candidate.id,
+                token: candidate.token,
+                logprob: to_logprob(candidate.logprob, candidate.prob),
+            })
+            .collect();
+        TokenProbability {
+            id: raw.id,
+            token: raw.token,
+            logprob: to_logprob(raw.logprob, raw.prob),
+            top,
+        }
+    }
+}
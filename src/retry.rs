@@ -0,0 +1,159 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, SystemTime},
+};
+
+use reqwest::{header::RETRY_AFTER, RequestBuilder, Response, StatusCode};
+
+use crate::{LlamaLink, RequestError};
+
+/// When and how often non streaming requests are retried, e.g. while the server is loading the
+/// model or has no free slot.
+#[derive(Debug, Clone, bon::Builder)]
+pub struct RetryPolicy {
+    /// The total number of attempts, including the first.
+    #[builder(default = 3)]
+    max_attempts: u32,
+    /// The delay before the first retry.
+    #[builder(default = Duration::from_millis(250))]
+    initial_backoff: Duration,
+    #[builder(default = Duration::from_secs(10))]
+    max_backoff: Duration,
+    /// The factor the delay grows by after each retry.
+    #[builder(default = 2.0)]
+    multiplier: f64,
+    /// Randomizes each delay between zero and the computed backoff, so concurrent clients do not
+    /// retry in lockstep.
+    #[builder(default = true)]
+    jitter: bool,
+    #[builder(default = vec![
+        StatusCode::TOO_MANY_REQUESTS,
+        StatusCode::BAD_GATEWAY,
+        StatusCode::SERVICE_UNAVAILABLE,
+        StatusCode::GATEWAY_TIMEOUT,
+    ])]
+    retryable_status_codes: Vec<StatusCode>,
+    /// Retry when the connection could not be established, e.g. while the server is starting.
+    #[builder(default = true)]
+    retry_connection_errors: bool,
+    /// Retry when the link's `request_timeout` elapsed. The server may still finish the timed out
+    /// attempt, so a completion may be generated twice.
+    #[builder(default = true)]
+    retry_timeouts: bool,
+    /// Wait for the delay in a `Retry-After` header, in seconds, instead of the backoff. The delay
+    /// is capped at `max_backoff`, so a server cannot stall a call indefinitely.
+    #[builder(default = true)]
+    honor_retry_after: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        Self::builder().max_attempts(1).build()
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .mul_f64(self.multiplier.powi(retry as i32))
+            .min(self.max_backoff);
+        if self.jitter {
+            backoff.mul_f64(random_fraction())
+        } else {
+            backoff
+        }
+    }
+
+    fn retry_after(&self, response: &Response) -> Option<Duration> {
+        if !self.honor_retry_after {
+            return None;
+        }
+        let seconds = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+        let delay = Duration::from_secs(seconds.trim().parse().ok()?);
+        Some(delay.min(self.max_backoff))
+    }
+
+    fn retries_error(&self, error: &reqwest::Error) -> bool {
+        (self.retry_connection_errors && error.is_connect())
+            || (self.retry_timeouts && error.is_timeout())
+    }
+}
+
+/// A number in `[0, 1)`. Not suitable for anything but spreading out retries.
+fn random_fraction() -> f64 {
+    (random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A random number from the std hasher's random keys and the time. Not cryptographically secure.
+pub(crate) fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+    hasher.write_u128(nanos);
+    hasher.finish()
+}
+
+impl LlamaLink {
+    /// Sends the request built by `request` according to the retry policy, or through the
+    /// [`crate::Cassette`], and checks the status of the final response. `request` is called for
+    /// every attempt, so e.g. api keys are refreshed.
+    pub(crate) async fn send(
+        &self,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<Response, RequestError> {
+        let response = match &self.cassette {
+            Some(cassette) => self.send_with_cassette(cassette, &request).await?,
+            None => self.send_with_retries(&request).await?,
+        };
+        Self::check_status(response).await
+    }
+
+    pub(crate) async fn send_with_retries(
+        &self,
+        request: &impl Fn() -> RequestBuilder,
+    ) -> Result<Response, RequestError> {
+        let policy = &self.retry_policy;
+        let mut attempt = 1;
+        loop {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(
+                "Sending request, attempt {attempt} of {}",
+                policy.max_attempts
+            );
+            let delay = match request().send().await {
+                Ok(response) => {
+                    let status = response.status();
+                    if attempt >= policy.max_attempts
+                        || !policy.retryable_status_codes.contains(&status)
+                    {
+                        return Ok(response);
+                    }
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!("Attempt {attempt} failed with status {status}, retrying");
+                    policy
+                        .retry_after(&response)
+                        .unwrap_or_else(|| policy.backoff(attempt - 1))
+                }
+                Err(error) => {
+                    if attempt >= policy.max_attempts || !policy.retries_error(&error) {
+                        return Err(error.into());
+                    }
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!("Attempt {attempt} failed with `{error}`, retrying");
+                    policy.backoff(attempt - 1)
+                }
+            };
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
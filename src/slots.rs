@@ -0,0 +1,109 @@
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{LlamaLink, RequestError};
+
+/// The state of a server slot, as reported by `GET /slots`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Slot {
+    pub id: usize,
+    /// The task currently assigned to the slot, `-1` if none.
+    #[serde(default)]
+    pub id_task: Option<i64>,
+    /// The context size of the slot.
+    #[serde(default)]
+    pub n_ctx: Option<usize>,
+    #[serde(default)]
+    pub is_processing: bool,
+    /// The prompt most recently processed in the slot.
+    #[serde(default)]
+    pub prompt: Option<String>,
+    #[serde(default)]
+    pub next_token: Option<SlotNextToken>,
+    /// Fields not modeled by this crate, e.g. the sampling `params` of the slot.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SlotNextToken {
+    #[serde(default)]
+    pub has_next_token: bool,
+    #[serde(default)]
+    pub n_remain: Option<i64>,
+    #[serde(default)]
+    pub n_decoded: Option<usize>,
+}
+
+/// The result of saving a slot's KV cache to disk.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SlotSaveResult {
+    pub id_slot: usize,
+    pub filename: String,
+    /// The number of tokens saved.
+    pub n_saved: usize,
+    /// The number of bytes written.
+    pub n_written: usize,
+    #[serde(default)]
+    pub timings: Map<String, Value>,
+}
+
+/// The result of restoring a slot's KV cache from disk.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SlotRestoreResult {
+    pub id_slot: usize,
+    pub filename: String,
+    /// The number of tokens restored.
+    pub n_restored: usize,
+    /// The number of bytes read.
+    pub n_read: usize,
+    #[serde(default)]
+    pub timings: Map<String, Value>,
+}
+
+/// The result of erasing a slot's KV cache.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SlotEraseResult {
+    pub id_slot: usize,
+    /// The number of tokens erased.
+    pub n_erased: usize,
+}
+
+impl LlamaLink {
+    /// Returns the state of every slot. Requires the server to have the slots endpoint enabled.
+    pub async fn slots(&self) -> Result<Vec<Slot>, RequestError> {
+        self.get_json("slots").await
+    }
+
+    /// Saves the KV cache of slot `id_slot` to `filename`, relative to the server's `--slot-save-path`.
+    pub async fn save_slot(
+        &self,
+        id_slot: usize,
+        filename: &str,
+    ) -> Result<SlotSaveResult, RequestError> {
+        self.post_json(
+            &format!("slots/{id_slot}?action=save"),
+            &json!({ "filename": filename }),
+        )
+        .await
+    }
+
+    /// Restores the KV cache of slot `id_slot` from `filename`, relative to the server's `--slot-save-path`.
+    pub async fn restore_slot(
+        &self,
+        id_slot: usize,
+        filename: &str,
+    ) -> Result<SlotRestoreResult, RequestError> {
+        self.post_json(
+            &format!("slots/{id_slot}?action=restore"),
+            &json!({ "filename": filename }),
+        )
+        .await
+    }
+
+    /// Erases the KV cache of slot `id_slot`.
+    pub async fn erase_slot(&self, id_slot: usize) -> Result<SlotEraseResult, RequestError> {
+        self.post_json(&format!("slots/{id_slot}?action=erase"), &json!({}))
+            .await
+    }
+}
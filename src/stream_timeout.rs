@@ -0,0 +1,70 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use reqwest_eventsource::{Event, EventSource};
+use tokio::time::Sleep;
+use tokio_stream::Stream;
+
+/// Marks that a [`TimeoutEventSource`] did not receive a message in time.
+pub(crate) struct TimedOut;
+
+/// Ends an [`EventSource`] if the first message does not arrive within `first_token` of the
+/// request starting, or if any later message does not arrive within `idle` of the previous one.
+pub(crate) struct TimeoutEventSource {
+    inner: EventSource,
+    idle: Option<Duration>,
+    deadline: Option<Pin<Box<Sleep>>>,
+    timed_out: bool,
+}
+
+impl TimeoutEventSource {
+    pub(crate) fn new(
+        inner: EventSource,
+        first_token: Option<Duration>,
+        idle: Option<Duration>,
+    ) -> Self {
+        Self {
+            inner,
+            idle,
+            deadline: first_token.map(|timeout| Box::pin(tokio::time::sleep(timeout))),
+            timed_out: false,
+        }
+    }
+}
+
+impl Stream for TimeoutEventSource {
+    type Item = Result<Result<Event, reqwest_eventsource::Error>, TimedOut>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.timed_out {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(Some(event)) => {
+                if matches!(event, Ok(Event::Message(_))) {
+                    self.deadline = self
+                        .idle
+                        .map(|timeout| Box::pin(tokio::time::sleep(timeout)));
+                }
+                Poll::Ready(Some(Ok(event)))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => {
+                let elapsed = match &mut self.deadline {
+                    Some(deadline) => deadline.as_mut().poll(cx).is_ready(),
+                    None => false,
+                };
+                if !elapsed {
+                    return Poll::Pending;
+                }
+                self.timed_out = true;
+                self.inner.close();
+                Poll::Ready(Some(Err(TimedOut)))
+            }
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{chat::chat_messages, LlamaLink, Message, PromptFormatter, RequestError};
+
+/// The number of characters of context included on each side of a [`TemplateDivergence`].
+const DIVERGENCE_CONTEXT: usize = 32;
+
+#[derive(Deserialize, Debug)]
+struct ApplyTemplateResponse {
+    prompt: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Props {
+    #[serde(default)]
+    bos_token: Option<String>,
+}
+
+/// The first point where a [`PromptFormatter`]'s output differs from the server's template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateDivergence {
+    /// The byte offset of the first differing character.
+    pub offset: usize,
+    /// The server's output around the divergence.
+    pub server: String,
+    /// The formatter's output around the divergence.
+    pub formatter: String,
+}
+
+impl std::fmt::Display for TemplateDivergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Prompts diverge at byte {}:\n  server:    {:?}\n  formatter: {:?}",
+            self.offset, self.server, self.formatter
+        )
+    }
+}
+
+impl LlamaLink {
+    /// Renders the messages with the model's own chat template through `POST /apply-template`,
+    /// including the generation prompt for the assistant's reply.
+    pub async fn apply_template(
+        &self,
+        system: &str,
+        messages: &[Message],
+    ) -> Result<String, RequestError> {
+        let response: ApplyTemplateResponse = self
+            .post_json(
+                "apply-template",
+                &json!({ "messages": Value::Array(chat_messages(system, messages)) }),
+            )
+            .await?;
+        Ok(response.prompt)
+    }
+
+    /// Compares the output of `formatter` against the model's own chat template. Returns `None` if
+    /// they are identical. The server omits the BOS token, e.g. `<|begin_of_text|>`, since it is
+    /// added during tokenization, so the `bos_token` of `GET /props` is removed from the start of
+    /// the formatter's output before comparing.
+    pub async fn compare_formatter(
+        &self,
+        system: &str,
+        messages: &[Message],
+        formatter: &PromptFormatter,
+    ) -> Result<Option<TemplateDivergence>, RequestError> {
+        let server = self.apply_template(system, messages).await?;
+        let props: Props = self.get_json("props").await?;
+        let formatted = (formatter.0)(system, messages);
+        let formatted = match props.bos_token.as_deref() {
+            Some(bos_token) if !bos_token.is_empty() && !server.starts_with(bos_token) => {
+                formatted.strip_prefix(bos_token).unwrap_or(&formatted)
+            }
+            _ => &formatted,
+        };
+        Ok(TemplateDivergence::between(&server, formatted))
+    }
+}
+
+impl TemplateDivergence {
+    /// Finds where `formatter` first differs from `server`, e.g. a template rendered elsewhere.
+    /// Returns `None` if they are identical. See [`LlamaLink::compare_formatter`].
+    pub fn between(server: &str, formatter: &str) -> Option<Self> {
+        first_divergence(server, formatter)
+    }
+}
+
+fn first_divergence(server: &str, formatter: &str) -> Option<TemplateDivergence> {
+    let offset = server
+        .char_indices()
+        .zip(formatter.chars())
+        .find(|((_, a), b)| a != b)
+        .map(|((offset, _), _)| offset)
+        .unwrap_or_else(|| server.len().min(formatter.len()));
+    if offset == server.len() && offset == formatter.len() {
+        return None;
+    }
+    Some(TemplateDivergence {
+        offset,
+        server: context_around(server, offset),
+        formatter: context_around(formatter, offset),
+    })
+}
+
+fn context_around(text: &str, offset: usize) -> String {
+    let start = text[..offset]
+        .char_indices()
+        .rev()
+        .nth(DIVERGENCE_CONTEXT - 1)
+        .map(|(index, _)| index)
+        .unwrap_or(0);
+    text[start..].chars().take(2 * DIVERGENCE_CONTEXT).collect()
+}
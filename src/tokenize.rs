@@ -0,0 +1,36 @@
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{LlamaLink, RequestError};
+
+#[derive(Deserialize, Debug)]
+struct TokenizeResponse {
+    tokens: Vec<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DetokenizeResponse {
+    content: String,
+}
+
+impl LlamaLink {
+    /// Tokenizes `content` with the model's tokenizer through `POST /tokenize`. Special tokens in
+    /// `content`, e.g. `<|eot_id|>`, are parsed as special tokens and no BOS token is added.
+    pub async fn tokenize(&self, content: &str) -> Result<Vec<u32>, RequestError> {
+        let response: TokenizeResponse = self
+            .post_json(
+                "tokenize",
+                &json!({ "content": content, "add_special": false, "parse_special": true }),
+            )
+            .await?;
+        Ok(response.tokens)
+    }
+
+    /// Converts tokens back to text through `POST /detokenize`.
+    pub async fn detokenize(&self, tokens: &[u32]) -> Result<String, RequestError> {
+        let response: DetokenizeResponse = self
+            .post_json("detokenize", &json!({ "tokens": tokens }))
+            .await?;
+        Ok(response.content)
+    }
+}
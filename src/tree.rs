@@ -0,0 +1,306 @@
+use std::{fmt, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{BranchError, Conversation, LlamaLink, Message, PromptFormatter};
+
+/// The id of a message in a [`ConversationTree`]. Ids are never reused.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct NodeId(usize);
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A message in a [`ConversationTree`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub id: NodeId,
+    /// `None` for the first message of a branch.
+    pub parent: Option<NodeId>,
+    pub message: Message,
+}
+
+/// A conversation whose history forks, e.g. when a reply is regenerated or an earlier message is
+/// edited. Every message is a node pointing to its parent, so each node is the end of one branch,
+/// linearized with [`ConversationTree::messages`]. Nothing is ever removed, so every branch can be
+/// revisited with [`ConversationTree::set_active`].
+///
+/// Requests go through the wrapped [`Conversation`], so its system prompt, formatter, overrides,
+/// context management and slot affinity apply to every branch. Its history is replaced with the
+/// branch a request continues from.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ConversationTree {
+    nodes: Vec<Node>,
+    /// The node new messages are appended to, `None` to start a new branch.
+    active: Option<NodeId>,
+    conversation: Conversation,
+    /// The node the history of `conversation` ends with, `None` if it is empty.
+    synced: Option<NodeId>,
+}
+
+impl From<Conversation> for ConversationTree {
+    /// A tree with the history of `conversation` as its only branch.
+    fn from(conversation: Conversation) -> Self {
+        let mut tree = Self {
+            nodes: Vec::new(),
+            active: None,
+            conversation,
+            synced: None,
+        };
+        for message in tree.conversation.history.clone() {
+            tree.add(tree.active, message);
+        }
+        tree.synced = tree.active;
+        tree
+    }
+}
+
+impl ConversationTree {
+    pub fn conversation(&self) -> &Conversation {
+        &self.conversation
+    }
+
+    /// The conversation's settings, e.g. the system prompt. Its history is replaced before the
+    /// next request, edit the tree instead.
+    pub fn conversation_mut(&mut self) -> &mut Conversation {
+        &mut self.conversation
+    }
+
+    pub fn node(&self, id: NodeId) -> Option<&Node> {
+        self.nodes.get(id.0)
+    }
+
+    /// Every node, parents before their children.
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn children(&self, id: NodeId) -> Vec<NodeId> {
+        self.child_nodes(Some(id))
+    }
+
+    /// The first messages of the branches.
+    pub fn roots(&self) -> Vec<NodeId> {
+        self.child_nodes(None)
+    }
+
+    /// The alternatives to `id`, including itself, in the order they were created. E.g. the
+    /// regenerated versions of a reply.
+    pub fn siblings(&self, id: NodeId) -> Vec<NodeId> {
+        match self.node(id) {
+            Some(node) => self.child_nodes(node.parent),
+            None => Vec::new(),
+        }
+    }
+
+    /// The nodes without children, each the end of a complete branch.
+    pub fn leaves(&self) -> Vec<NodeId> {
+        self.nodes
+            .iter()
+            .filter(|node| !self.nodes.iter().any(|child| child.parent == Some(node.id)))
+            .map(|node| node.id)
+            .collect()
+    }
+
+    /// The node the next message is appended to. `None` if the tree is empty or a new branch
+    /// is started.
+    pub fn active(&self) -> Option<NodeId> {
+        self.active
+    }
+
+    /// Continues the conversation from `id`, or starts a new branch for `None`. Switching to a node
+    /// with children forks the conversation at the next message.
+    pub fn set_active(&mut self, id: Option<NodeId>) -> Result<(), BranchError> {
+        if let Some(id) = id {
+            self.check(id)?;
+        }
+        self.active = id;
+        Ok(())
+    }
+
+    /// The ids of the branch ending with `id`, oldest first.
+    pub fn path(&self, id: NodeId) -> Vec<NodeId> {
+        let mut path = Vec::new();
+        let mut next = self.node(id);
+        while let Some(node) = next {
+            path.push(node.id);
+            next = node.parent.and_then(|parent| self.node(parent));
+        }
+        path.reverse();
+        path
+    }
+
+    /// The messages of the branch ending with `id`, e.g. to format it with a [`PromptFormatter`].
+    pub fn messages(&self, id: NodeId) -> Vec<Message> {
+        self.path(id)
+            .into_iter()
+            .map(|id| self.nodes[id.0].message.clone())
+            .collect()
+    }
+
+    /// The messages of the active branch.
+    pub fn active_messages(&self) -> Vec<Message> {
+        self.active.map(|id| self.messages(id)).unwrap_or_default()
+    }
+
+    /// Appends `message` to `parent` without sending anything, and makes it active.
+    pub fn push(
+        &mut self,
+        parent: Option<NodeId>,
+        message: Message,
+    ) -> Result<NodeId, BranchError> {
+        if let Some(parent) = parent {
+            self.check(parent)?;
+        }
+        Ok(self.add(parent, message))
+    }
+
+    /// Sends `message` as the next message of the active branch. The message and the reply become
+    /// the end of the active branch.
+    pub async fn send(
+        &mut self,
+        link: &LlamaLink,
+        message: impl Into<Message>,
+    ) -> Result<String, BranchError> {
+        self.send_after(link, self.active, message.into()).await
+    }
+
+    /// Generates another reply to the user message `reply` answered. The new reply is added as a
+    /// sibling of `reply` and becomes active.
+    pub async fn regenerate(
+        &mut self,
+        link: &LlamaLink,
+        reply: NodeId,
+    ) -> Result<String, BranchError> {
+        let node = self.check(reply)?;
+        let message = match node.parent.and_then(|parent| self.node(parent)) {
+            Some(message) if !node.message.is_user() && message.message.is_user() => {
+                message.clone()
+            }
+            _ => return Err(BranchError::NotAReply { id: reply }),
+        };
+        let reply = self.reply(link, message.parent, message.message).await?;
+        self.add_reply(message.id, reply.clone());
+        Ok(reply)
+    }
+
+    /// Replaces the user message `message` with `text`, text or a [`Message::UserWithImages`], in a
+    /// new branch forked at its parent, and sends it. The edited message and the reply become
+    /// active.
+    pub async fn edit(
+        &mut self,
+        link: &LlamaLink,
+        message: NodeId,
+        text: impl Into<Message>,
+    ) -> Result<String, BranchError> {
+        let node = self.check(message)?;
+        if !node.message.is_user() {
+            return Err(BranchError::NotAUserMessage { id: message });
+        }
+        self.send_after(link, node.parent, text.into()).await
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    /// See [`Conversation::from_json`]. Fails if the nodes are not in the order of their ids, a
+    /// node's parent does not precede it, or the active node does not exist.
+    pub fn from_json(json: &str, formatter: PromptFormatter) -> serde_json::Result<Self> {
+        let mut tree: Self = serde_json::from_str(json)?;
+        tree.validate().map_err(serde::de::Error::custom)?;
+        tree.conversation.formatter = formatter;
+        Ok(tree)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_json()?)
+    }
+
+    pub fn load(path: impl AsRef<Path>, formatter: PromptFormatter) -> std::io::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(Self::from_json(&json, formatter)?)
+    }
+
+    /// Checks the invariants the navigation relies on, which hold for every tree built through
+    /// its methods but not necessarily for loaded json.
+    fn validate(&self) -> Result<(), String> {
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.id.0 != index {
+                return Err(format!("Node `{}` is stored at index {index}", node.id));
+            }
+            if node.parent.is_some_and(|parent| parent.0 >= index) {
+                return Err(format!("Node `{}` does not follow its parent", node.id));
+            }
+        }
+        for (field, id) in [("active", self.active), ("synced", self.synced)] {
+            if id.is_some_and(|id| self.node(id).is_none()) {
+                return Err(format!("The `{field}` node does not exist"));
+            }
+        }
+        Ok(())
+    }
+
+    fn check(&self, id: NodeId) -> Result<&Node, BranchError> {
+        self.node(id).ok_or(BranchError::UnknownNode { id })
+    }
+
+    fn child_nodes(&self, parent: Option<NodeId>) -> Vec<NodeId> {
+        self.nodes
+            .iter()
+            .filter(|node| node.parent == parent)
+            .map(|node| node.id)
+            .collect()
+    }
+
+    fn add(&mut self, parent: Option<NodeId>, message: Message) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(Node {
+            id,
+            parent,
+            message,
+        });
+        self.active = Some(id);
+        id
+    }
+
+    async fn send_after(
+        &mut self,
+        link: &LlamaLink,
+        parent: Option<NodeId>,
+        message: Message,
+    ) -> Result<String, BranchError> {
+        let reply = self.reply(link, parent, message.clone()).await?;
+        let message = self.add(parent, message);
+        self.add_reply(message, reply.clone());
+        Ok(reply)
+    }
+
+    fn add_reply(&mut self, message: NodeId, reply: String) {
+        let reply = self.add(Some(message), Message::Assistant(reply));
+        self.synced = Some(reply);
+    }
+
+    /// Has the conversation reply to `message` following the branch ending with `parent`.
+    async fn reply(
+        &mut self,
+        link: &LlamaLink,
+        parent: Option<NodeId>,
+        message: Message,
+    ) -> Result<String, BranchError> {
+        // Continuing the last branch keeps what the conversation dropped or summarized to fit the context
+        if self.synced != parent {
+            self.conversation.clear();
+            for message in parent.map(|id| self.messages(id)).unwrap_or_default() {
+                self.conversation.push(message);
+            }
+            self.synced = parent;
+        }
+        // A failed turn is not appended, so the history still ends with `parent`
+        Ok(self.conversation.send(link, message).await?)
+    }
+}
@@ -16,6 +16,7 @@ mod normal {
     }
 
     #[tokio::test]
+    #[allow(clippy::useless_vec)]
     async fn completion_stream() {
         let link = LlamaLink::new("http://127.0.0.1:3756", Config::builder().build());
         let mut response_stream = link.create_formatted_completion_stream(
@@ -37,6 +38,188 @@ mod normal {
         }
         assert!(count > 0);
     }
+
+    #[tokio::test]
+    async fn completion_probabilities() {
+        let link = LlamaLink::new(
+            "http://127.0.0.1:3756",
+            Config::builder().n_probs(3).n_predict(8).build(),
+        );
+
+        let completion = link
+            .create_completion_full("In one sentence, tell me a joke.".to_owned())
+            .await
+            .unwrap();
+        assert!(!completion.probabilities.is_empty());
+        assert!(completion.probabilities[0].top.len() <= 3);
+        assert!(completion.log_likelihood() <= 0.0);
+
+        let mut stream =
+            link.create_completion_stream_full("In one sentence, tell me a joke.".to_owned());
+        let mut probabilities = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            probabilities.extend(chunk.unwrap().probabilities);
+        }
+        assert!(!probabilities.is_empty());
+    }
+
+    #[tokio::test]
+    async fn classify() {
+        let link = LlamaLink::new("http://127.0.0.1:3756", Config::builder().build());
+        let labels = ["positive", "negative", "neutral"];
+
+        let classification = link
+            .classify_with_format(
+                "Classify the sentiment of the user's message.",
+                &[Message::User("I love this library!".to_owned())],
+                &PromptFormatter::default(),
+                &labels,
+            )
+            .await
+            .unwrap();
+        assert!(labels.contains(&classification.label.as_str()));
+        assert_eq!(classification.distribution.len(), labels.len());
+        let total: f64 = classification.distribution.iter().map(|(_, p)| p).sum();
+        assert!((total - 1.0).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn logit_bias() {
+        let link = LlamaLink::new("http://127.0.0.1:3756", Config::builder().build());
+
+        let tokens = link.tokenize("Hello world").await.unwrap();
+        assert_eq!(link.detokenize(&tokens).await.unwrap(), "Hello world");
+
+        let resolved = link
+            .resolve_logit_bias(&LogitBias::new().ban_string("Hello world"))
+            .await
+            .unwrap();
+        assert_eq!(resolved.entries().len(), tokens.len());
+
+        let special = link
+            .special_tokens_logit_bias(&PromptFormatter::default())
+            .await
+            .unwrap();
+        let response = link
+            .with_overrides(
+                Config::builder()
+                    .logit_bias(special.extend(resolved))
+                    .build(),
+            )
+            .create_completion("In one sentence, tell me a joke.".to_owned())
+            .await
+            .unwrap();
+        assert!(!response.contains("<|start_header_id|>"));
+    }
+
+    #[tokio::test]
+    async fn best_of_n() {
+        let link = LlamaLink::new(
+            "http://127.0.0.1:3756",
+            Config::builder().temperature(0.8).n_predict(16).build(),
+        );
+
+        let best_of = link
+            .best_of_n(
+                "In one word, what color is the sky?".to_owned(),
+                3,
+                Selection::MajorityVote,
+            )
+            .await
+            .unwrap();
+        assert_eq!(best_of.candidates.len(), 3);
+        assert_eq!(best_of.candidates[1].seed, 1);
+
+        let shortest = |candidate: &Candidate| -(candidate.completion.content.len() as f64);
+        let best_of = link
+            .best_of_n(
+                "In one word, what color is the sky?".to_owned(),
+                3,
+                Selection::Score(&shortest),
+            )
+            .await
+            .unwrap();
+        let min_len = best_of
+            .candidates
+            .iter()
+            .map(|candidate| candidate.completion.content.len())
+            .min()
+            .unwrap();
+        assert_eq!(best_of.selected().completion.content.len(), min_len);
+    }
+
+    #[tokio::test]
+    async fn chat_completion() {
+        let link = LlamaLink::new("http://127.0.0.1:3756", Config::builder().build());
+        let messages = [Message::User("In one sentence, tell me a joke.".to_owned())];
+
+        let completion = link
+            .create_chat_completion_full("You are a helpful assistant.", &messages)
+            .await
+            .unwrap();
+        assert!(!completion.content.unwrap().is_empty());
+        assert!(completion.finish_reason.is_some());
+
+        let mut response_stream = link.create_chat_completion_stream("", &messages);
+        let mut response = String::new();
+        while let Some(content) = response_stream.next().await {
+            response.push_str(&content.unwrap());
+        }
+        assert!(!response.is_empty());
+    }
+
+    #[tokio::test]
+    async fn apply_template() {
+        let link = LlamaLink::new("http://127.0.0.1:3756", Config::builder().build());
+        let messages = [Message::User("In one sentence, tell me a joke.".to_owned())];
+
+        let prompt = link.apply_template("", &messages).await.unwrap();
+        assert!(prompt.contains("In one sentence, tell me a joke."));
+
+        let broken = PromptFormatter::new(|_, _| "not a template".to_owned());
+        let divergence = link
+            .compare_formatter("", &messages, &broken)
+            .await
+            .unwrap();
+        assert!(divergence.is_some());
+    }
+
+    #[tokio::test]
+    async fn slots() {
+        let link = LlamaLink::new("http://127.0.0.1:3756", Config::builder().build());
+
+        let slots = link.slots().await.unwrap();
+        assert!(!slots.is_empty());
+
+        let pinned = link.with_overrides(Config::builder().id_slot(slots[0].id).build());
+        let response = pinned
+            .create_completion("In one sentence, tell me a joke.".to_owned())
+            .await
+            .unwrap();
+        assert!(!response.is_empty());
+
+        let erased = link.erase_slot(slots[0].id).await.unwrap();
+        assert_eq!(erased.id_slot, slots[0].id);
+    }
+
+    #[tokio::test]
+    async fn lora_adapters() {
+        let link = LlamaLink::new("http://127.0.0.1:3756", Config::builder().build());
+
+        let adapters = link.lora_adapters().await.unwrap();
+        let scales: Vec<LoraScale> = adapters
+            .iter()
+            .map(|adapter| LoraScale::new(adapter.id, 0.5))
+            .collect();
+        link.set_lora_adapters(&scales).await.unwrap();
+
+        let response = link
+            .with_overrides(Config::builder().lora(scales).build())
+            .create_completion("In one sentence, tell me a joke.".to_owned())
+            .await
+            .unwrap();
+        assert!(!response.is_empty());
+    }
 }
 
 #[cfg(test)]
@@ -106,4 +289,2319 @@ mod toolbox {
             Err(error) => panic!("{}", error),
         }
     }
+
+    #[tokio::test]
+    async fn function_call_chat() {
+        let tool = MyTool::new();
+        let mut toolbox: ToolBox<Box<dyn Any>, Infallible> = ToolBox::new();
+        toolbox.add_tool(tool).unwrap();
+
+        let link = LlamaLink::new("http://127.0.0.1:3756", Config::builder().build());
+        let result = link
+            .call_function_chat_full(
+                "You are a helpful assistant.",
+                &[Message::User("call greet".to_owned())],
+                &toolbox,
+            )
+            .await
+            .unwrap();
+        assert!(result.raw_input.contains("function_name"));
+        match result.output_result {
+            Ok(call_result) => match call_result.downcast::<String>() {
+                Ok(message) => assert!(message.deref().starts_with("This is the greeting")),
+                Err(_) => panic!("Not the corect type"),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn chat_tool_call() {
+        let mut toolbox: ToolBox<Box<dyn Any>, Infallible> = ToolBox::new();
+        toolbox.add_tool(MyTool::new()).unwrap();
+        let server = MockServer::start().await;
+        server.enqueue(
+            "/v1/chat/completions",
+            MockResponse::json(
+                200,
+                serde_json::json!({
+                    "choices": [{
+                        "message": {
+                            "content": null,
+                            "tool_calls": [{
+                                "id": "call_0",
+                                "type": "function",
+                                "function": { "name": "greet", "arguments": "{\"greeting\": \"hi\"}" },
+                            }],
+                        },
+                        "finish_reason": "tool_calls",
+                    }],
+                }),
+            ),
+        );
+        let link = server.link();
+
+        let result = link
+            .call_function_chat_full(
+                "Be brief.",
+                &[Message::User("call greet".to_owned())],
+                &toolbox,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&result.raw_input).unwrap(),
+            serde_json::json!({ "function_name": "greet", "parameters": { "greeting": "hi" } })
+        );
+        let Ok(output) = result.output_result;
+        assert_eq!(
+            *output.downcast::<String>().unwrap(),
+            "This is the greeting `hi`"
+        );
+
+        let request = &server.requests_to("/v1/chat/completions")[0].body;
+        assert_eq!(request["tool_choice"], "required");
+        let tools = request["tools"].as_array().unwrap();
+        assert_eq!(tools.len(), 2);
+        let greet = tools
+            .iter()
+            .find(|tool| tool["function"]["name"] == "greet")
+            .unwrap();
+        assert_eq!(greet["type"], "function");
+        assert_eq!(
+            greet["function"]["parameters"]["properties"]["greeting"]["type"],
+            "string"
+        );
+    }
+
+    #[tokio::test]
+    async fn chat_stream_tool_call() {
+        let mut toolbox: ToolBox<Box<dyn Any>, Infallible> = ToolBox::new();
+        toolbox.add_tool(MyTool::new()).unwrap();
+        let server = MockServer::start().await;
+        let delta = |delta: serde_json::Value| serde_json::json!({ "choices": [{ "delta": delta, "finish_reason": null }] });
+        server.enqueue(
+            "/v1/chat/completions",
+            MockResponse::events([
+                delta(serde_json::json!({ "content": "Calling " })),
+                delta(serde_json::json!({ "tool_calls": [{
+                    "index": 0, "id": "call_0", "type": "function",
+                    "function": { "name": "greet", "arguments": "{\"greet" },
+                }] })),
+                delta(serde_json::json!({ "tool_calls": [{
+                    "index": 0, "function": { "arguments": "ing\": \"hi\"}" },
+                }] })),
+                serde_json::json!({
+                    "choices": [{ "delta": {}, "finish_reason": "tool_calls" }],
+                    "usage": { "prompt_tokens": 12, "completion_tokens": 8, "total_tokens": 20 },
+                    "timings": { "cache_n": 4 },
+                }),
+            ]),
+        );
+        let link = server.link();
+
+        let mut stream = link.create_chat_completion_stream_with_tools(
+            "Be brief.",
+            &[Message::User("call greet".to_owned())],
+            &toolbox,
+        );
+        let mut chunks = Vec::new();
+        while let Some(chunk) = tokio_stream::StreamExt::next(&mut stream).await {
+            chunks.push(chunk.unwrap());
+        }
+        let content: String = chunks.iter().map(|chunk| chunk.content.as_str()).collect();
+        assert_eq!(content, "Calling ");
+        let deltas: Vec<&ToolCallDelta> =
+            chunks.iter().flat_map(|chunk| &chunk.tool_calls).collect();
+        assert!(deltas.iter().all(|delta| delta.index == 0));
+        assert_eq!(deltas[0].id.as_deref(), Some("call_0"));
+        assert_eq!(deltas[0].function.name.as_deref(), Some("greet"));
+        let arguments: String = deltas
+            .iter()
+            .filter_map(|delta| delta.function.arguments.as_deref())
+            .collect();
+        assert_eq!(arguments, r#"{"greeting": "hi"}"#);
+        let last = chunks.last().unwrap();
+        assert_eq!(last.finish_reason, Some(FinishReason::ToolCalls));
+        assert_eq!(last.usage.unwrap().total_tokens, 20);
+        assert_eq!(last.tokens_cached, Some(4));
+
+        let request = &server.requests_to("/v1/chat/completions")[0].body;
+        assert_eq!(request["stream"], true);
+        assert_eq!(request["stream_options"]["include_usage"], true);
+        assert_eq!(request["tools"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn best_of_skips_invalid_tool_calls() {
+        let mut toolbox: ToolBox<Box<dyn Any>, Infallible> = ToolBox::new();
+        toolbox.add_tool(MyTool::new()).unwrap();
+        let server = MockServer::start().await;
+        let candidate = |content: &str, logprob: f64| {
+            MockResponse::json(
+                200,
+                serde_json::json!({
+                    "content": content,
+                    "stop": true,
+                    "completion_probabilities": [{ "token": content, "logprob": logprob, "top_logprobs": [] }],
+                }),
+            )
+        };
+        let valid = r#"{"function_name": "greet", "parameters": {"greeting": "hi"}}"#;
+        let link = server.link();
+
+        // The more confident candidates are truncated or miss the function name
+        for selection in [Selection::HighestMeanLogprob, Selection::Score(&|_| 1.0)] {
+            server.enqueue("/completion", candidate(r#"{"function_name": "gre"#, -0.1));
+            server.enqueue("/completion", candidate(r#"{"greeting": "hi"}"#, -0.2));
+            server.enqueue("/completion", candidate(valid, -0.5));
+            let result = link
+                .call_function_best_of_n("call greet".to_owned(), &toolbox, 3, selection)
+                .await
+                .unwrap();
+            assert_eq!(result.context.raw_input, valid);
+            assert_eq!(result.best_of.selected().completion.content, valid);
+            let Ok(output) = result.context.output_result;
+            assert!(output.downcast::<String>().is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn best_of_no_candidates() {
+        let mut toolbox: ToolBox<Box<dyn Any>, Infallible> = ToolBox::new();
+        toolbox.add_tool(MyTool::new()).unwrap();
+        let server = MockServer::start().await;
+        let link = server.link();
+
+        let error = link
+            .best_of_n("Hi".to_owned(), 0, Selection::MajorityVote)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, CompletionError::InvalidRequest { .. }));
+        let error = link
+            .call_function_best_of_n(
+                "call greet".to_owned(),
+                &toolbox,
+                0,
+                Selection::MajorityVote,
+            )
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, FunctionCallError::InvalidRequest { .. }));
+        assert!(server.requests().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod probs {
+    use llama_link::*;
+
+    fn token(logprob: f64, top: &[f64]) -> TokenProbability {
+        TokenProbability {
+            id: None,
+            token: String::new(),
+            logprob,
+            top: top
+                .iter()
+                .map(|prob| TokenCandidate {
+                    id: None,
+                    token: String::new(),
+                    logprob: prob.ln(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn likelihood_and_entropy() {
+        let tokens = [token(0.5f64.ln(), &[0.5, 0.5]), token(0.0, &[1.0])];
+        assert!((log_likelihood(&tokens) - 0.5f64.ln()).abs() < 1e-9);
+        assert!((mean_logprob(&tokens).unwrap() - 0.5f64.ln() / 2.0).abs() < 1e-9);
+        assert_eq!(mean_logprob(&[]), None);
+        assert!((tokens[0].entropy() - 2f64.ln()).abs() < 1e-9);
+        assert_eq!(tokens[1].entropy(), 0.0);
+    }
+}
+
+#[cfg(test)]
+mod logit_bias {
+    use llama_link::*;
+
+    #[test]
+    fn serialize_logit_bias() {
+        let logit_bias = LogitBias::new()
+            .bias_token(15043, 1.5)
+            .ban_token(2)
+            .ban_string("Hello")
+            .boost_string("world", -0.5);
+        assert_eq!(
+            serde_json::to_value(&logit_bias).unwrap(),
+            serde_json::json!([[15043, 1.5], [2, false], ["Hello", false], ["world", -0.5]])
+        );
+    }
+}
+
+#[cfg(test)]
+mod metrics {
+    use llama_link::*;
+
+    #[test]
+    fn parse_metrics() {
+        let text = r#"# HELP llamacpp:prompt_tokens_total Number of prompt tokens processed.
+# TYPE llamacpp:prompt_tokens_total counter
+llamacpp:prompt_tokens_total 1024
+# TYPE llamacpp:kv_cache_usage_ratio gauge
+llamacpp:kv_cache_usage_ratio 0.25
+llamacpp:requests_deferred 3
+custom_metric{slot="0",note="a \"quoted\" value"} +Inf 1700000000000
+"#;
+        let metrics = ServerMetrics::parse(text).unwrap();
+        assert_eq!(metrics.prompt_tokens_total, Some(1024.0));
+        assert_eq!(metrics.kv_cache_usage_ratio, Some(0.25));
+        assert_eq!(metrics.requests_deferred, Some(3.0));
+        assert_eq!(metrics.requests_processing, None);
+
+        let custom = metrics.sample("custom_metric").unwrap();
+        assert_eq!(custom.labels["slot"], "0");
+        assert_eq!(custom.labels["note"], "a \"quoted\" value");
+        assert_eq!(custom.value, f64::INFINITY);
+        assert_eq!(custom.timestamp, Some(1700000000000));
+
+        assert!(ServerMetrics::parse("broken{slot=\"0\" 1").is_err());
+    }
+}
+
+#[cfg(test)]
+mod builder {
+    use std::time::Duration;
+
+    use llama_link::*;
+
+    #[test]
+    fn normalizes_url() {
+        let link = LlamaLink::builder()
+            .url("http://127.0.0.1:3756")
+            .build()
+            .unwrap();
+        assert_eq!(link.url().unwrap().as_str(), "http://127.0.0.1:3756/");
+
+        let link = LlamaLink::builder()
+            .url("https://proxy.example.com/llama//")
+            .user_agent("my-service/1.0")
+            .api_key(ApiKey::provider(|| "rotating-key".to_owned()))
+            .request_timeout(Duration::from_secs(30))
+            .build()
+            .unwrap();
+        assert_eq!(
+            link.url().unwrap().as_str(),
+            "https://proxy.example.com/llama/"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_configuration() {
+        assert!(LlamaLink::builder().url("127.0.0.1:3756").build().is_err());
+        assert!(LlamaLink::builder()
+            .url("http://127.0.0.1:3756?model=a")
+            .build()
+            .is_err());
+        assert!(matches!(
+            LlamaLink::builder()
+                .url("http://127.0.0.1:3756")
+                .client(reqwest::Client::new())
+                .connect_timeout(Duration::from_secs(1))
+                .build(),
+            Err(BuildError::ConnectTimeoutWithClient)
+        ));
+    }
+
+    #[tokio::test]
+    async fn new_does_not_validate() {
+        let link = LlamaLink::new("127.0.0.1:3756/", Config::builder().build());
+        assert_eq!(link.url().unwrap().as_str(), "http://127.0.0.1:3756/");
+        let link = OllamaLink::new("localhost:11434", "llama3.2", Config::builder().build());
+        assert_eq!(link.url().unwrap().as_str(), "http://localhost:11434/");
+
+        let link = LlamaLink::new("not a url", Config::builder().build());
+        assert_eq!(link.url(), None);
+        let error = link.create_completion("Hi".to_owned()).await.unwrap_err();
+        assert!(matches!(error, CompletionError::InvalidRequest { .. }));
+        assert!(!error.is_retryable());
+        let link = OllamaLink::new("not a url", "llama3.2", Config::builder().build());
+        let error = link.complete("Hi".to_owned()).await.unwrap_err();
+        assert!(matches!(error, CompletionError::InvalidRequest { .. }));
+
+        let link = LlamaLink::builder()
+            .url("http://127.0.0.1:3756")
+            .api_key("invalid\nkey")
+            .build()
+            .unwrap();
+        let error = link.health().await.unwrap_err();
+        assert!(matches!(error, RequestError::InvalidRequest { .. }));
+    }
+}
+
+#[cfg(test)]
+mod retry {
+    use std::time::Duration;
+
+    use llama_link::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Serves each of `responses` to one connection, in order.
+    pub(crate) async fn serve(responses: Vec<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buffer = [0; 4096];
+                let _ = socket.read(&mut buffer).await.unwrap();
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn retries_unavailable() {
+        let url = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 0\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 16\r\nConnection: close\r\n\r\n{\"tokens\":[1,2]}",
+        ])
+        .await;
+        let link = LlamaLink::builder()
+            .url(url)
+            .retry_policy(
+                RetryPolicy::builder()
+                    .initial_backoff(Duration::from_millis(1))
+                    .build(),
+            )
+            .build()
+            .unwrap();
+
+        assert_eq!(link.tokenize("hi").await.unwrap(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_by_default() {
+        let url = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ])
+        .await;
+        let link = LlamaLink::new(&url, Config::builder().build());
+
+        assert!(link.tokenize("hi").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn caps_retry_after() {
+        let url = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 3600\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 16\r\nConnection: close\r\n\r\n{\"tokens\":[1,2]}",
+        ])
+        .await;
+        let link = LlamaLink::builder()
+            .url(url)
+            .retry_policy(
+                RetryPolicy::builder()
+                    .max_backoff(Duration::from_millis(10))
+                    .build(),
+            )
+            .build()
+            .unwrap();
+
+        let tokens = tokio::time::timeout(Duration::from_secs(5), link.tokenize("hi"))
+            .await
+            .expect("The Retry-After delay is capped at max_backoff");
+        assert_eq!(tokens.unwrap(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn retries_timeouts() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            // The first attempt never gets a response
+            let (_stalled, _) = listener.accept().await.unwrap();
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 4096];
+            let _ = socket.read(&mut buffer).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 16\r\nConnection: close\r\n\r\n{\"tokens\":[1,2]}")
+                .await
+                .unwrap();
+            std::future::pending::<()>().await;
+        });
+        let link = LlamaLink::builder()
+            .url(url)
+            .request_timeout(Duration::from_millis(200))
+            .retry_policy(
+                RetryPolicy::builder()
+                    .initial_backoff(Duration::from_millis(1))
+                    .build(),
+            )
+            .build()
+            .unwrap();
+
+        assert_eq!(link.tokenize("hi").await.unwrap(), vec![1, 2]);
+    }
+}
+
+#[cfg(test)]
+mod pool {
+    use llama_link::*;
+
+    use crate::retry::serve;
+
+    const UNAVAILABLE: &str =
+        "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    const COMPLETION: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 16\r\nConnection: close\r\n\r\n{\"content\":\"hi\"}";
+
+    #[tokio::test]
+    async fn fails_over_to_healthy_server() {
+        let down = serve(vec![UNAVAILABLE]).await;
+        let up = serve(vec![COMPLETION]).await;
+        let pool = LlamaPool::from_urls(&[&down, &up], Config::builder().build()).unwrap();
+
+        let completion = pool.create_completion("hello".to_owned()).await.unwrap();
+        assert_eq!(completion, "hi");
+        assert_eq!(pool.healthy(), vec![false, true]);
+        assert_eq!(pool.in_flight(), vec![0, 0]);
+    }
+
+    #[tokio::test]
+    async fn round_robin_alternates() {
+        let first = serve(vec![COMPLETION]).await;
+        let second = serve(vec![COMPLETION]).await;
+        let pool = LlamaPool::from_urls(&[&first, &second], Config::builder().build()).unwrap();
+
+        for _ in 0..2 {
+            pool.create_completion("hello".to_owned()).await.unwrap();
+        }
+        assert_eq!(pool.healthy(), vec![true, true]);
+    }
+
+    #[tokio::test]
+    async fn queue_timeout_keeps_server_healthy() {
+        let busy = MockServer::start().await;
+        busy.set_latency(std::time::Duration::from_millis(500));
+        let idle = MockServer::start().await;
+        let link = LlamaLink::builder()
+            .url(busy.url())
+            .concurrency_limit(ConcurrencyLimit::Fixed(1))
+            .queue_timeout(std::time::Duration::from_millis(20))
+            .build()
+            .unwrap();
+        let pool = LlamaPool::builder()
+            .links(vec![link.clone(), idle.link()])
+            .build();
+
+        let hog = tokio::spawn(async move { link.create_completion("hello".to_owned()).await });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        pool.create_completion("hello".to_owned()).await.unwrap();
+        assert_eq!(pool.healthy(), vec![true, true]);
+        assert_eq!(idle.requests_to("/completion").len(), 1);
+        hog.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn free_slots_ranks_unknown_above_full() {
+        let full = MockServer::start().await;
+        full.enqueue(
+            "/slots",
+            MockResponse::json(200, serde_json::json!([{"id": 0, "is_processing": true}])),
+        );
+        // Neither `/slots` nor `slots_idle` in `/health`
+        let unknown = MockServer::start().await;
+        let pool = LlamaPool::builder()
+            .links(vec![full.link(), unknown.link()])
+            .routing(Routing::FreeSlots)
+            .free_slots_ttl(std::time::Duration::from_secs(60))
+            .build();
+
+        for _ in 0..2 {
+            pool.create_completion("hello".to_owned()).await.unwrap();
+        }
+        assert_eq!(full.requests_to("/completion").len(), 0);
+        assert_eq!(unknown.requests_to("/completion").len(), 2);
+        // The second request reuses the reports of the first
+        assert_eq!(full.requests_to("/slots").len(), 1);
+        assert_eq!(unknown.requests_to("/slots").len(), 1);
+        assert_eq!(unknown.requests_to("/health").len(), 1);
+    }
+}
+
+#[cfg(test)]
+mod limiter {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use llama_link::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    const COMPLETION: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 16\r\nConnection: close\r\n\r\n{\"content\":\"hi\"}";
+
+    /// Never answers the first connection, and answers every later one with a completion.
+    async fn serve_after_stall() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stalled, _) = listener.accept().await.unwrap();
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buffer = [0; 4096];
+                let _ = socket.read(&mut buffer).await.unwrap();
+                socket.write_all(COMPLETION.as_bytes()).await.unwrap();
+                let _ = &stalled;
+            }
+        });
+        url
+    }
+
+    async fn wait_until(condition: impl Fn() -> bool) {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn queue_timeout() {
+        let url = serve_after_stall().await;
+        let link = LlamaLink::builder()
+            .url(url)
+            .concurrency_limit(ConcurrencyLimit::Fixed(1))
+            .queue_timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+
+        let stalled = tokio::spawn({
+            let link = link.clone();
+            async move { link.create_completion("stall".to_owned()).await }
+        });
+        wait_until(|| link.active_requests() == 1).await;
+
+        let error = link
+            .create_completion("hello".to_owned())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, CompletionError::QueueTimeout));
+        assert_eq!(link.queue_depth(), 0);
+
+        stalled.abort();
+        let _ = stalled.await;
+        assert_eq!(link.active_requests(), 0);
+        assert_eq!(
+            link.create_completion("hello".to_owned()).await.unwrap(),
+            "hi"
+        );
+    }
+
+    #[tokio::test]
+    async fn higher_priority_goes_first() {
+        let url = serve_after_stall().await;
+        let link = LlamaLink::builder()
+            .url(url)
+            .concurrency_limit(ConcurrencyLimit::Fixed(1))
+            .build()
+            .unwrap();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let stalled = tokio::spawn({
+            let link = link.clone();
+            async move { link.create_completion("stall".to_owned()).await }
+        });
+        wait_until(|| link.active_requests() == 1).await;
+        let mut queued = Vec::new();
+        for priority in [0, 10] {
+            let prioritized = link.with_priority(priority);
+            let order = order.clone();
+            queued.push(tokio::spawn(async move {
+                prioritized
+                    .create_completion("hello".to_owned())
+                    .await
+                    .unwrap();
+                order.lock().unwrap().push(priority);
+            }));
+            wait_until(|| link.queue_depth() == queued.len()).await;
+        }
+
+        stalled.abort();
+        for task in queued {
+            task.await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), vec![10, 0]);
+    }
+
+    #[tokio::test]
+    async fn server_slots_without_props() {
+        let server = MockServer::start().await;
+        server.enqueue(
+            "/props",
+            MockResponse::error(404, "not_found_error", "File Not Found"),
+        );
+        let link = LlamaLink::builder()
+            .url(server.url())
+            .concurrency_limit(ConcurrencyLimit::ServerSlots)
+            .build()
+            .unwrap();
+
+        for _ in 0..2 {
+            link.create_completion("Hi".to_owned()).await.unwrap();
+        }
+        assert_eq!(link.concurrency_limit(), None);
+        assert_eq!(server.requests_to("/props").len(), 1);
+
+        let server = MockServer::start().await;
+        server.set_total_slots(2);
+        let link = LlamaLink::builder()
+            .url(server.url())
+            .concurrency_limit(ConcurrencyLimit::ServerSlots)
+            .build()
+            .unwrap();
+        link.create_completion("Hi".to_owned()).await.unwrap();
+        assert_eq!(link.concurrency_limit(), Some(2));
+    }
+}
+
+#[cfg(test)]
+mod errors {
+    use llama_link::*;
+    use reqwest::StatusCode;
+    use tokio_stream::StreamExt;
+
+    use crate::retry::serve;
+
+    fn json_response(status: &str, body: &str) -> &'static str {
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        Box::leak(response.into_boxed_str())
+    }
+
+    const CONTEXT_OVERFLOW: &str = r#"{"error":{"code":400,"message":"request (5000 tokens) exceeds the available context size (4096 tokens), try increasing it","type":"exceed_context_size_error","n_prompt_tokens":5000,"n_ctx":4096}}"#;
+
+    #[tokio::test]
+    async fn context_overflow() {
+        let url = serve(vec![json_response("400 Bad Request", CONTEXT_OVERFLOW)]).await;
+        let link = LlamaLink::new(&url, Config::builder().build());
+
+        let error = link
+            .create_completion("hello".to_owned())
+            .await
+            .unwrap_err();
+        let CompletionError::ContextOverflow {
+            server_error,
+            n_prompt_tokens,
+            n_ctx,
+        } = &error
+        else {
+            panic!("Expected a context overflow, got {error}");
+        };
+        assert_eq!(server_error.kind, "exceed_context_size_error");
+        assert_eq!(*n_prompt_tokens, Some(5000));
+        assert_eq!(*n_ctx, Some(4096));
+        assert!(!error.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn server_errors() {
+        let url = serve(vec![
+            json_response(
+                "503 Service Unavailable",
+                r#"{"error":{"code":503,"message":"Loading model","type":"unavailable_error"}}"#,
+            ),
+            json_response("404 Not Found", "File Not Found"),
+        ])
+        .await;
+        let link = LlamaLink::new(&url, Config::builder().build());
+
+        let error = link.tokenize("hello").await.unwrap_err();
+        assert!(matches!(error, RequestError::Unavailable { .. }));
+        assert!(error.is_retryable());
+
+        let error = link.tokenize("hello").await.unwrap_err();
+        let RequestError::Http {
+            status,
+            server_error,
+        } = &error
+        else {
+            panic!("Expected an http error, got {error}");
+        };
+        assert_eq!(*status, StatusCode::NOT_FOUND);
+        assert_eq!(server_error.message, "File Not Found");
+        assert!(!error.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn stream_context_overflow() {
+        let url = serve(vec![json_response("400 Bad Request", CONTEXT_OVERFLOW)]).await;
+        let link = LlamaLink::new(&url, Config::builder().build());
+
+        let mut stream = link.create_completion_stream("hello".to_owned());
+        let error = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(
+            error,
+            CompletionStreamError::ContextOverflow {
+                n_ctx: Some(4096),
+                ..
+            }
+        ));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn transport_error() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let link = LlamaLink::new(&url, Config::builder().build());
+
+        let error = link
+            .create_completion("hello".to_owned())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, CompletionError::Transport(_)));
+        assert!(error.is_retryable());
+    }
+}
+
+#[cfg(test)]
+mod mock {
+    use std::time::Duration;
+
+    use llama_link::*;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn completion() {
+        let server = MockServer::start().await;
+        server.enqueue(
+            "/completion",
+            MockResponse::completion("Why did the chicken"),
+        );
+        let link = server.link();
+
+        let response = link
+            .create_completion_with_format(
+                "",
+                &[Message::User("In one sentence, tell me a joke.".to_owned())],
+                &PromptFormatter::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response, "Why did the chicken");
+
+        let requests = server.requests_to("/completion");
+        assert_eq!(requests.len(), 1);
+        let prompt = requests[0].body["prompt"].as_str().unwrap();
+        assert!(prompt.contains("In one sentence, tell me a joke."));
+    }
+
+    #[tokio::test]
+    async fn completion_stream() {
+        let server = MockServer::start().await;
+        server.enqueue(
+            "completion",
+            MockResponse::chunks(["Why ", "did ", "the ", "chicken"]),
+        );
+        let link = server.link();
+
+        let mut stream = link.create_completion_stream("Tell me a joke.".to_owned());
+        let mut response = String::new();
+        while let Some(content) = stream.next().await {
+            response.push_str(&content.unwrap());
+        }
+        assert_eq!(response, "Why did the chicken");
+        assert_eq!(server.requests_to("/completion")[0].body["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn tokenize_health_and_props() {
+        let server = MockServer::start().await;
+        server.set_total_slots(2);
+        let link = LlamaLink::builder()
+            .url(server.url())
+            .concurrency_limit(ConcurrencyLimit::ServerSlots)
+            .build()
+            .unwrap();
+
+        let tokens = link.tokenize("hi").await.unwrap();
+        assert_eq!(tokens, vec![104, 105]);
+        assert_eq!(link.detokenize(&tokens).await.unwrap(), "hi");
+        assert_eq!(link.health().await.unwrap().status, "ok");
+
+        link.create_completion("hello".to_owned()).await.unwrap();
+        assert_eq!(link.concurrency_limit(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn injected_errors() {
+        let server = MockServer::start().await;
+        server.enqueue("/completion", MockResponse::context_overflow(5000, 4096));
+        server.enqueue("/completion", MockResponse::unavailable());
+        server.set_default_completion("Recovered");
+        let link = LlamaLink::builder()
+            .url(server.url())
+            .retry_policy(
+                RetryPolicy::builder()
+                    .initial_backoff(Duration::from_millis(1))
+                    .build(),
+            )
+            .build()
+            .unwrap();
+
+        let error = link
+            .create_completion("hello".to_owned())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, CompletionError::ContextOverflow { .. }));
+        let response = link.create_completion("hello".to_owned()).await.unwrap();
+        assert_eq!(response, "Recovered");
+        assert_eq!(server.requests_to("/completion").len(), 3);
+    }
+
+    #[tokio::test]
+    async fn latency() {
+        let server = MockServer::start().await;
+        server.enqueue(
+            "/completion",
+            MockResponse::completion("Too slow").with_chunk_delay(Duration::from_millis(500)),
+        );
+        let link = LlamaLink::builder()
+            .url(server.url())
+            .first_token_timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+
+        let mut stream = link.create_completion_stream("hello".to_owned());
+        let mut timed_out = false;
+        while let Some(content) = stream.next().await {
+            if let Err(error) = content {
+                timed_out = matches!(error, CompletionStreamError::StreamTimeout);
+            }
+        }
+        assert!(timed_out);
+    }
+}
+
+#[cfg(test)]
+mod cassette {
+    use llama_link::*;
+    use tokio_stream::StreamExt;
+
+    fn cassette_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("llama_link_{name}_{}.jsonl", std::process::id()))
+    }
+
+    async fn collect(mut stream: CompletionStream) -> String {
+        let mut response = String::new();
+        while let Some(content) = stream.next().await {
+            response.push_str(&content.unwrap());
+        }
+        response
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let path = cassette_path("record_and_replay");
+        let server = MockServer::start().await;
+        server.enqueue(
+            "/completion",
+            MockResponse::completion("Why did the chicken"),
+        );
+        server.enqueue("/completion", MockResponse::chunks(["Why ", "did ", "it"]));
+        let recording = Cassette::record(&path).unwrap();
+        let link = LlamaLink::builder()
+            .url(server.url())
+            .cassette(recording.clone())
+            .build()
+            .unwrap();
+        let completion = link
+            .create_completion("Tell me a joke.".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(completion, "Why did the chicken");
+        let streamed = collect(link.create_completion_stream("Tell me another.".to_owned())).await;
+        assert_eq!(streamed, "Why did it");
+        assert_eq!(recording.interactions().len(), 2);
+        drop(server);
+
+        let replaying = Cassette::replay(&path, MatchRules::default()).unwrap();
+        let link = LlamaLink::builder()
+            .url("http://127.0.0.1:1")
+            .cassette(replaying.clone())
+            .build()
+            .unwrap();
+        let completion = link
+            .create_completion("Tell me a joke.".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(completion, "Why did the chicken");
+        let streamed = collect(link.create_completion_stream("Tell me another.".to_owned())).await;
+        assert_eq!(streamed, "Why did it");
+        assert!(replaying.unplayed().is_empty());
+
+        let error = link
+            .create_completion("Tell me a joke.".to_owned())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, CompletionError::UnmatchedRequest { .. }));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn match_rules() {
+        let server = MockServer::start().await;
+        let recording = Cassette::record(cassette_path("match_rules")).unwrap();
+        let link = LlamaLink::builder()
+            .url(server.url())
+            .config(Config::builder().seed(1).build())
+            .cassette(recording.clone())
+            .build()
+            .unwrap();
+        link.create_completion("Tell  me a joke.".to_owned())
+            .await
+            .unwrap();
+        let _ = std::fs::remove_file(cassette_path("match_rules"));
+
+        let reseeded = Config::builder().seed(2).build();
+        let strict = Cassette::from_interactions(recording.interactions(), MatchRules::default());
+        let link = LlamaLink::builder()
+            .url(server.url())
+            .config(reseeded.clone())
+            .cassette(strict)
+            .build()
+            .unwrap();
+        let error = link.create_completion("Tell  me a joke.".to_owned()).await;
+        assert!(matches!(
+            error,
+            Err(CompletionError::UnmatchedRequest { .. })
+        ));
+
+        let lenient = MatchRules::builder()
+            .ignore_seed(true)
+            .exact_prompt(false)
+            .consume(false)
+            .build();
+        let link = LlamaLink::builder()
+            .url(server.url())
+            .config(reseeded)
+            .cassette(Cassette::from_interactions(
+                recording.interactions(),
+                lenient,
+            ))
+            .build()
+            .unwrap();
+        for _ in 0..2 {
+            link.create_completion("Tell me a joke.".to_owned())
+                .await
+                .unwrap();
+        }
+        assert_eq!(server.requests_to("/completion").len(), 1);
+    }
+
+    fn loose() -> MatchRules {
+        MatchRules::builder()
+            .exact_prompt(false)
+            .consume(false)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn loose_multimodal_prompt() {
+        const PNG: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 13];
+        let look = |text: &str| Message::UserWithImages {
+            text: text.to_owned(),
+            images: vec![Image::from_bytes(PNG).unwrap()],
+        };
+        let server = MockServer::start().await;
+        server.set_vision(true);
+        let recording = Cassette::record(cassette_path("loose_multimodal_prompt")).unwrap();
+        let link = LlamaLink::builder()
+            .url(server.url())
+            .cassette(recording.clone())
+            .build()
+            .unwrap();
+        let formatter = PromptFormatter::default();
+        link.create_completion_with_format("", &[look("What  is\nthis?")], &formatter)
+            .await
+            .unwrap();
+        let _ = std::fs::remove_file(cassette_path("loose_multimodal_prompt"));
+
+        let link = LlamaLink::builder()
+            .url("http://127.0.0.1:1")
+            .cassette(Cassette::from_interactions(
+                recording.interactions(),
+                loose(),
+            ))
+            .build()
+            .unwrap();
+        link.create_completion_with_format("", &[look("What is this?")], &formatter)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn loose_chat_messages() {
+        let server = MockServer::start().await;
+        server.enqueue(
+            "/v1/chat/completions",
+            MockResponse::json(
+                200,
+                serde_json::json!({ "choices": [{ "message": { "content": "Why" } }] }),
+            ),
+        );
+        let recording = Cassette::record(cassette_path("loose_chat_messages")).unwrap();
+        let link = LlamaLink::builder()
+            .url(server.url())
+            .cassette(recording.clone())
+            .build()
+            .unwrap();
+        link.create_chat_completion(
+            "Be  brief.",
+            &[Message::User("Tell  me a joke.".to_owned())],
+        )
+        .await
+        .unwrap();
+        let _ = std::fs::remove_file(cassette_path("loose_chat_messages"));
+
+        let link = LlamaLink::builder()
+            .url("http://127.0.0.1:1")
+            .cassette(Cassette::from_interactions(
+                recording.interactions(),
+                loose(),
+            ))
+            .build()
+            .unwrap();
+        let reply = link
+            .create_chat_completion("Be brief.", &[Message::User("Tell me a joke.".to_owned())])
+            .await
+            .unwrap();
+        assert_eq!(reply, "Why");
+    }
+}
+
+#[cfg(test)]
+mod backend {
+    use std::sync::Mutex;
+
+    use llama_link::*;
+    use serde_json::{json, Map, Value};
+
+    /// Answers every prompt with the next scripted response.
+    struct Scripted {
+        responses: Mutex<Vec<&'static str>>,
+        prompts: Mutex<Vec<String>>,
+    }
+
+    impl Scripted {
+        fn new(responses: &[&'static str]) -> Self {
+            Self {
+                responses: Mutex::new(responses.iter().rev().copied().collect()),
+                prompts: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    impl CompletionBackend for Scripted {
+        async fn complete(&self, prompt: String) -> Result<Completion, CompletionError> {
+            self.prompts.lock().unwrap().push(prompt);
+            let content = self.responses.lock().unwrap().pop().unwrap_or_default();
+            Ok(Completion {
+                content: content.to_owned(),
+                probabilities: Vec::new(),
+                tokens_cached: None,
+            })
+        }
+
+        fn complete_stream(&self, _prompt: String) -> CompletionChunkStream {
+            unimplemented!()
+        }
+
+        async fn complete_constrained(
+            &self,
+            prompt: String,
+            _constraint: Constraint<'_>,
+        ) -> Result<Completion, CompletionError> {
+            self.complete(prompt).await
+        }
+
+        async fn tokenize(&self, content: &str) -> Result<Vec<u32>, RequestError> {
+            Ok(content.bytes().map(u32::from).collect())
+        }
+    }
+
+    fn schema() -> Map<String, Value> {
+        let Value::Object(schema) = json!({
+            "type": "object",
+            "properties": { "answer": { "type": "integer" } }
+        }) else {
+            unreachable!()
+        };
+        schema
+    }
+
+    #[tokio::test]
+    async fn test_double() {
+        let backend = Scripted::new(&["Hello", r#"{"answer": 42}"#, "not json"]);
+
+        let completion = backend
+            .complete_with_format(
+                "Be brief.",
+                &[Message::User("Hi".to_owned())],
+                &PromptFormatter::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(completion.content, "Hello");
+        assert!(backend.prompts.lock().unwrap()[0].contains("Be brief."));
+
+        let value = backend.complete_json("Answer".to_owned(), &schema()).await;
+        assert_eq!(value.unwrap()["answer"], 42);
+        let error = backend.complete_json("Answer".to_owned(), &schema()).await;
+        assert!(matches!(error, Err(CompletionError::Parsing { .. })));
+        assert_eq!(backend.tokenize("hi").await.unwrap(), vec![104, 105]);
+    }
+
+    #[tokio::test]
+    async fn llama_link_constraints() {
+        let server = MockServer::start().await;
+        server.enqueue("/completion", MockResponse::completion(r#"{"answer": 42}"#));
+        server.enqueue("/completion", MockResponse::completion("yes"));
+        let link = server.link();
+
+        let value = link.complete_json("Answer".to_owned(), &schema()).await;
+        assert_eq!(value.unwrap()["answer"], 42);
+        let completion = link
+            .complete_constrained(
+                "Agree?".to_owned(),
+                Constraint::Grammar(r#"root ::= "yes""#),
+            )
+            .await
+            .unwrap();
+        assert_eq!(completion.content, "yes");
+
+        let requests = server.requests_to("/completion");
+        assert_eq!(requests[0].body["json_schema"], Value::Object(schema()));
+        assert_eq!(requests[1].body["grammar"], r#"root ::= "yes""#);
+    }
+}
+
+#[cfg(test)]
+mod ollama {
+    use llama_link::*;
+    use serde_json::json;
+    use tokio_stream::StreamExt;
+
+    use crate::retry::serve;
+
+    #[tokio::test]
+    async fn generate_and_chat() {
+        let server = MockServer::start().await;
+        server.enqueue(
+            "/api/generate",
+            MockResponse::json(
+                200,
+                json!({ "response": "Why did the chicken", "done": true }),
+            ),
+        );
+        server.enqueue(
+            "/api/chat",
+            MockResponse::json(
+                200,
+                json!({
+                    "message": { "role": "assistant", "content": "Hello!" },
+                    "done": true,
+                    "done_reason": "stop",
+                    "prompt_eval_count": 12,
+                    "eval_count": 3
+                }),
+            ),
+        );
+        let link = OllamaLink::new(
+            server.url(),
+            "llama3.2",
+            Config::builder().n_predict(16).seed(7).n_probs(2).build(),
+        );
+
+        let completion = link.create_completion("Tell me a joke.".to_owned()).await;
+        assert_eq!(completion.unwrap(), "Why did the chicken");
+        let chat = link
+            .create_chat_completion_full("Be brief.", &[Message::User("Hi".to_owned())])
+            .await
+            .unwrap();
+        assert_eq!(chat.content.as_deref(), Some("Hello!"));
+        assert_eq!(chat.finish_reason, Some(FinishReason::Stop));
+        assert_eq!(chat.usage.unwrap().total_tokens, 15);
+
+        let generate = &server.requests_to("/api/generate")[0].body;
+        assert_eq!(generate["model"], "llama3.2");
+        assert_eq!(generate["raw"], true);
+        assert_eq!(generate["options"], json!({ "num_predict": 16, "seed": 7 }));
+        let chat = &server.requests_to("/api/chat")[0].body;
+        assert_eq!(chat["messages"][0]["role"], "system");
+        assert_eq!(chat["messages"][1]["content"], "Hi");
+    }
+
+    #[tokio::test]
+    async fn ndjson_stream() {
+        let body = "{\"response\":\"Why \",\"done\":false}\n{\"response\":\"did \",\"done\":false}\n{\"response\":\"it\",\"done\":false}\n{\"response\":\"\",\"done\":true}\n";
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        let url = serve(vec![Box::leak(response.into_boxed_str())]).await;
+        let link = OllamaLink::new(&url, "llama3.2", Config::builder().build());
+
+        let mut stream = link.create_completion_stream("Tell me a joke.".to_owned());
+        let mut response = String::new();
+        while let Some(content) = stream.next().await {
+            response.push_str(&content.unwrap());
+        }
+        assert_eq!(response, "Why did it");
+    }
+
+    #[tokio::test]
+    async fn stream_error_lines() {
+        let server = MockServer::start().await;
+        server.enqueue(
+            "/api/generate",
+            MockResponse::ndjson([
+                json!({ "response": "Why ", "done": false }),
+                json!({ "error": "model runner has unexpectedly stopped" }),
+            ]),
+        );
+        server.enqueue(
+            "/api/chat",
+            MockResponse::ndjson([json!({ "error": "model runner has unexpectedly stopped" })]),
+        );
+        let link = OllamaLink::new(server.url(), "llama3.2", Config::builder().build());
+
+        let mut stream = link.create_completion_stream("Tell me a joke.".to_owned());
+        assert_eq!(stream.next().await.unwrap().unwrap(), "Why ");
+        match stream.next().await {
+            Some(Err(CompletionStreamError::Http { server_error, .. })) => {
+                assert_eq!(
+                    server_error.message,
+                    "model runner has unexpectedly stopped"
+                )
+            }
+            _ => panic!("Expected the error line as an http error"),
+        }
+        assert!(stream.next().await.is_none());
+
+        let mut stream = link.create_chat_completion_stream("", &[Message::User("Hi".to_owned())]);
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(CompletionStreamError::Http { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn constraints_and_errors() {
+        let server = MockServer::start().await;
+        server.enqueue(
+            "/api/generate",
+            MockResponse::json(200, json!({ "response": "{\"answer\": 42}", "done": true })),
+        );
+        server.enqueue(
+            "/api/generate",
+            MockResponse::json(404, json!({ "error": "model 'missing' not found" })),
+        );
+        let engine = Engine::from(OllamaLink::new(
+            server.url(),
+            "llama3.2",
+            Config::builder().build(),
+        ));
+        let schema = json!({ "type": "object" });
+        let value = engine
+            .complete_json("Answer".to_owned(), schema.as_object().unwrap())
+            .await;
+        assert_eq!(value.unwrap()["answer"], 42);
+        assert_eq!(
+            server.requests_to("/api/generate")[0].body["format"],
+            schema
+        );
+
+        match engine.complete("Hi".to_owned()).await {
+            Err(CompletionError::Http { server_error, .. }) => {
+                assert_eq!(server_error.message, "model 'missing' not found")
+            }
+            _ => panic!("Expected an http error"),
+        }
+        let grammar = engine
+            .complete_constrained("Hi".to_owned(), Constraint::Grammar("root ::= \"a\""))
+            .await;
+        assert!(matches!(grammar, Err(CompletionError::Unsupported { .. })));
+        assert!(matches!(
+            engine.tokenize("hi").await,
+            Err(RequestError::Unsupported { .. })
+        ));
+    }
+}
+
+#[cfg(test)]
+mod blocking {
+    use llama_link::*;
+
+    /// The mock server runs on its own runtime, since the blocking link must not be used inside one.
+    fn start_server() -> (tokio::runtime::Runtime, MockServer) {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = runtime.block_on(MockServer::start());
+        (runtime, server)
+    }
+
+    #[test]
+    fn completion_and_stream() {
+        let (_runtime, server) = start_server();
+        server.enqueue(
+            "/completion",
+            MockResponse::completion("Why did the chicken"),
+        );
+        server.enqueue("/completion", MockResponse::chunks(["Why ", "did ", "it"]));
+        let link = BlockingLlamaLink::new(server.url(), Config::builder().build());
+
+        let completion = link.create_completion_with_format(
+            "",
+            &[Message::User("Tell me a joke.".to_owned())],
+            &PromptFormatter::default(),
+        );
+        assert_eq!(completion.unwrap(), "Why did the chicken");
+        let streamed: Result<String, _> = link
+            .create_completion_stream("Tell me another.".to_owned())
+            .collect();
+        assert_eq!(streamed.unwrap(), "Why did it");
+        assert_eq!(link.tokenize("hi").unwrap(), vec![104, 105]);
+    }
+
+    #[test]
+    fn errors() {
+        let (_runtime, server) = start_server();
+        server.enqueue("/completion", MockResponse::unavailable());
+        let link = BlockingLlamaLink::from(server.link());
+
+        let error = link.create_completion("Hi".to_owned()).unwrap_err();
+        assert!(matches!(error, CompletionError::Unavailable { .. }));
+    }
+}
+
+#[cfg(test)]
+mod conversation {
+    use llama_link::*;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn send_and_stream() {
+        let server = MockServer::start().await;
+        server.enqueue("/completion", MockResponse::completion("Hello!"));
+        server.enqueue("/completion", MockResponse::chunks(["Fine, ", "thanks."]));
+        let link = server.link();
+        let mut conversation = Conversation::builder()
+            .system("Be brief.")
+            .overrides(Config::builder().seed(3).build())
+            .build();
+
+        assert_eq!(conversation.send(&link, "Hi").await.unwrap(), "Hello!");
+        let mut stream = conversation
+            .send_stream(&link, "How are you?")
+            .await
+            .unwrap();
+        while let Some(chunk) = stream.next().await {
+            chunk.unwrap();
+        }
+        drop(stream);
+
+        assert_eq!(
+            conversation.history(),
+            [
+                Message::User("Hi".to_owned()),
+                Message::Assistant("Hello!".to_owned()),
+                Message::User("How are you?".to_owned()),
+                Message::Assistant("Fine, thanks.".to_owned()),
+            ]
+        );
+        let requests = server.requests_to("/completion");
+        assert_eq!(requests[0].body["seed"], 3);
+        let prompt = requests[1].body["prompt"].as_str().unwrap();
+        assert!(prompt.contains("Be brief.") && prompt.contains("Hello!"));
+    }
+
+    #[tokio::test]
+    async fn failed_turns_are_not_appended() {
+        let server = MockServer::start().await;
+        server.enqueue("/completion", MockResponse::unavailable());
+        let mut conversation = Conversation::builder().build();
+
+        assert!(conversation.send(&server.link(), "Hi").await.is_err());
+        assert!(conversation.history().is_empty());
+    }
+
+    #[test]
+    fn budget_and_persistence() {
+        let mut conversation = Conversation::builder()
+            .system("Be brief.")
+            .max_messages(3)
+            .build();
+        for turn in 0..3 {
+            conversation.push(Message::User(format!("Question {turn}")));
+            conversation.push(Message::Assistant(format!("Answer {turn}")));
+        }
+        assert_eq!(
+            conversation.history(),
+            [
+                Message::User("Question 2".to_owned()),
+                Message::Assistant("Answer 2".to_owned()),
+            ]
+        );
+
+        let json = conversation.to_json().unwrap();
+        assert!(json.contains(r#"{"role":"user","content":"Question 2"}"#));
+        let loaded = Conversation::from_json(&json, PromptFormatter::default()).unwrap();
+        assert_eq!(loaded.system(), "Be brief.");
+        assert_eq!(loaded.history(), conversation.history());
+    }
+}
+
+#[cfg(test)]
+mod context {
+    use llama_link::*;
+
+    fn turns(count: usize) -> Vec<Message> {
+        (0..count)
+            .flat_map(|turn| {
+                [
+                    Message::User(format!("Question {turn} {}", "padding ".repeat(20))),
+                    Message::Assistant(format!("Answer {turn} {}", "padding ".repeat(20))),
+                ]
+            })
+            .collect()
+    }
+
+    /// The context size that fits exactly the prompt of `history` followed by `message`, with
+    /// `reserve` tokens left. The mock server tokenizes into bytes.
+    fn n_ctx_for(history: Vec<Message>, message: &str, reserve: usize) -> usize {
+        let conversation = Conversation::builder()
+            .system("Be brief.")
+            .history(history)
+            .build();
+        conversation.prompt(message).len() + reserve
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let server = MockServer::start().await;
+        server.set_n_ctx(n_ctx_for(turns(3)[4..].to_vec(), "Next", 16));
+        server.enqueue("/completion", MockResponse::completion("Done"));
+        let link = LlamaLink::new(server.url(), Config::builder().n_predict(16).build());
+        let mut conversation = Conversation::builder()
+            .system("Be brief.")
+            .history(turns(3))
+            .context(ContextManagement::builder().build())
+            .build();
+
+        conversation.send(&link, "Next").await.unwrap();
+        assert_eq!(conversation.history()[..2], turns(3)[4..]);
+        assert_eq!(conversation.history().len(), 4);
+        assert_eq!(server.requests_to("/tokenize").len(), 3);
+    }
+
+    #[tokio::test]
+    async fn keep_first_last() {
+        let server = MockServer::start().await;
+        let history = turns(4);
+        let kept = [&history[..2], &history[6..]].concat();
+        let link = server.link();
+        let mut conversation = Conversation::builder()
+            .system("Be brief.")
+            .history(history)
+            .context(
+                ContextManagement::builder()
+                    .strategy(TruncationStrategy::KeepFirstLast { first: 1, last: 1 })
+                    .counter(TokenCounter::Estimate {
+                        chars_per_token: 1.0,
+                    })
+                    .n_ctx(n_ctx_for(kept.clone(), "Next", 512) - 1)
+                    .build(),
+            )
+            .build();
+
+        let error = conversation.send(&link, "Next").await.unwrap_err();
+        assert!(matches!(error, CompletionError::ContextOverflow { .. }));
+        assert_eq!(conversation.history(), turns(4));
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn summarize() {
+        let server = MockServer::start().await;
+        server.set_n_ctx(n_ctx_for(turns(3)[4..].to_vec(), "Next", 512) + 64);
+        server.enqueue("/completion", MockResponse::completion("They asked twice."));
+        server.enqueue("/completion", MockResponse::completion("Done"));
+        let link = server.link();
+        let mut conversation = Conversation::builder()
+            .system("Be brief.")
+            .history(turns(3))
+            .context(
+                ContextManagement::builder()
+                    .strategy(TruncationStrategy::Summarize { max_tokens: 64 })
+                    .counter(TokenCounter::Calibrated)
+                    .build(),
+            )
+            .build();
+
+        conversation.send(&link, "Next").await.unwrap();
+        assert_eq!(conversation.summary(), Some("They asked twice."));
+        let requests = server.requests_to("/completion");
+        let summary_prompt = requests[0].body["prompt"].as_str().unwrap();
+        assert!(summary_prompt.contains("User: Question 0"));
+        assert_eq!(requests[0].body["n_predict"], 64);
+        let prompt = requests[1].body["prompt"].as_str().unwrap();
+        assert!(prompt.contains("They asked twice.") && !prompt.contains("Question 0"));
+        assert_eq!(server.requests_to("/tokenize").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_summary_keeps_history() {
+        let server = MockServer::start().await;
+        server.set_n_ctx(n_ctx_for(turns(3)[4..].to_vec(), "Next", 512) + 64);
+        server.enqueue("/completion", MockResponse::unavailable());
+        let link = server.link();
+        let mut conversation = Conversation::builder()
+            .system("Be brief.")
+            .history(turns(3))
+            .context(
+                ContextManagement::builder()
+                    .strategy(TruncationStrategy::Summarize { max_tokens: 64 })
+                    .build(),
+            )
+            .build();
+
+        let error = conversation.send(&link, "Next").await.unwrap_err();
+        assert!(matches!(error, CompletionError::Unavailable { .. }));
+        assert_eq!(conversation.history(), turns(3));
+        assert_eq!(conversation.summary(), None);
+        assert_eq!(server.requests_to("/completion").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn summarize_again_fits() {
+        let server = MockServer::start().await;
+        let n_ctx = n_ctx_for(turns(3)[4..].to_vec(), "Next", 16) + 464;
+        server.set_n_ctx(n_ctx);
+        server.enqueue("/completion", MockResponse::completion("Short."));
+        server.enqueue(
+            "/completion",
+            MockResponse::completion(&"long ".repeat(120)),
+        );
+        server.enqueue("/completion", MockResponse::completion(&"x".repeat(400)));
+        server.enqueue("/completion", MockResponse::completion("Done"));
+        let link = LlamaLink::new(server.url(), Config::builder().n_predict(16).build());
+        let mut conversation = Conversation::builder()
+            .system("Be brief.")
+            .history(turns(3))
+            .context(
+                ContextManagement::builder()
+                    .strategy(TruncationStrategy::Summarize { max_tokens: 400 })
+                    .build(),
+            )
+            .build();
+
+        conversation.send(&link, "Next").await.unwrap();
+        conversation.send(&link, "Again").await.unwrap();
+        // The second summary is much longer than the first, but still fits
+        assert_eq!(conversation.summary().unwrap().len(), 400);
+        let requests = server.requests_to("/completion");
+        assert_eq!(requests.len(), 4);
+        for request in requests
+            .iter()
+            .filter(|request| request.body["n_predict"] == 16)
+        {
+            assert!(request.body["prompt"].as_str().unwrap().len() + 16 <= n_ctx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod affinity {
+    use llama_link::*;
+    use serde_json::json;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn session_slot_reuses_cache() {
+        let server = MockServer::start().await;
+        server.set_total_slots(4);
+        server.enqueue("/completion", MockResponse::completion("Hello!"));
+        server.enqueue("/completion", MockResponse::chunks(["Fine, ", "thanks."]));
+        let link = server.link();
+        let mut conversation = Conversation::builder()
+            .system("Be brief.")
+            .session("user-42")
+            .build();
+
+        conversation.send(&link, "Hi").await.unwrap();
+        assert_eq!(conversation.tokens_cached(), Some(0));
+        let mut stream = conversation
+            .send_stream(&link, "How are you?")
+            .await
+            .unwrap();
+        while let Some(chunk) = stream.next().await {
+            chunk.unwrap();
+        }
+        drop(stream);
+        let first_prompt = server.requests_to("/completion")[0].body["prompt"]
+            .as_str()
+            .unwrap()
+            .len();
+        assert!(conversation.tokens_cached().unwrap() > first_prompt);
+
+        let mut loaded =
+            Conversation::from_json(&conversation.to_json().unwrap(), PromptFormatter::default())
+                .unwrap();
+        assert_eq!(loaded.session(), "user-42");
+        loaded.send(&link, "Bye").await.unwrap();
+
+        let requests = server.requests_to("/completion");
+        assert!(requests[0].body["id_slot"].as_u64().unwrap() < 4);
+        assert!(requests.iter().all(|request| request.body["id_slot"]
+            == requests[0].body["id_slot"]
+            && request.body["cache_prompt"] == true));
+        // Fetched once for the link, not per conversation
+        assert_eq!(server.requests_to("/props").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn session_without_props() {
+        let server = MockServer::start().await;
+        server.enqueue(
+            "/props",
+            MockResponse::error(404, "not_found_error", "File Not Found"),
+        );
+        let link = server.link();
+        let mut conversation = Conversation::builder().session("user-42").build();
+
+        conversation.send(&link, "Hi").await.unwrap();
+        conversation.send(&link, "Bye").await.unwrap();
+
+        let requests = server.requests_to("/completion");
+        assert!(requests
+            .iter()
+            .all(|request| request.body["id_slot"].is_null()));
+        assert_eq!(server.requests_to("/props").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fixed_slot_and_overrides() {
+        let server = MockServer::start().await;
+        let link = server.link();
+
+        let mut fixed = Conversation::builder()
+            .affinity(SlotAffinity::Slot(2))
+            .build();
+        fixed.send(&link, "Hi").await.unwrap();
+        let mut overridden = Conversation::builder()
+            .affinity(SlotAffinity::Slot(2))
+            .overrides(Config::builder().id_slot(1).cache_prompt(false).build())
+            .build();
+        overridden.send(&link, "Hi").await.unwrap();
+        let mut unpinned = Conversation::builder().affinity(SlotAffinity::None).build();
+        unpinned.send(&link, "Hi").await.unwrap();
+
+        let requests = server.requests_to("/completion");
+        assert_eq!(requests[0].body["id_slot"], 2);
+        assert_eq!(requests[1].body["id_slot"], 1);
+        assert_eq!(requests[1].body["cache_prompt"], false);
+        assert_eq!(overridden.tokens_cached(), Some(0));
+        assert!(requests[2].body["id_slot"].is_null());
+        assert_eq!(requests[2].body["cache_prompt"], true);
+        assert!(server.requests_to("/props").is_empty());
+    }
+
+    #[tokio::test]
+    async fn chat_caches_prompt_by_default() {
+        let server = MockServer::start().await;
+        server.enqueue(
+            "/v1/chat/completions",
+            MockResponse::json(
+                200,
+                json!({
+                    "choices": [{ "message": { "content": "Hello!" }, "finish_reason": "stop" }],
+                    "timings": { "cache_n": 12, "prompt_n": 3 },
+                }),
+            ),
+        );
+        let link = server.link();
+
+        let completion = link
+            .create_chat_completion_full("", &[Message::User("Hi".to_owned())])
+            .await
+            .unwrap();
+        assert_eq!(completion.tokens_cached, Some(12));
+        assert_eq!(
+            server.requests_to("/v1/chat/completions")[0].body["cache_prompt"],
+            true
+        );
+
+        let completion = link
+            .with_overrides(Config::builder().cache_prompt(false).build())
+            .create_completion_full("Hi".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(completion.tokens_cached, Some(0));
+        assert_eq!(
+            server.requests_to("/completion")[0].body["cache_prompt"],
+            false
+        );
+    }
+}
+
+#[cfg(test)]
+mod tree {
+    use llama_link::*;
+
+    #[tokio::test]
+    async fn regenerate_and_edit() {
+        let server = MockServer::start().await;
+        for reply in ["Hello!", "Fine.", "Great.", "Hi there.", "Still fine."] {
+            server.enqueue("/completion", MockResponse::completion(reply));
+        }
+        let link = server.link();
+        let mut tree = ConversationTree::from(Conversation::builder().system("Be brief.").build());
+
+        tree.send(&link, "Hi").await.unwrap();
+        tree.send(&link, "How are you?").await.unwrap();
+        let fine = tree.active().unwrap();
+        assert_eq!(tree.regenerate(&link, fine).await.unwrap(), "Great.");
+        let great = tree.active().unwrap();
+        assert_eq!(tree.siblings(great), [fine, great]);
+        let prompt = server.requests_to("/completion")[2].body["prompt"]
+            .as_str()
+            .unwrap()
+            .to_owned();
+        assert!(prompt.contains("Hello!") && !prompt.contains("Fine."));
+
+        let hi = tree.roots()[0];
+        assert_eq!(tree.edit(&link, hi, "Hey").await.unwrap(), "Hi there.");
+        assert_eq!(
+            tree.active_messages(),
+            [
+                Message::User("Hey".to_owned()),
+                Message::Assistant("Hi there.".to_owned()),
+            ]
+        );
+        assert_eq!(tree.roots().len(), 2);
+        assert_eq!(tree.leaves().len(), 3);
+
+        tree.set_active(Some(fine)).unwrap();
+        tree.send(&link, "Really?").await.unwrap();
+        let prompt = server.requests_to("/completion")[4].body["prompt"]
+            .as_str()
+            .unwrap()
+            .to_owned();
+        assert!(prompt.contains("Fine.") && !prompt.contains("Hey"));
+        assert_eq!(tree.messages(tree.active().unwrap()).len(), 6);
+    }
+
+    #[tokio::test]
+    async fn errors_and_persistence() {
+        let server = MockServer::start().await;
+        let link = server.link();
+        let mut tree = ConversationTree::from(
+            Conversation::builder()
+                .history(vec![
+                    Message::User("Hi".to_owned()),
+                    Message::Assistant("Hello!".to_owned()),
+                ])
+                .build(),
+        );
+        let [hi, hello] = tree.path(tree.active().unwrap())[..] else {
+            panic!("Expected the history as one branch");
+        };
+
+        assert!(matches!(
+            tree.regenerate(&link, hi).await,
+            Err(BranchError::NotAReply { .. })
+        ));
+        assert!(matches!(
+            tree.edit(&link, hello, "Hey").await,
+            Err(BranchError::NotAUserMessage { .. })
+        ));
+        let unknown: NodeId = serde_json::from_str("99").unwrap();
+        assert!(matches!(
+            tree.set_active(Some(unknown)),
+            Err(BranchError::UnknownNode { .. })
+        ));
+        assert!(server.requests().is_empty());
+
+        server.enqueue("/completion", MockResponse::unavailable());
+        assert!(tree.regenerate(&link, hello).await.is_err());
+        assert_eq!(tree.nodes().len(), 2);
+        assert_eq!(tree.active(), Some(hello));
+
+        let loaded =
+            ConversationTree::from_json(&tree.to_json().unwrap(), PromptFormatter::default())
+                .unwrap();
+        assert_eq!(loaded.nodes(), tree.nodes());
+        assert_eq!(loaded.active(), Some(hello));
+    }
+
+    #[test]
+    fn load_rejects_invalid_trees() {
+        let tree = ConversationTree::from(
+            Conversation::builder()
+                .history(vec![
+                    Message::User("Hi".to_owned()),
+                    Message::Assistant("Hello!".to_owned()),
+                ])
+                .build(),
+        );
+        let json: serde_json::Value = serde_json::from_str(&tree.to_json().unwrap()).unwrap();
+        let corruptions: [fn(&mut serde_json::Value); 5] = [
+            |json| json["nodes"][1]["id"] = 5.into(),
+            |json| json["nodes"][0]["parent"] = 1.into(),
+            |json| json["nodes"][1]["parent"] = 1.into(),
+            |json| json["active"] = 2.into(),
+            |json| json["synced"] = 2.into(),
+        ];
+        for corrupt in corruptions {
+            let mut json = json.clone();
+            corrupt(&mut json);
+            assert!(
+                ConversationTree::from_json(&json.to_string(), PromptFormatter::default()).is_err(),
+                "Loaded {json}"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod image {
+    use llama_link::*;
+    use serde_json::json;
+    use tokio_stream::StreamExt;
+
+    const PNG: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 13];
+
+    fn look(text: &str) -> Message {
+        Message::UserWithImages {
+            text: text.to_owned(),
+            images: vec![Image::from_bytes(PNG).unwrap()],
+        }
+    }
+
+    #[test]
+    fn image_sources() {
+        let image = Image::from_bytes(PNG).unwrap();
+        assert_eq!(image.mime(), "image/png");
+        assert_eq!(Image::from_data_url(&image.data_url()).unwrap(), image);
+        let untyped = format!("data:;base64,{}", image.base64());
+        assert_eq!(Image::from_data_url(&untyped).unwrap(), image);
+
+        let path = std::env::temp_dir().join("llama_link_image_sources.png");
+        std::fs::write(&path, PNG).unwrap();
+        assert_eq!(Image::from_path(&path).unwrap(), image);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            Image::from_bytes(b"plain text"),
+            Err(ImageError::UnsupportedFormat)
+        ));
+        assert!(matches!(
+            Image::from_data_url("data:text/plain;base64,aGk="),
+            Err(ImageError::InvalidDataUrl { .. })
+        ));
+        assert!(matches!(
+            Image::from_path("/does/not/exist.png"),
+            Err(ImageError::Io(_))
+        ));
+        assert_eq!(
+            look("What is this?").text_with_media_markers(),
+            format!("{MEDIA_MARKER}\nWhat is this?")
+        );
+    }
+
+    #[tokio::test]
+    async fn completion_with_images() {
+        let server = MockServer::start().await;
+        server.set_vision(true);
+        server.enqueue("/completion", MockResponse::chunks(["A ", "logo."]));
+        let link = server.link();
+        let messages = [look("What is this?")];
+
+        let completion = link
+            .create_completion_with_format("", &messages, &PromptFormatter::default())
+            .await
+            .unwrap();
+        assert_eq!(completion, "A logo.");
+        let mut stream =
+            link.create_formatted_completion_stream("", &messages, &PromptFormatter::default());
+        let mut streamed = String::new();
+        while let Some(chunk) = stream.next().await {
+            streamed.push_str(&chunk.unwrap());
+        }
+        assert_eq!(streamed, "Hello from the mock server");
+
+        let prompt = &server.requests_to("/completion")[0].body["prompt"];
+        assert!(prompt["prompt_string"]
+            .as_str()
+            .unwrap()
+            .contains(&format!("{MEDIA_MARKER}\nWhat is this?")));
+        assert_eq!(
+            prompt["multimodal_data"],
+            json!([Image::from_bytes(PNG).unwrap().base64()])
+        );
+        assert_eq!(server.requests_to("/props").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn requires_vision_support() {
+        let server = MockServer::start().await;
+        let link = server.link();
+        let messages = [look("What is this?")];
+
+        assert!(!link.supports_images().await.unwrap());
+        let result = link
+            .create_completion_with_format("", &messages, &PromptFormatter::default())
+            .await;
+        assert!(matches!(result, Err(CompletionError::Unsupported { .. })));
+        let result = link
+            .classify_with_format("", &messages, &PromptFormatter::default(), &["logo"])
+            .await;
+        assert!(matches!(result, Err(CompletionError::Unsupported { .. })));
+        let mut stream = link.create_chat_completion_stream("", &messages);
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(CompletionStreamError::Unsupported { .. }))
+        ));
+        assert!(stream.next().await.is_none());
+        assert!(server.requests_to("/completion").is_empty());
+        assert!(server.requests_to("/v1/chat/completions").is_empty());
+    }
+
+    #[tokio::test]
+    async fn classify_and_best_of() {
+        let server = MockServer::start().await;
+        server.set_vision(true);
+        server.enqueue("/completion", MockResponse::completion("logo"));
+        let link = server.link();
+        let messages = [look("What is this?")];
+        let formatter = PromptFormatter::default();
+
+        let classification = link
+            .classify_with_format("", &messages, &formatter, &["logo", "photo"])
+            .await
+            .unwrap();
+        assert_eq!(classification.label, "logo");
+        link.best_of_n_with_format("", &messages, &formatter, 2, Selection::MajorityVote)
+            .await
+            .unwrap();
+
+        let requests = server.requests_to("/completion");
+        assert_eq!(requests.len(), 3);
+        for request in requests {
+            assert_eq!(
+                request.body["prompt"]["multimodal_data"],
+                json!([Image::from_bytes(PNG).unwrap().base64()])
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn backend_methods() {
+        let server = MockServer::start().await;
+        server.set_vision(true);
+        let messages = [look("What is this?")];
+        let formatter = PromptFormatter::default();
+
+        let engine = Engine::from(server.link());
+        engine
+            .complete_with_format("", &messages, &formatter)
+            .await
+            .unwrap();
+        let prompt = &server.requests_to("/completion")[0].body["prompt"];
+        assert_eq!(prompt["multimodal_data"].as_array().unwrap().len(), 1);
+
+        // Formatted Ollama prompts cannot carry images, so they are rejected instead of dropped
+        let engine = Engine::from(OllamaLink::new(
+            server.url(),
+            "llava",
+            Config::builder().build(),
+        ));
+        let result = engine.complete_with_format("", &messages, &formatter).await;
+        assert!(matches!(result, Err(CompletionError::Unsupported { .. })));
+        let mut stream = engine.complete_stream_with_format("", &messages, &formatter);
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(CompletionStreamError::Unsupported { .. }))
+        ));
+        assert!(server.requests_to("/api/generate").is_empty());
+    }
+
+    #[tokio::test]
+    async fn chat_and_conversation() {
+        let server = MockServer::start().await;
+        server.set_vision(true);
+        server.enqueue(
+            "/v1/chat/completions",
+            MockResponse::json(
+                200,
+                json!({ "choices": [{ "message": { "content": "A logo." }, "finish_reason": "stop" }] }),
+            ),
+        );
+        let link = server.link();
+
+        let reply = link
+            .create_chat_completion("", &[look("What is this?")])
+            .await
+            .unwrap();
+        assert_eq!(reply, "A logo.");
+        let content = &server.requests_to("/v1/chat/completions")[0].body["messages"][0]["content"];
+        assert_eq!(
+            content[0],
+            json!({ "type": "text", "text": "What is this?" })
+        );
+        assert_eq!(
+            content[1]["image_url"]["url"],
+            Image::from_bytes(PNG).unwrap().data_url()
+        );
+
+        let mut conversation = Conversation::builder().build();
+        conversation
+            .send(&link, look("What is this?"))
+            .await
+            .unwrap();
+        conversation.send(&link, "And the colour?").await.unwrap();
+        let prompt = &server.requests_to("/completion")[1].body["prompt"];
+        assert_eq!(prompt["multimodal_data"].as_array().unwrap().len(), 1);
+        let loaded =
+            Conversation::from_json(&conversation.to_json().unwrap(), PromptFormatter::default())
+                .unwrap();
+        assert_eq!(loaded.history()[0], look("What is this?"));
+    }
+}
+
+#[cfg(test)]
+mod template {
+    use llama_link::*;
+
+    #[test]
+    fn divergence() {
+        let server = "<|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|>";
+        let formatter = "<|start_header_id|>user<|end_header_id|>\nHi<|eot_id|>";
+
+        let divergence = TemplateDivergence::between(server, formatter).unwrap();
+        assert_eq!(divergence.offset, 41);
+        assert_eq!(
+            divergence.server,
+            "eader_id|>user<|end_header_id|>\n\nHi<|eot_id|>"
+        );
+        assert_eq!(
+            divergence.formatter,
+            "eader_id|>user<|end_header_id|>\nHi<|eot_id|>"
+        );
+
+        assert_eq!(TemplateDivergence::between(server, server), None);
+        let truncated = TemplateDivergence::between(server, "<|start_header_id|>").unwrap();
+        assert_eq!(truncated.offset, 19);
+        assert_eq!(truncated.formatter, "<|start_header_id|>");
+    }
+
+    #[tokio::test]
+    async fn compare_formatter_ignores_bos() {
+        let server = MockServer::start().await;
+        let messages = [Message::User("Hi".to_owned())];
+        let rendered = Conversation::builder()
+            .system("Be brief.")
+            .build()
+            .prompt("Hi")
+            .strip_prefix("<|begin_of_text|>")
+            .unwrap()
+            .to_owned();
+        server.enqueue(
+            "/apply-template",
+            MockResponse::json(200, serde_json::json!({ "prompt": rendered })),
+        );
+        server.enqueue(
+            "/apply-template",
+            MockResponse::json(
+                200,
+                serde_json::json!({ "prompt": rendered.replace("\n\nHi", "\nHi") }),
+            ),
+        );
+        let link = server.link();
+
+        let divergence = link
+            .compare_formatter("Be brief.", &messages, &PromptFormatter::default())
+            .await
+            .unwrap();
+        assert_eq!(divergence, None);
+        let divergence = link
+            .compare_formatter("Be brief.", &messages, &PromptFormatter::default())
+            .await
+            .unwrap()
+            .unwrap();
+        // Where the server has `Hi` the formatter has its second newline
+        assert_eq!(divergence.offset, rendered.find("\nHi").unwrap());
+    }
+}
+
+#[cfg(test)]
+mod classify {
+    use llama_link::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn shared_prefix_labels() {
+        let server = MockServer::start().await;
+        server.enqueue(
+            "/completion",
+            MockResponse::json(
+                200,
+                json!({
+                    "content": "cat",
+                    "stop": true,
+                    "completion_probabilities": [
+                        {
+                            "token": "ca",
+                            "prob": 0.9,
+                            "top_probs": [{ "token": "ca", "prob": 0.9 }, { "token": "do", "prob": 0.1 }],
+                        },
+                        {
+                            "token": "t",
+                            "prob": 0.6,
+                            "top_probs": [{ "token": "t", "prob": 0.6 }, { "token": "r", "prob": 0.4 }],
+                        },
+                    ],
+                }),
+            ),
+        );
+        let link = server.link();
+
+        let classification = link
+            .classify("Which animal purrs?".to_owned(), &["cat", "car", "dog"])
+            .await
+            .unwrap();
+        assert_eq!(classification.label, "cat");
+        assert_eq!(classification.most_likely(), Some("cat"));
+        // "car" diverges from the generated path at its second token, "dog" at its first
+        assert!((classification.prob("cat").unwrap() - 0.54).abs() < 1e-9);
+        assert!((classification.prob("car").unwrap() - 0.36).abs() < 1e-9);
+        assert!((classification.prob("dog").unwrap() - 0.1).abs() < 1e-9);
+
+        let request = &server.requests_to("/completion")[0].body;
+        assert_eq!(request["grammar"], r#"root ::= "cat" | "car" | "dog""#);
+        assert_eq!(request["post_sampling_probs"], true);
+        assert_eq!(request["n_probs"], 3);
+    }
+
+    #[tokio::test]
+    async fn no_labels() {
+        let server = MockServer::start().await;
+        let link = server.link();
+
+        let error = link
+            .classify("Which animal purrs?".to_owned(), &[] as &[&str])
+            .await
+            .unwrap_err();
+        assert!(matches!(error, CompletionError::InvalidRequest { .. }));
+        assert!(server.requests().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod auth {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use llama_link::*;
+    use tokio_stream::StreamExt;
+
+    async fn complete_and_stream(link: &LlamaLink) {
+        link.create_completion("Hi".to_owned()).await.unwrap();
+        let mut stream = link.create_completion_stream("Hi".to_owned());
+        while let Some(chunk) = stream.next().await {
+            chunk.unwrap();
+        }
+    }
+
+    fn authorizations(server: &MockServer) -> Vec<String> {
+        server
+            .requests_to("/completion")
+            .iter()
+            .map(|request| {
+                request
+                    .header("authorization")
+                    .unwrap_or_default()
+                    .to_owned()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn static_key() {
+        let server = MockServer::start().await;
+        let link = LlamaLink::builder()
+            .url(server.url())
+            .api_key("secret")
+            .build()
+            .unwrap();
+
+        complete_and_stream(&link).await;
+        assert_eq!(authorizations(&server), ["Bearer secret", "Bearer secret"]);
+    }
+
+    #[tokio::test]
+    async fn provider_key() {
+        let server = MockServer::start().await;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let link = LlamaLink::builder()
+            .url(server.url())
+            .api_key(ApiKey::provider({
+                let calls = calls.clone();
+                move || format!("key-{}", calls.fetch_add(1, Ordering::SeqCst))
+            }))
+            .build()
+            .unwrap();
+
+        complete_and_stream(&link).await;
+        assert_eq!(authorizations(&server), ["Bearer key-0", "Bearer key-1"]);
+    }
+
+    #[tokio::test]
+    async fn unauthorized() {
+        let server = MockServer::start().await;
+        for _ in 0..2 {
+            server.enqueue(
+                "/completion",
+                MockResponse::error(401, "authentication_error", "Invalid API Key"),
+            );
+        }
+        let link = LlamaLink::builder()
+            .url(server.url())
+            .api_key("wrong")
+            .build()
+            .unwrap();
+
+        let error = link.create_completion("Hi".to_owned()).await.unwrap_err();
+        assert!(matches!(error, CompletionError::Unauthorized));
+        assert!(!error.is_retryable());
+        let mut stream = link.create_completion_stream("Hi".to_owned());
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(CompletionStreamError::Unauthorized))
+        ));
+        assert_eq!(server.requests_to("/completion").len(), 2);
+    }
 }